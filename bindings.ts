@@ -14,8 +14,8 @@ export function errorPopup(msg: string) {
     return invoke()<null>("error_popup", { msg })
 }
 
-export function getMessage(msg: string, chatId: string, providerName: string, modelName: string) {
-    return invoke()<string>("get_message", { msg,chatId,providerName,modelName })
+export function getMessage(msg: string, chatId: string, providerName: string, modelName: string, filePaths: string[]) {
+    return invoke()<string>("get_message", { msg,chatId,providerName,modelName,filePaths })
 }
 
 export function getChats() {
@@ -31,13 +31,61 @@ export function loadProviders() {
 }
 
 export function setApiKey(provider: ProviderData) {
-    return invoke()<null>("set_api_key", { provider })
+    return invoke()<KeyValidation | null>("set_api_key", { provider })
+}
+
+export function getApiKeys(providerName: string | null) {
+    return invoke()<ApiKeys>("get_api_keys", { providerName })
+}
+
+export function addApiKey(providerName: string, label: string, apiKey: string) {
+    return invoke()<ApiKey>("add_api_key", { providerName,label,apiKey })
+}
+
+export function deleteApiKey(apiKeyId: string) {
+    return invoke()<null>("delete_api_key", { apiKeyId })
+}
+
+export function setDefaultApiKey(providerName: string, apiKeyId: string) {
+    return invoke()<null>("set_default_api_key", { providerName,apiKeyId })
+}
+
+export function setChatApiKey(chatId: string, apiKeyId: string | null) {
+    return invoke()<null>("set_chat_api_key", { chatId,apiKeyId })
+}
+
+export function revalidateApiKey(apiKeyId: string) {
+    return invoke()<ApiKey>("revalidate_api_key", { apiKeyId })
 }
 
 export function getModels() {
     return invoke()<Models>("get_models")
 }
 
+export function refreshModels(providerName: string) {
+    return invoke()<Models>("refresh_models", { providerName })
+}
+
+export function getAllModels() {
+    return invoke()<Models>("get_all_models")
+}
+
+export function addModel(model: Model) {
+    return invoke()<null>("add_model", { model })
+}
+
+export function updateModel(model: Model) {
+    return invoke()<null>("update_model", { model })
+}
+
+export function deleteModel(providerName: string, modelName: string) {
+    return invoke()<null>("delete_model", { providerName,modelName })
+}
+
+export function setModelVisibility(providerName: string, modelName: string, show: boolean) {
+    return invoke()<null>("set_model_visibility", { providerName,modelName,show })
+}
+
 export function readApiKeysFromEnv() {
     return invoke()<null>("read_api_keys_from_env")
 }
@@ -54,6 +102,30 @@ export function deleteChat(chatId: string) {
     return invoke()<null>("delete_chat", { chatId })
 }
 
+export function markBlockCopied(blockId: number) {
+    return invoke()<null>("mark_block_copied", { blockId })
+}
+
+export function saveBlockToFile(blockId: number) {
+    return invoke()<string | null>("save_block_to_file", { blockId })
+}
+
+export function copyAllCode(messageId: string) {
+    return invoke()<string>("copy_all_code", { messageId })
+}
+
+export function unlockApiKeys(passphrase: string) {
+    return invoke()<null>("unlock_api_keys", { passphrase })
+}
+
+export function listJobs(chatId: string | null) {
+    return invoke()<Job[]>("list_jobs", { chatId })
+}
+
+export function retryJob(jobId: string) {
+    return invoke()<Job>("retry_job", { jobId })
+}
+
 export function getSettings() {
     return invoke()<Settings>("get_settings")
 }
@@ -62,12 +134,70 @@ export function applyAndSaveSettings(newSettings: Settings) {
     return invoke()<null>("apply_and_save_settings", { newSettings })
 }
 
-export type Settings = { default_model: string; default_provider: string; code_theme: string }
+export function listCodeThemes() {
+    return invoke()<CodeTheme[]>("list_code_themes")
+}
+
+export function importCodeTheme(path: string) {
+    return invoke()<CodeTheme>("import_code_theme", { path })
+}
+
+export function listLanguages() {
+    return invoke()<Language[]>("list_languages")
+}
+
+export function getChatTools(chatId: string) {
+    return invoke()<ChatTool[]>("get_chat_tools", { chatId })
+}
+
+export function setChatTool(chatId: string, toolName: string, enabled: boolean) {
+    return invoke()<null>("set_chat_tool", { chatId,toolName,enabled })
+}
+
+export function listToolApprovals() {
+    return invoke()<ToolApprovalRequest[]>("list_tool_approvals")
+}
+
+export function answerToolApproval(requestId: string, approved: boolean) {
+    return invoke()<null>("answer_tool_approval", { requestId,approved })
+}
+
+export function getMcpServers() {
+    return invoke()<McpServerStatus[]>("get_mcp_servers")
+}
+
+export function reconnectMcpServer(name: string) {
+    return invoke()<null>("reconnect_mcp_server", { name })
+}
+
+export function getMcpPrompt(serverName: string, promptName: string, promptArguments: { [key: string]: string }) {
+    return invoke()<string>("get_mcp_prompt", { serverName,promptName,promptArguments })
+}
+
+export type Settings = { version: number; default_model: string; default_provider: string; code_theme: string; code_theme_light: string | null; max_retries: number; proxy: string | null; tool_folders: string[]; mcp_servers: McpServerConfig[] }
+export type CodeTheme = { name: string; dark: boolean; custom: boolean }
+export type Language = { name: string; extensions: string[] }
 export type Chat = { id: string; display_name: string; creation_date: string; last_updated: string }
-export type Model = { provider_name: string; model_name: string; model_display_name: string; show: boolean; max_tokens: number; context_window: number }
+export type Model = { provider_name: string; model_name: string; model_display_name: string; show: boolean; max_tokens: number; context_window: number; deprecated: boolean }
 export type Chats = Chat[]
-export type Message = { id: string; role: string; content: string; model_name: string; blocks: MessageBlocks | null }
+export type Message = { id: string; role: string; content: string; model_name: string; blocks: MessageBlocks | null; tool_calls: ToolCall[] | null; tool_call_id: string | null }
+export type ToolCall = { id: string; name: string; arguments: string }
 export type MessageBlocks = MessageBlock[]
 export type Models = Model[]
+export type ApiKey = { id: string; provider_name: string; label: string; key_suffix: string; api_key_valid: boolean; validation_status: KeyStatus; last_validated_at: string | null; created_at: string }
+export type KeyStatus = "valid" | "invalid" | "no_quota" | "rate_limited" | "network_error" | "unknown"
+export type KeyValidation = { status: KeyStatus; message: string | null }
+export type ApiKeys = ApiKey[]
 export type ProviderData = { provider_name: string; api_key: string; display_name: string; api_key_valid: boolean }
 export type MessageBlock = { id: number | null; type_: string; language: string | null; raw_content: string; rendered_content: string; copied: boolean | null }
+export type JobStatus = "queued" | "running" | "done" | "failed"
+export type JobKind = "generate_answer" | "generate_title" | "rehighlight"
+export type Job = { id: string; kind: JobKind; chat_id: string | null; payload: string; status: JobStatus; result: string | null; error: string | null; attempts: number; created_at: string; updated_at: string }
+export type ChatTool = { name: string; description: string; enabled: boolean; needs_approval: boolean }
+export type ToolApprovalRequest = { id: string; chat_id: string; tool_name: string; arguments: string }
+export type McpServerConfig = { name: string; command: string | null; args: string[]; env: { [key: string]: string }; url: string | null; enabled: boolean }
+export type McpServerState = "disabled" | "connecting" | "connected" | "failed"
+export type McpServerStatus = { name: string; state: McpServerState; error: string | null; server_info: string | null; tools: string[]; resources: McpResource[]; prompts: McpPrompt[] }
+export type McpResource = { uri: string; name: string; description: string | null }
+export type McpPrompt = { name: string; description: string | null; arguments: McpPromptArgument[] }
+export type McpPromptArgument = { name: string; description: string | null; required: boolean }
@@ -0,0 +1,8 @@
+-- Up migration
+
+-- API keys move into the OS keyring or, as a fallback, an encrypted column.
+-- Existing plaintext keys in providers.api_key are moved by the app on startup (see secrets.rs)
+-- because the keyring cannot be reached from SQL.
+ALTER TABLE providers ADD COLUMN api_key_storage TEXT NOT NULL DEFAULT '';
+ALTER TABLE providers ADD COLUMN api_key_encrypted TEXT NOT NULL DEFAULT '';
+ALTER TABLE providers ADD COLUMN api_key_suffix TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,31 @@
+-- Up migration
+
+-- A provider can have several named keys (e.g. work and personal). key_ref is the account name
+-- under which the key is stored in the OS keyring; key_storage/key_encrypted mirror the former
+-- providers.api_key_storage/api_key_encrypted columns.
+CREATE TABLE IF NOT EXISTS api_keys
+(
+    id TEXT NOT NULL PRIMARY KEY,
+    provider_name TEXT NOT NULL,
+    label TEXT NOT NULL,
+    key_ref TEXT NOT NULL,
+    key_storage TEXT NOT NULL DEFAULT '',
+    key_encrypted TEXT NOT NULL DEFAULT '',
+    key_suffix TEXT NOT NULL DEFAULT '',
+    api_key_valid BOOLEAN DEFAULT FALSE,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_api_keys_provider_name ON api_keys(provider_name);
+
+ALTER TABLE providers ADD COLUMN default_api_key_id TEXT;
+
+-- Existing keys become the "Default" key of their provider, keeping their keyring account
+INSERT INTO api_keys (id, provider_name, label, key_ref, key_storage, key_encrypted, key_suffix, api_key_valid)
+SELECT lower(hex(randomblob(16))), provider_name, 'Default', provider_name, api_key_storage, api_key_encrypted, api_key_suffix, api_key_valid
+FROM providers WHERE api_key_storage != '';
+
+UPDATE providers SET default_api_key_id = (SELECT id FROM api_keys WHERE api_keys.provider_name = providers.provider_name LIMIT 1);
+
+ALTER TABLE providers DROP COLUMN api_key_storage;
+ALTER TABLE providers DROP COLUMN api_key_encrypted;
+ALTER TABLE providers DROP COLUMN api_key_suffix;
@@ -0,0 +1,4 @@
+-- Up migration
+
+ALTER TABLE api_keys ADD COLUMN validation_status TEXT NOT NULL DEFAULT 'unknown';
+ALTER TABLE api_keys ADD COLUMN last_validated_at DATETIME;
@@ -0,0 +1,8 @@
+-- Up migration
+
+-- Models that disappear from a provider's model listing are kept for existing chats but marked as deprecated
+ALTER TABLE models ADD COLUMN deprecated BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_models_provider_model ON models(provider_name, model_name);
+
+-- Retired by Groq
+UPDATE models SET deprecated = TRUE WHERE provider_name = 'groqcloud' AND model_name = 'llama-3.1-70b-versatile';
@@ -0,0 +1,21 @@
+-- Up migration
+
+-- Background jobs (answer generation, chat titles, re-highlighting), so they survive reloads and restarts.
+-- `payload` and `result` are JSON.
+
+CREATE TABLE IF NOT EXISTS jobs
+(
+    id TEXT NOT NULL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    chat_id TEXT,
+    payload TEXT NOT NULL DEFAULT '{}',
+    status TEXT NOT NULL DEFAULT 'queued',
+    result TEXT,
+    error TEXT,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS jobs_status ON jobs (status);
+CREATE INDEX IF NOT EXISTS jobs_chat_id ON jobs (chat_id);
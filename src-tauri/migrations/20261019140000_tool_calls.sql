@@ -0,0 +1,7 @@
+-- Up migration
+
+-- Tool calling: an answer that calls tools keeps its calls as a JSON array in `tool_calls`,
+-- and each result is a message with role `tool` that refers to its call by `tool_call_id`.
+
+ALTER TABLE messages ADD COLUMN tool_calls TEXT;
+ALTER TABLE messages ADD COLUMN tool_call_id TEXT;
@@ -0,0 +1,11 @@
+-- Up migration
+
+-- The tools each chat may use, chats without rows use none.
+-- Not tied to `chats`, since tools can be picked before the first message creates the chat.
+
+CREATE TABLE IF NOT EXISTS chat_tools
+(
+    chat_id TEXT NOT NULL,
+    tool_name TEXT NOT NULL,
+    PRIMARY KEY (chat_id, tool_name)
+);
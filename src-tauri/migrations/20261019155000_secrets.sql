@@ -0,0 +1,12 @@
+-- Up migration
+
+-- Secrets other than API keys, e.g. the environment variables of MCP servers. The settings only hold references to them,
+-- the values are in the OS keyring under `key_ref` or encrypted in `key_encrypted`, like the API keys.
+
+CREATE TABLE IF NOT EXISTS secrets
+(
+    key_ref TEXT NOT NULL PRIMARY KEY,
+    key_storage TEXT NOT NULL,
+    key_encrypted TEXT NOT NULL DEFAULT '',
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,19 @@
+-- Up migration
+
+-- Files attached to user messages. Images keep their bytes in `data`, text files their content in `extracted_text`.
+-- `path` is where the file was attached from, the stored copy is what the models get.
+
+CREATE TABLE IF NOT EXISTS attachments
+(
+    id TEXT NOT NULL PRIMARY KEY,
+    message_id TEXT NOT NULL,
+    chat_id TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    mime_type TEXT NOT NULL,
+    data BLOB,
+    path TEXT,
+    extracted_text TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS attachments_chat_id ON attachments (chat_id);
@@ -0,0 +1,4 @@
+-- Up migration
+
+-- Models the user added by hand, which the providers' model listings don't know about and never deprecate
+ALTER TABLE models ADD COLUMN custom BOOLEAN NOT NULL DEFAULT FALSE;
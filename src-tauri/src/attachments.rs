@@ -0,0 +1,125 @@
+//! Files attached to user messages. Images are sent to the models as image parts, text files are inlined into the message.
+
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::math::escape_html;
+use crate::throw;
+use crate::types::{Attachment, Message, MessageBlock};
+
+/// Anthropic takes images up to 5 MB, and bigger text files would fill the context window anyway
+pub const MAX_ATTACHMENT_SIZE: u64 = 5 * 1024 * 1024;
+/// The image types both OpenAI and Anthropic accept, by file extension
+const IMAGE_TYPES: [(&str, &str); 5] = [
+	("png", "image/png"),
+	("jpg", "image/jpeg"),
+	("jpeg", "image/jpeg"),
+	("gif", "image/gif"),
+	("webp", "image/webp"),
+];
+
+/// Reads the files to attach them to a message. Fails before anything is stored if one of them can't be attached.
+pub fn attach_files(paths: &[impl AsRef<Path>]) -> Result<Vec<Attachment>, String> {
+	paths.iter().map(|path| read_attachment(path.as_ref())).collect()
+}
+
+/// Reads an image or a text file. Other files (PDFs, archives, ...) can't be sent to the models, so they fail.
+pub fn read_attachment(path: &Path) -> Result<Attachment, String> {
+	let file_name = match path.file_name() {
+		Some(file_name) => file_name.to_string_lossy().to_string(),
+		None => throw!("{:?} is not a file", path),
+	};
+	match std::fs::metadata(path) {
+		Ok(metadata) if !metadata.is_file() => throw!("{:?} is not a file", path),
+		Ok(metadata) if metadata.len() > MAX_ATTACHMENT_SIZE => {
+			throw!(
+				"{} is too big to attach, files may have up to {} MB",
+				file_name,
+				MAX_ATTACHMENT_SIZE / 1024 / 1024
+			)
+		}
+		Ok(_) => (),
+		Err(e) => throw!("Could not read {:?}: {}", path, e),
+	}
+	let bytes = match std::fs::read(path) {
+		Ok(bytes) => bytes,
+		Err(e) => throw!("Could not read {:?}: {}", path, e),
+	};
+	let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+	let mut attachment = Attachment {
+		id: uuid::Uuid::new_v4().to_string(),
+		file_name,
+		mime_type: String::new(),
+		data: None,
+		path: Some(path.to_string_lossy().to_string()),
+		extracted_text: None,
+	};
+	if let Some((_, mime_type)) = IMAGE_TYPES.iter().find(|(image_extension, _)| *image_extension == extension) {
+		attachment.mime_type = mime_type.to_string();
+		attachment.data = Some(bytes);
+		return Ok(attachment);
+	}
+	// Binary files usually have null bytes early on, even when they happen to be valid UTF-8
+	match String::from_utf8(bytes) {
+		Ok(text) if !text.contains('\0') => {
+			attachment.mime_type = text_mime_type(&extension).to_string();
+			attachment.extracted_text = Some(text.trim_start_matches('\u{feff}').to_string());
+			Ok(attachment)
+		}
+		_ => throw!(
+			"{} can't be attached, only images (PNG, JPEG, GIF, WebP) and text files can",
+			attachment.file_name
+		),
+	}
+}
+
+fn text_mime_type(extension: &str) -> &'static str {
+	match extension {
+		"md" | "markdown" => "text/markdown",
+		"csv" => "text/csv",
+		"html" | "htm" => "text/html",
+		"json" => "application/json",
+		"xml" => "application/xml",
+		_ => "text/plain",
+	}
+}
+
+impl Attachment {
+	pub fn is_image(&self) -> bool {
+		self.mime_type.starts_with("image/") && self.data.is_some()
+	}
+
+	pub fn base64_data(&self) -> String {
+		BASE64.encode(self.data.as_deref().unwrap_or_default())
+	}
+}
+
+/// The content of a message with its text files appended, each wrapped in a `<file>` tag so the model knows where it starts and ends
+pub fn inline_text(message: &Message) -> String {
+	let mut content = message.content.clone();
+	for attachment in &message.attachments {
+		if let Some(text) = &attachment.extracted_text {
+			content.push_str(&format!("\n\n<file name=\"{}\">\n{}\n</file>", attachment.file_name, text.trim_end()));
+		}
+	}
+	content
+}
+
+/// The images of a message, to be sent as the providers' image parts
+pub fn images(message: &Message) -> impl Iterator<Item = &Attachment> {
+	message.attachments.iter().filter(|attachment| attachment.is_image())
+}
+
+/// Shows an attached file in the chat. `language` holds the MIME type, `raw_content` the file name.
+pub fn render_attachment_block(attachment: &Attachment) -> MessageBlock {
+	MessageBlock {
+		id: None,
+		type_: "attachment".to_string(),
+		language: Some(attachment.mime_type.clone()),
+		raw_content: attachment.file_name.clone(),
+		rendered_content: escape_html(&attachment.file_name),
+		copied: Some(false),
+	}
+}
@@ -0,0 +1,514 @@
+//! Terminal client for byok. It uses the app's database, settings and API keys, so chats started here show up in the app
+//! and the other way around.
+
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use byok::attachments::attach_files;
+use byok::data::AppPaths;
+use byok::db;
+use byok::events::NoEvents;
+use byok::mcp::McpManager;
+use byok::secrets::SecretStore;
+use byok::service::{ChatService, UNNAMED_CHAT_PREFIX};
+use byok::settings::{apply_network_settings, Settings};
+use byok::tools::approval::{ToolApprovalRequest, ToolApprover};
+use byok::tools::builtin_tools;
+use byok::tools::files::ApprovedFolders;
+use byok::types::{Chat, Message, Model};
+use byok::{syntaxes, themes, throw};
+use clap::{Parser, Subcommand, ValueEnum};
+use dotenv::dotenv;
+use serde_json::json;
+
+#[derive(Parser)]
+#[command(name = "byok-cli", version, about = "Chat with your models from the terminal")]
+struct Cli {
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Chat interactively. End a line with `\` to continue the message on the next line, `/exit` quits.
+	Chat {
+		/// Id (or the start of it) of the chat to continue
+		#[arg(long)]
+		chat: Option<String>,
+		/// `provider/model`, a model name or a display name. Defaults to the chat's last model or the default model.
+		#[arg(short, long)]
+		model: Option<String>,
+		/// Turns a tool on for the chat, e.g. `--tool calculator`. Can be repeated, the chat keeps its tools.
+		#[arg(long = "tool", value_name = "TOOL")]
+		tools: Vec<String>,
+	},
+	/// Ask a single question. Input piped to stdin is appended to the prompt.
+	Ask {
+		prompt: Vec<String>,
+		/// Id (or the start of it) of the chat to continue
+		#[arg(long)]
+		chat: Option<String>,
+		/// `provider/model`, a model name or a display name. Defaults to the chat's last model or the default model.
+		#[arg(short, long)]
+		model: Option<String>,
+		/// Turns a tool on for the chat, e.g. `--tool calculator`. Can be repeated, the chat keeps its tools.
+		#[arg(long = "tool", value_name = "TOOL")]
+		tools: Vec<String>,
+		/// Attaches an image or a text file to the prompt. Can be repeated.
+		#[arg(long = "file", value_name = "FILE")]
+		files: Vec<PathBuf>,
+	},
+	/// List the chats, most recently updated first
+	ListChats,
+	/// Print the messages of a chat
+	Show {
+		/// Id (or the start of it) of the chat
+		chat: String,
+	},
+	/// Export a chat, or all chats if none is given
+	Export {
+		/// Id (or the start of it) of the chat
+		chat: Option<String>,
+		#[arg(short, long, value_enum, default_value_t = ExportFormat::Markdown)]
+		format: ExportFormat,
+		/// Write to this file instead of stdout
+		#[arg(short, long)]
+		output: Option<PathBuf>,
+	},
+	/// List the models that have an API key and are shown in the app
+	Models {
+		/// Include hidden and deprecated models and those without an API key
+		#[arg(long)]
+		all: bool,
+	},
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+	Markdown,
+	Json,
+}
+
+struct Context {
+	service: ChatService,
+	settings: Settings,
+}
+
+impl Context {
+	/// Opens the app's database and loads its settings, themes and syntaxes, like the app does on startup.
+	/// Only commands that talk to models connect to the MCP servers, starting them takes a while.
+	async fn init(connect_mcp_servers: bool) -> Result<Self, String> {
+		let app_paths = AppPaths::from_data_dir()?;
+		let secrets = SecretStore::from_env();
+		let pool = db::init(&app_paths, &secrets).await?;
+		themes::load_user_themes(&app_paths.themes_dir);
+		syntaxes::load_user_syntaxes(&app_paths.syntaxes_dir);
+		let (settings, settings_warning) = Settings::load(&app_paths.settings_file);
+		if let Some(warning) = settings_warning {
+			eprintln!("{}", warning);
+		}
+		apply_network_settings(&settings)?;
+		let tool_folders = ApprovedFolders::new(&settings.tool_folders);
+		let mut tools = builtin_tools(&tool_folders);
+		if connect_mcp_servers {
+			let mcp = McpManager::new(Arc::new(NoEvents)).with_secrets(secrets.clone(), pool.clone());
+			mcp.apply(&settings.mcp_servers).await;
+			for error in mcp.statuses().into_iter().filter_map(|status| status.error) {
+				eprintln!("{}", error);
+			}
+			tools.extend(mcp.tools());
+		}
+		Ok(Context {
+			service: ChatService::new(pool, secrets, Arc::new(NoEvents))
+				.with_tools(tools)
+				.with_approver(Arc::new(TerminalApprover)),
+			settings,
+		})
+	}
+
+	/// Messages are rendered for the app, which can't be asked for its appearance from here, so this uses the dark theme
+	fn code_theme(&self) -> String {
+		self.settings.code_theme_for(true).to_string()
+	}
+}
+
+/// Asks on the terminal before side-effecting tools run
+struct TerminalApprover;
+
+#[async_trait]
+impl ToolApprover for TerminalApprover {
+	async fn approve(&self, request: &ToolApprovalRequest) -> bool {
+		if !io::stdin().is_terminal() {
+			eprintln!("\nNot running {}, approving it needs a terminal", request.tool_name);
+			return false;
+		}
+		eprint!("\nRun {} with {}? [y/N] ", request.tool_name, request.arguments);
+		let answer = tokio::task::spawn_blocking(read_line).await.ok().flatten().unwrap_or_default();
+		matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+	}
+}
+
+#[tokio::main]
+async fn main() {
+	dotenv().ok();
+	env_logger::init();
+	let cli = Cli::parse();
+
+	let ctx = match Context::init(matches!(cli.command, Command::Chat { .. } | Command::Ask { .. })).await {
+		Ok(ctx) => ctx,
+		Err(e) => {
+			eprintln!("Error: {}", e);
+			std::process::exit(1);
+		}
+	};
+	let result = match cli.command {
+		Command::Chat { chat, model, tools } => run_chat(&ctx, chat.as_deref(), model.as_deref(), &tools).await,
+		Command::Ask {
+			prompt,
+			chat,
+			model,
+			tools,
+			files,
+		} => run_ask(&ctx, prompt, chat.as_deref(), model.as_deref(), &tools, &files).await,
+		Command::ListChats => run_list_chats(&ctx).await,
+		Command::Show { chat } => run_show(&ctx, &chat).await,
+		Command::Export { chat, format, output } => run_export(&ctx, chat.as_deref(), format, output).await,
+		Command::Models { all } => run_models(&ctx, all).await,
+	};
+	ctx.service.pool.close().await;
+	if let Err(e) = result {
+		eprintln!("Error: {}", e);
+		std::process::exit(1);
+	}
+}
+
+/// Finds a chat by its id or the start of it
+async fn resolve_chat(query: &str, service: &ChatService) -> Result<Chat, String> {
+	let chats = service.chats().await?;
+	if let Some(chat) = chats.iter().find(|chat| chat.id == query) {
+		return Ok(chat.clone());
+	}
+	let matches: Vec<&Chat> = chats.iter().filter(|chat| chat.id.starts_with(query)).collect();
+	match matches.as_slice() {
+		[chat] => Ok((*chat).clone()),
+		[] => throw!("No chat with id {}, see `byok-cli list-chats`", query),
+		_ => throw!("{} matches {} chats, please use a longer id", query, matches.len()),
+	}
+}
+
+/// Picks the model given with `--model`, else the model that answered last in the chat, else the default model
+async fn resolve_model(ctx: &Context, selector: Option<&str>, messages: &[Message]) -> Result<Model, String> {
+	let models = ctx.service.all_models().await?;
+	let selector = match selector {
+		Some(selector) => selector,
+		None => {
+			let last_model = messages
+				.iter()
+				.rev()
+				.find(|message| message.role == "assistant")
+				.map(|message| &message.model_name);
+			let model = last_model
+				.and_then(|model_name| models.iter().find(|model| &model.model_name == model_name))
+				.or_else(|| {
+					models
+						.iter()
+						.find(|model| model.provider_name == ctx.settings.default_provider && model.model_name == ctx.settings.default_model)
+				});
+			return match model {
+				Some(model) => Ok(model.clone()),
+				None => throw!(
+					"The default model {} is not available, please pick one with --model",
+					ctx.settings.default_model
+				),
+			};
+		}
+	};
+	let matches: Vec<&Model> = models
+		.iter()
+		.filter(|model| {
+			format!("{}/{}", model.provider_name, model.model_name) == selector
+				|| model.model_name == selector
+				|| model.model_display_name.eq_ignore_ascii_case(selector)
+		})
+		.collect();
+	match matches.as_slice() {
+		[model] => Ok((*model).clone()),
+		[] => throw!("Unknown model {}, see `byok-cli models --all`", selector),
+		_ => throw!("{} matches {} models, please use provider/model", selector, matches.len()),
+	}
+}
+
+/// Returns the chat id and its messages, or a new chat id
+async fn open_chat(query: Option<&str>, service: &ChatService) -> Result<(String, Vec<Message>), String> {
+	match query {
+		Some(query) => {
+			let chat = resolve_chat(query, service).await?;
+			let messages = service.messages(&chat.id).await?;
+			Ok((chat.id, messages))
+		}
+		None => Ok((uuid::Uuid::new_v4().to_string(), vec![])),
+	}
+}
+
+async fn enable_tools(ctx: &Context, chat_id: &str, tools: &[String]) -> Result<(), String> {
+	for tool in tools {
+		ctx.service.set_chat_tool(chat_id, tool, true).await?;
+	}
+	Ok(())
+}
+
+async fn run_chat(ctx: &Context, chat: Option<&str>, model: Option<&str>, tools: &[String]) -> Result<(), String> {
+	let (chat_id, messages) = open_chat(chat, &ctx.service).await?;
+	let model = resolve_model(ctx, model, &messages).await?;
+	enable_tools(ctx, &chat_id, tools).await?;
+	for message in messages.iter() {
+		print_message(message);
+	}
+	eprintln!(
+		"Chatting with {} ({}/{}), /exit to quit",
+		model.model_display_name, model.provider_name, model.model_name
+	);
+
+	loop {
+		let prompt = match read_prompt() {
+			Some(prompt) => prompt,
+			None => break,
+		};
+		match prompt.trim() {
+			"" => continue,
+			"/exit" | "/quit" => break,
+			_ => (),
+		}
+		println!();
+		match ctx
+			.service
+			.ask(
+				&chat_id,
+				&model.provider_name,
+				&model.model_name,
+				&prompt,
+				vec![],
+				&ctx.code_theme(),
+				&mut print_delta,
+			)
+			.await
+		{
+			Ok(_) => println!("\n"),
+			Err(e) => eprintln!("Error: {}", e),
+		}
+	}
+	Ok(())
+}
+
+/// Reads a line without its line break. Returns `None` at the end of the input.
+/// Stdin is only locked while reading, so tool approvals can read from it during an answer.
+fn read_line() -> Option<String> {
+	let mut line = String::new();
+	match io::stdin().read_line(&mut line) {
+		Ok(0) | Err(_) => None,
+		Ok(_) => Some(line.trim_end_matches(|c| c == '\n' || c == '\r').to_string()),
+	}
+}
+
+/// Reads one message, joining lines that end with a backslash. Returns `None` at the end of the input.
+fn read_prompt() -> Option<String> {
+	let mut prompt = String::new();
+	print!("> ");
+	let _ = io::stdout().flush();
+	loop {
+		let line = match read_line() {
+			Some(line) => line,
+			None => return if prompt.is_empty() { None } else { Some(prompt) },
+		};
+		match line.strip_suffix('\\') {
+			Some(line) => {
+				prompt.push_str(line);
+				prompt.push('\n');
+				print!(". ");
+				let _ = io::stdout().flush();
+			}
+			None => {
+				prompt.push_str(&line);
+				return Some(prompt);
+			}
+		}
+	}
+}
+
+async fn run_ask(ctx: &Context, prompt: Vec<String>, chat: Option<&str>, model: Option<&str>, tools: &[String], files: &[PathBuf]) -> Result<(), String> {
+	let mut prompt = prompt.join(" ");
+	if !io::stdin().is_terminal() {
+		let mut input = String::new();
+		if let Err(e) = io::stdin().read_to_string(&mut input) {
+			throw!("Could not read stdin: {}", e);
+		}
+		if !input.trim().is_empty() {
+			prompt = match prompt.is_empty() {
+				true => input,
+				false => format!("{}\n\n{}", prompt, input),
+			};
+		}
+	}
+	if prompt.trim().is_empty() {
+		throw!("Nothing to ask, please pass a prompt or pipe one to stdin");
+	}
+	let attachments = attach_files(files)?;
+	let (chat_id, messages) = open_chat(chat, &ctx.service).await?;
+	let model = resolve_model(ctx, model, &messages).await?;
+	enable_tools(ctx, &chat_id, tools).await?;
+	ctx.service
+		.ask(
+			&chat_id,
+			&model.provider_name,
+			&model.model_name,
+			&prompt,
+			attachments,
+			&ctx.code_theme(),
+			&mut print_delta,
+		)
+		.await?;
+	println!();
+	Ok(())
+}
+
+/// Prints the answer as it streams in
+fn print_delta(delta: &str) {
+	print!("{}", delta);
+	let _ = io::stdout().flush();
+}
+
+fn display_name(chat: &Chat) -> &str {
+	match chat.display_name.starts_with(UNNAMED_CHAT_PREFIX) {
+		true => "New chat",
+		false => &chat.display_name,
+	}
+}
+
+async fn run_list_chats(ctx: &Context) -> Result<(), String> {
+	for chat in ctx.service.chats().await?.iter() {
+		println!("{}  {}  {}", &chat.id[..8.min(chat.id.len())], chat.last_updated, display_name(chat));
+	}
+	Ok(())
+}
+
+fn author(message: &Message) -> &str {
+	match message.role.as_str() {
+		"user" => "You",
+		"tool" => "Tool",
+		_ => &message.model_name,
+	}
+}
+
+/// The content with the tool calls of the message appended
+fn message_text(message: &Message) -> String {
+	let mut text = message.content.trim_end().to_string();
+	for call in message.tool_calls.iter().flatten() {
+		if !text.is_empty() {
+			text.push('\n');
+		}
+		text.push_str(&format!("Calling `{}` with `{}`", call.name, call.arguments));
+	}
+	text
+}
+
+fn print_message(message: &Message) {
+	println!("{}:", author(message));
+	println!("{}\n", message_text(message));
+}
+
+async fn run_show(ctx: &Context, query: &str) -> Result<(), String> {
+	let chat = resolve_chat(query, &ctx.service).await?;
+	println!("# {}\n", display_name(&chat));
+	for message in ctx.service.messages(&chat.id).await?.iter() {
+		print_message(message);
+	}
+	Ok(())
+}
+
+fn chat_to_markdown(chat: &Chat, messages: &[Message]) -> String {
+	let mut markdown = format!("# {}\n\n", display_name(chat));
+	for message in messages {
+		markdown.push_str(&format!("## {}\n\n{}\n\n", author(message), message_text(message)));
+	}
+	markdown
+}
+
+async fn run_export(ctx: &Context, query: Option<&str>, format: ExportFormat, output: Option<PathBuf>) -> Result<(), String> {
+	let chats = match query {
+		Some(query) => vec![resolve_chat(query, &ctx.service).await?],
+		None => ctx.service.chats().await?.0,
+	};
+	let mut exported_chats = vec![];
+	for chat in chats {
+		let messages = ctx.service.messages(&chat.id).await?;
+		exported_chats.push((chat, messages));
+	}
+
+	let export = match format {
+		ExportFormat::Markdown => exported_chats
+			.iter()
+			.map(|(chat, messages)| chat_to_markdown(chat, messages))
+			.collect::<Vec<_>>()
+			.join("---\n\n"),
+		ExportFormat::Json => {
+			// The rendered blocks are HTML for the app, the raw content is what's worth exporting
+			let chats: Vec<_> = exported_chats
+				.iter()
+				.map(|(chat, messages)| {
+					json!({
+						"id": chat.id,
+						"display_name": display_name(chat),
+						"creation_date": chat.creation_date,
+						"last_updated": chat.last_updated,
+						"messages": messages.iter().map(|message| json!({
+							"role": message.role,
+							"content": message.content,
+							"model_name": message.model_name,
+							"tool_calls": message.tool_calls,
+							"tool_call_id": message.tool_call_id,
+						})).collect::<Vec<_>>(),
+					})
+				})
+				.collect();
+			serde_json::to_string_pretty(&chats).map_err(|e| e.to_string())?
+		}
+	};
+	match output {
+		Some(path) => match std::fs::write(&path, export) {
+			Ok(_) => Ok(()),
+			Err(e) => throw!("Could not write {:?}: {}", path, e),
+		},
+		None => {
+			print!("{}", export);
+			Ok(())
+		}
+	}
+}
+
+async fn run_models(ctx: &Context, all: bool) -> Result<(), String> {
+	let models = match all {
+		true => ctx.service.all_models().await?,
+		false => ctx.service.models().await?,
+	};
+	for model in models.iter() {
+		let is_default = model.provider_name == ctx.settings.default_provider && model.model_name == ctx.settings.default_model;
+		let mut flags = vec![];
+		if is_default {
+			flags.push("default");
+		}
+		if !model.show {
+			flags.push("hidden");
+		}
+		if model.deprecated {
+			flags.push("deprecated");
+		}
+		let flags = match flags.is_empty() {
+			true => String::new(),
+			false => format!("  ({})", flags.join(", ")),
+		};
+		println!("{}/{}  {}{}", model.provider_name, model.model_name, model.model_display_name, flags);
+	}
+	Ok(())
+}
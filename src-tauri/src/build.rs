@@ -1,3 +1,6 @@
 fn main() {
-	tauri_build::build();
+	// The CLI builds without the app window, so it doesn't need the Tauri config or icons
+	if std::env::var_os("CARGO_FEATURE_GUI").is_some() {
+		tauri_build::build();
+	}
 }
@@ -1,45 +1,109 @@
 use sqlx::SqlitePool;
 use std::env;
 
+use crate::events::EventSink;
+use crate::jobs::JobQueue;
+use crate::mcp::McpManager;
+use crate::secrets::SecretStore;
+use crate::service::ChatService;
 use crate::settings::Settings;
+use crate::tools::approval::EventApprover;
+use crate::tools::files::ApprovedFolders;
+use crate::tools::ToolRegistry;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+#[cfg(feature = "gui")]
 use tauri::{Config, State};
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 
 #[derive(Clone)]
 pub struct AppPaths {
 	pub app_dir: PathBuf,
 	pub settings_file: PathBuf,
+	pub themes_dir: PathBuf,
+	pub syntaxes_dir: PathBuf,
 	pub db: String,
 	// pub models: PathBuf,
 }
+/// The bundle identifier in `tauri.conf.json`, which names the app data directory
+pub const APP_IDENTIFIER: &str = "com.byok.byok";
+
 impl AppPaths {
+	#[cfg(feature = "gui")]
 	pub fn from_tauri_config(config: &Config) -> Self {
 		let app_dir = match env::var("DEVELOPMENT").is_ok() {
 			true => env::current_dir().unwrap().join("appdata"),
 			false => tauri::api::path::app_data_dir(config).unwrap(),
 		};
+		Self::from_app_dir(app_dir)
+	}
+
+	/// The same paths as the app uses, without a Tauri config, e.g. for the CLI
+	pub fn from_data_dir() -> Result<Self, String> {
+		let app_dir = match env::var("DEVELOPMENT").is_ok() {
+			true => env::current_dir().map_err(|e| e.to_string())?.join("appdata"),
+			false => match dirs::data_dir() {
+				Some(data_dir) => data_dir.join(APP_IDENTIFIER),
+				None => return Err("Could not find the app data directory".to_string()),
+			},
+		};
+		Ok(Self::from_app_dir(app_dir))
+	}
+
+	pub fn from_app_dir(app_dir: PathBuf) -> Self {
 		AppPaths {
 			app_dir: app_dir.clone(),
 			settings_file: app_dir.join("settings.json"),
+			themes_dir: app_dir.join("themes"),
+			syntaxes_dir: app_dir.join("syntaxes"),
 			db: app_dir.join("byok.sqlite").to_string_lossy().to_string(),
 			// models: app_dir.join("models"),
 		}
 	}
 }
 
+/// Shared app state. Only the settings can change at runtime and sit behind a lock, so commands
+/// (and generations in different chats) don't wait for each other.
 pub struct Data {
 	pub db_pool: SqlitePool,
 	pub paths: AppPaths,
-	pub window: tauri::Window,
-	pub settings: Settings,
+	pub events: Arc<dyn EventSink>,
+	pub settings: RwLock<Settings>,
+	pub secrets: SecretStore,
+	/// Whether the system appearance is dark, which selects the code theme
+	pub dark_mode: AtomicBool,
+	pub jobs: JobQueue,
+	/// The tools the models may call while answering
+	pub tools: ToolRegistry,
+	/// The folders of `Settings::tool_folders`, shared with the tools that access files
+	pub tool_folders: ApprovedFolders,
+	/// Asks the window before side-effecting tools run
+	pub approvals: Arc<EventApprover>,
+	/// The servers of `Settings::mcp_servers`, whose tools are offered alongside `tools`
+	pub mcp: McpManager,
+}
+impl Data {
+	pub fn chat_service(&self) -> ChatService {
+		let mut tools = self.tools.clone();
+		tools.extend(self.mcp.tools());
+		ChatService::new(self.db_pool.clone(), self.secrets.clone(), self.events.clone())
+			.with_tools(tools)
+			.with_approver(self.approvals.clone())
+	}
+
+	pub async fn code_theme(&self) -> String {
+		self.settings.read().await.code_theme_for(self.dark_mode.load(Ordering::Relaxed)).to_string()
+	}
 }
 
+#[cfg(feature = "gui")]
 pub type DataState<'a> = State<'a, ArcData>;
-pub struct ArcData(pub Arc<Mutex<Data>>);
+#[cfg(feature = "gui")]
+pub struct ArcData(pub Arc<Data>);
+#[cfg(feature = "gui")]
 impl ArcData {
 	pub fn new(data: Data) -> Self {
-		Self(Arc::new(Mutex::new(data)))
+		Self(Arc::new(data))
 	}
 }
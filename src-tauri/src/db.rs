@@ -1,19 +1,34 @@
 use std::collections::HashMap;
+#[cfg(feature = "gui")]
 use std::env;
 
+#[cfg(feature = "gui")]
 use dotenv::dotenv;
 use lazy_static::lazy_static;
 use log;
 use sqlx::migrate::MigrateDatabase;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
-use sqlx::{Row, Sqlite, SqlitePool};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow};
+use sqlx::{FromRow, Row, Sqlite, SqlitePool};
+#[cfg(feature = "gui")]
+use tauri::api::dialog::blocking::FileDialogBuilder;
+#[cfg(feature = "gui")]
 use tauri::command;
 
-use crate::data::{AppPaths, DataState};
-use crate::llm_providers::{LLMConfig, LLMProvider};
+use crate::data::AppPaths;
+#[cfg(feature = "gui")]
+use crate::data::DataState;
+#[cfg(feature = "gui")]
+use crate::llm_providers::LLMProvider;
+use crate::llm_providers::{KeyStatus, KeyValidation};
+#[cfg(feature = "gui")]
 use crate::providers::ProviderData;
+use crate::secrets::{mask_api_key, SecretStore};
 use crate::throw;
-use crate::types::{Chat, Chats, Message, MessageBlock, MessageBlocks, MessageHistory, Model, Models};
+#[cfg(feature = "gui")]
+use crate::types::{ApiKey, ApiKeys};
+use crate::types::{Attachment, Chat, Chats, Message, MessageBlock, MessageBlocks, MessageHistory, Model, Models};
+#[cfg(feature = "gui")]
+use crate::utils::file_extension_for_language;
 
 lazy_static! {
 	pub static ref DEFAULT_MODELS: Vec<Model> = vec![
@@ -24,6 +39,7 @@ lazy_static! {
 			show: true,
 			max_tokens: 16384,
 			context_window: 128000,
+			deprecated: false,
 		},
 		Model {
 			provider_name: "anthropic".to_string(),
@@ -32,6 +48,7 @@ lazy_static! {
 			show: true,
 			max_tokens: 8192,
 			context_window: 200000,
+			deprecated: false,
 		},
 		Model {
 			provider_name: "mistralai".to_string(),
@@ -40,19 +57,21 @@ lazy_static! {
 			show: true,
 			max_tokens: 32768,
 			context_window: 32768,
+			deprecated: false,
 		},
 		Model {
 			provider_name: "groqcloud".to_string(),
-			model_name: "llama-3.1-70b-versatile".to_string(),
+			model_name: "llama-3.3-70b-versatile".to_string(),
 			model_display_name: "LLaMA".to_string(),
 			show: true,
-			max_tokens: 2048,
+			max_tokens: 32768,
 			context_window: 131072,
+			deprecated: false,
 		},
 	];
 }
 
-pub async fn init(app_paths: &AppPaths) -> Result<SqlitePool, String> {
+pub async fn init(app_paths: &AppPaths, secrets: &SecretStore) -> Result<SqlitePool, String> {
 	let exists = match Sqlite::database_exists(&app_paths.db).await {
 		Ok(exists) => exists,
 		Err(e) => throw!("Could not check if database exists: {}", e),
@@ -72,48 +91,133 @@ pub async fn init(app_paths: &AppPaths) -> Result<SqlitePool, String> {
 		Ok(pool) => pool,
 		Err(e) => throw!("Could not open database: {}", e),
 	};
+	prepare(&pool, secrets).await?;
+	Ok(pool)
+}
+
+/// An empty database that lives as long as the pool, e.g. for tests
+pub async fn init_in_memory(secrets: &SecretStore) -> Result<SqlitePool, String> {
+	// Every connection to `:memory:` gets its own database, so the pool keeps exactly one connection open
+	let pool = match SqlitePoolOptions::new()
+		.max_connections(1)
+		.idle_timeout(None)
+		.max_lifetime(None)
+		.connect_with(SqliteConnectOptions::new().filename(":memory:"))
+		.await
+	{
+		Ok(pool) => pool,
+		Err(e) => throw!("Could not open in-memory database: {}", e),
+	};
+	prepare(&pool, secrets).await?;
+	Ok(pool)
+}
 
-	match sqlx::migrate!("./migrations").run(&pool).await {
+/// Runs the migrations and adds the default models
+async fn prepare(pool: &SqlitePool, secrets: &SecretStore) -> Result<(), String> {
+	match sqlx::migrate!("./migrations").run(pool).await {
 		Ok(_) => {}
 		Err(e) => throw!("Could not run database migrations: {}", e),
 	};
 
 	for model in DEFAULT_MODELS.iter() {
-		_ = insert_model(model, &pool).await;
+		_ = insert_model(model, pool).await;
 	}
 
-	Ok(pool)
+	if let Err(e) = secrets.migrate_plaintext_api_keys(pool).await {
+		log::error!("{}", e);
+	}
+
+	Ok(())
 }
 
-// insert model into models table if it doesn't exist
+// insert model into models table if it doesn't exist, otherwise leave the user's settings (e.g. `show`) untouched
 pub async fn insert_model(model: &Model, pool: &SqlitePool) -> Result<(), String> {
-	// check if model already exists
-	let query = sqlx::query("SELECT * FROM models WHERE provider_name = $1 AND model_name = $2")
-		.bind(&model.provider_name)
-		.bind(&model.model_name)
-		.fetch_one(pool)
-		.await;
-
-	if let Ok(_) = query {
-		return Ok(());
-	}
-	let _ = sqlx::query("INSERT INTO models (provider_name, model_name, model_display_name, show, max_tokens, context_window) VALUES ($1, $2, $3, $4, $5, $6)")
+	let insert_query = "INSERT INTO models (provider_name, model_name, model_display_name, show, max_tokens, context_window, deprecated) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT(provider_name, model_name) DO NOTHING";
+	match sqlx::query(insert_query)
 		.bind(&model.provider_name)
 		.bind(&model.model_name)
 		.bind(&model.model_display_name)
 		.bind(&model.show)
 		.bind(&model.max_tokens)
 		.bind(&model.context_window)
+		.bind(&model.deprecated)
 		.execute(pool)
-		.await;
-	Ok(())
+		.await
+	{
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error inserting model {}: {}", model.model_name, e),
+	}
+}
+
+/// Syncs the models table with the provider's model listing, see `sync_models`
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn refresh_models(provider_name: String, data: DataState<'_>) -> Result<Models, String> {
+	let api_key = get_api_key(&provider_name, None, &data.0.secrets, &data.0.db_pool).await?;
+	let llm: LLMProvider = match LLMProvider::new(&provider_name, api_key) {
+		Ok(llm) => llm,
+		Err(e) => throw!("{}", e),
+	};
+	let provider_models = match llm.list_models().await {
+		Ok(provider_models) => provider_models,
+		Err(e) => throw!("Error fetching models for provider {}: {:#}", provider_name, e),
+	};
+
+	let data = &data.0;
+	// Most listings don't report the output limit, the provider's default model is a better guess than a fixed number
+	let default_max_tokens = DEFAULT_MODELS
+		.iter()
+		.find(|model| model.provider_name == provider_name)
+		.map_or(4096, |model| model.max_tokens);
+	let listed_models: Vec<Model> = provider_models
+		.iter()
+		.map(|provider_model| Model {
+			provider_name: provider_name.clone(),
+			model_name: provider_model.id.clone(),
+			model_display_name: provider_model.display_name.clone().unwrap_or_else(|| provider_model.id.clone()),
+			show: false,
+			max_tokens: provider_model.max_output_tokens.unwrap_or(default_max_tokens),
+			context_window: provider_model.context_window.unwrap_or(4096),
+			deprecated: false,
+		})
+		.collect();
+	sync_models(&provider_name, &listed_models, &data.db_pool).await?;
+
+	let models_query = "SELECT provider_name, model_name, model_display_name, show, max_tokens, context_window, deprecated FROM models WHERE provider_name = $1 ORDER BY model_name";
+	match sqlx::query_as::<_, Model>(models_query).bind(&provider_name).fetch_all(&data.db_pool).await {
+		Ok(models) => Ok(Models(models)),
+		Err(e) => throw!("Error fetching models from database: {}", e),
+	}
+}
+
+/// New models are inserted hidden, models that are no longer listed are marked as deprecated instead of being deleted.
+/// Models the user added by hand are left alone, and so is everything when the listing is empty, which rather means
+/// the provider's endpoint is broken than that all its models are gone.
+pub async fn sync_models(provider_name: &str, listed_models: &[Model], pool: &SqlitePool) -> Result<(), String> {
+	if listed_models.is_empty() {
+		log::warn!("The model listing of provider {} is empty, keeping its models as they are", provider_name);
+		return Ok(());
+	}
+	for model in listed_models {
+		insert_model(model, pool).await?;
+	}
+
+	let listed_model_names = serde_json::to_string(&listed_models.iter().map(|model| &model.model_name).collect::<Vec<_>>()).map_err(|e| e.to_string())?;
+	let deprecate_query = "UPDATE models SET deprecated = (model_name NOT IN (SELECT value FROM json_each($1))) WHERE provider_name = $2 AND custom = FALSE";
+	match sqlx::query(deprecate_query).bind(&listed_model_names).bind(provider_name).execute(pool).await {
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error updating models for provider {}: {}", provider_name, e),
+	}
 }
 
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn load_providers(data: DataState<'_>) -> Result<Vec<ProviderData>, String> {
-	let data = data.0.lock().await;
-	let query = "SELECT provider_name, api_key, display_name, api_key_valid FROM providers";
+	let data = &data.0;
+	// Only the masked suffix of the provider's default key is sent to the webview
+	let query = "SELECT p.provider_name, COALESCE(k.key_suffix, '') AS api_key, p.display_name, COALESCE(k.api_key_valid, FALSE) AS api_key_valid FROM providers p LEFT JOIN api_keys k ON k.id = p.default_api_key_id";
 	let providers = sqlx::query_as::<_, ProviderData>(&query);
 	match providers.fetch_all(&data.db_pool).await {
 		Ok(providers) => {
@@ -124,49 +228,258 @@ pub async fn load_providers(data: DataState<'_>) -> Result<Vec<ProviderData>, St
 	};
 }
 
+/// Sets the default key of a provider. A key that fails validation is still stored, marked as invalid.
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
-pub async fn set_api_key(provider: ProviderData, data: DataState<'_>) -> Result<(), String> {
-	let data = data.0.lock().await;
-	let is_valid: bool = validate_api_key(&provider).await?;
+pub async fn set_api_key(provider: ProviderData, data: DataState<'_>) -> Result<Option<KeyValidation>, String> {
+	let data = &data.0;
+	// The webview only knows the masked key, so an unchanged masked value means there is nothing to do
+	if provider.api_key.starts_with('•') {
+		return Ok(None);
+	}
+	if provider.api_key.is_empty() {
+		let default_api_key_query = "SELECT default_api_key_id FROM providers WHERE provider_name = $1 AND default_api_key_id IS NOT NULL";
+		return match sqlx::query_as::<_, (String,)>(default_api_key_query)
+			.bind(&provider.provider_name)
+			.fetch_optional(&data.db_pool)
+			.await
+		{
+			Ok(Some((api_key_id,))) => remove_api_key(&api_key_id, &data.secrets, &data.db_pool).await.map(|_| None),
+			Ok(None) => Ok(None),
+			Err(e) => throw!("Error fetching default API key for provider {}: {}", &provider.provider_name, e),
+		};
+	}
 
-	println!("API key for provider {} is valid: {}", &provider.provider_name, is_valid);
+	let validation = validate_api_key(&provider.provider_name, &provider.api_key).await?;
 
-	let query = sqlx::query("UPDATE providers SET api_key = ?, api_key_valid = ? WHERE provider_name = ?")
-		.bind(&provider.api_key)
-		.bind(is_valid)
-		.bind(&provider.provider_name);
+	log::debug!("API key for provider {} validated as {:?}", &provider.provider_name, validation.status);
 
-	// Execute the update query for the current provider
-	query
-		.execute(&data.db_pool)
+	let api_key_id = set_default_api_key_secret(&provider.provider_name, &provider.api_key, &data.secrets, &data.db_pool).await?;
+	record_key_validation(&api_key_id, &validation, &data.db_pool).await?;
+	Ok(Some(validation))
+}
+
+/// Stores `api_key` as the default key of a provider, replacing the secret of the current default key if there is one.
+/// The key is considered unvalidated until `record_key_validation` is called.
+pub async fn set_default_api_key_secret(provider_name: &str, api_key: &str, secrets: &SecretStore, pool: &SqlitePool) -> Result<String, String> {
+	let default_api_key_query = "SELECT k.id, k.key_ref FROM api_keys k JOIN providers p ON p.default_api_key_id = k.id WHERE p.provider_name = $1";
+	let default_api_key = match sqlx::query_as::<_, (String, String)>(default_api_key_query)
+		.bind(provider_name)
+		.fetch_optional(pool)
 		.await
-		.map_err(|e| format!("Error updating API key for provider {}: {:?}", &provider.provider_name, e))?;
+	{
+		Ok(default_api_key) => default_api_key,
+		Err(e) => throw!("Error fetching default API key for provider {}: {}", provider_name, e),
+	};
+
+	match default_api_key {
+		Some((api_key_id, key_ref)) => {
+			let (storage, encrypted) = secrets.store_secret(&key_ref, api_key)?;
+			let update_query = "UPDATE api_keys SET key_storage = $1, key_encrypted = $2, key_suffix = $3, api_key_valid = FALSE, validation_status = $4, last_validated_at = NULL WHERE id = $5";
+			match sqlx::query(update_query)
+				.bind(storage)
+				.bind(&encrypted)
+				.bind(mask_api_key(api_key))
+				.bind(KeyStatus::Unknown)
+				.bind(&api_key_id)
+				.execute(pool)
+				.await
+			{
+				Ok(_) => Ok(api_key_id),
+				Err(e) => throw!("Error updating API key for provider {}: {}", provider_name, e),
+			}
+		}
+		None => {
+			let api_key_id = insert_api_key(provider_name, "Default", api_key, secrets, pool).await?;
+			match sqlx::query("UPDATE providers SET default_api_key_id = $1 WHERE provider_name = $2")
+				.bind(&api_key_id)
+				.bind(provider_name)
+				.execute(pool)
+				.await
+			{
+				Ok(_) => Ok(api_key_id),
+				Err(e) => throw!("Error setting default API key for provider {}: {}", provider_name, e),
+			}
+		}
+	}
+}
 
+pub async fn insert_api_key(provider_name: &str, label: &str, api_key: &str, secrets: &SecretStore, pool: &SqlitePool) -> Result<String, String> {
+	let api_key_id = uuid::Uuid::new_v4().to_string();
+	// New keys are stored in the keyring under their own id
+	let (storage, encrypted) = secrets.store_secret(&api_key_id, api_key)?;
+	let insert_query = "INSERT INTO api_keys (id, provider_name, label, key_ref, key_storage, key_encrypted, key_suffix) VALUES ($1, $2, $3, $4, $5, $6, $7)";
+	match sqlx::query(insert_query)
+		.bind(&api_key_id)
+		.bind(provider_name)
+		.bind(label)
+		.bind(&api_key_id)
+		.bind(storage)
+		.bind(&encrypted)
+		.bind(mask_api_key(api_key))
+		.execute(pool)
+		.await
+	{
+		Ok(_) => Ok(api_key_id),
+		Err(e) => {
+			secrets.delete_secret(&api_key_id);
+			throw!("Error inserting API key for provider {}: {}", provider_name, e)
+		}
+	}
+}
+
+pub async fn record_key_validation(api_key_id: &str, validation: &KeyValidation, pool: &SqlitePool) -> Result<(), String> {
+	let query = "UPDATE api_keys SET api_key_valid = $1, validation_status = $2, last_validated_at = CURRENT_TIMESTAMP WHERE id = $3";
+	match sqlx::query(query)
+		.bind(validation.is_valid())
+		.bind(validation.status)
+		.bind(api_key_id)
+		.execute(pool)
+		.await
+	{
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error storing validation result of API key {}: {}", api_key_id, e),
+	}
+}
+
+/// Deletes the key in one transaction and its secret once that succeeded.
+/// If it was the provider's default key, the provider's oldest remaining key becomes the default.
+pub async fn remove_api_key(api_key_id: &str, secrets: &SecretStore, pool: &SqlitePool) -> Result<(), String> {
+	let key_ref = match sqlx::query_as::<_, (String,)>("SELECT key_ref FROM api_keys WHERE id = $1")
+		.bind(api_key_id)
+		.fetch_optional(pool)
+		.await
+	{
+		Ok(Some((key_ref,))) => key_ref,
+		Ok(None) => throw!("API key {} does not exist", api_key_id),
+		Err(e) => throw!("Error fetching API key {}: {}", api_key_id, e),
+	};
+	if let Err(e) = delete_api_key_rows(api_key_id, pool).await {
+		throw!("Error deleting API key {}: {}", api_key_id, e);
+	}
+	secrets.delete_secret(&key_ref);
 	Ok(())
 }
 
-async fn validate_api_key(provider: &ProviderData) -> Result<bool, String> {
-	let model = DEFAULT_MODELS.iter().find(|m| m.provider_name == provider.provider_name).unwrap();
-	let llm_config = LLMConfig::default();
+async fn delete_api_key_rows(api_key_id: &str, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+	// Chats pinned to this key fall back to the provider's default key
+	let queries = [
+		"DELETE FROM api_keys WHERE id = $1",
+		"UPDATE providers SET default_api_key_id = (SELECT id FROM api_keys WHERE api_keys.provider_name = providers.provider_name ORDER BY created_at LIMIT 1) WHERE default_api_key_id = $1",
+		"UPDATE chats SET api_key_id = 'NA' WHERE api_key_id = $1",
+	];
+	let mut transaction = pool.begin().await?;
+	for query in queries {
+		sqlx::query(query).bind(api_key_id).execute(&mut *transaction).await?;
+	}
+	transaction.commit().await
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn get_api_keys(provider_name: Option<String>, data: DataState<'_>) -> Result<ApiKeys, String> {
+	let data = &data.0;
+	let query = "SELECT id, provider_name, label, key_suffix, api_key_valid, validation_status, last_validated_at, created_at FROM api_keys WHERE $1 IS NULL OR provider_name = $1 ORDER BY provider_name, created_at";
+	match sqlx::query_as::<_, ApiKey>(query).bind(&provider_name).fetch_all(&data.db_pool).await {
+		Ok(api_keys) => Ok(ApiKeys(api_keys)),
+		Err(e) => throw!("Error fetching API keys: {}", e),
+	}
+}
 
-	let llm: LLMProvider = LLMProvider::new(&provider.provider_name, provider.api_key.clone());
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn add_api_key(provider_name: String, label: String, api_key: String, data: DataState<'_>) -> Result<ApiKey, String> {
+	let data = &data.0;
+	if label.trim().is_empty() {
+		throw!("Please enter a label for the API key");
+	}
+	let validation = validate_api_key(&provider_name, &api_key).await?;
+	let api_key_id = insert_api_key(&provider_name, label.trim(), &api_key, &data.secrets, &data.db_pool).await?;
+	record_key_validation(&api_key_id, &validation, &data.db_pool).await?;
+
+	// The first key of a provider becomes its default key
+	let set_default_query = "UPDATE providers SET default_api_key_id = $1 WHERE provider_name = $2 AND default_api_key_id IS NULL";
+	if let Err(e) = sqlx::query(set_default_query).bind(&api_key_id).bind(&provider_name).execute(&data.db_pool).await {
+		log::error!("Error setting default API key for provider {}: {}", &provider_name, e);
+	}
 
-	let messages = MessageHistory(vec![Message {
-		id: "".to_string(),
-		role: "user".to_string(),
-		content: "Hello".to_string(),
-		model_name: model.model_name.clone(),
-		blocks: None,
-	}]);
+	let query = "SELECT id, provider_name, label, key_suffix, api_key_valid, validation_status, last_validated_at, created_at FROM api_keys WHERE id = $1";
+	match sqlx::query_as::<_, ApiKey>(query).bind(&api_key_id).fetch_one(&data.db_pool).await {
+		Ok(api_key) => Ok(api_key),
+		Err(e) => throw!("Error fetching API key {}: {}", api_key_id, e),
+	}
+}
 
-	match llm.send_message(&messages, &model.model_name, &llm_config).await {
-		Ok(_) => return Ok(true),
-		Err(e) => {
-			log::error!("Error sending message to LLM: {}", e);
-			return Err(e.to_string());
-		}
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn delete_api_key(api_key_id: String, data: DataState<'_>) -> Result<(), String> {
+	let data = &data.0;
+	remove_api_key(&api_key_id, &data.secrets, &data.db_pool).await
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn set_default_api_key(provider_name: String, api_key_id: String, data: DataState<'_>) -> Result<(), String> {
+	let data = &data.0;
+	let query = "UPDATE providers SET default_api_key_id = $1 WHERE provider_name = $2 AND EXISTS (SELECT 1 FROM api_keys WHERE id = $1 AND provider_name = $2)";
+	match sqlx::query(query).bind(&api_key_id).bind(&provider_name).execute(&data.db_pool).await {
+		Ok(result) if result.rows_affected() == 0 => throw!("API key {} does not belong to provider {}", api_key_id, provider_name),
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error setting default API key for provider {}: {}", provider_name, e),
+	}
+}
+
+/// Pins a key to a chat. `None` makes the chat use the default key of its provider again.
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn set_chat_api_key(chat_id: String, api_key_id: Option<String>, data: DataState<'_>) -> Result<(), String> {
+	data.0.chat_service().set_chat_api_key(&chat_id, api_key_id.as_deref()).await
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn revalidate_api_key(api_key_id: String, data: DataState<'_>) -> Result<ApiKey, String> {
+	let data = &data.0;
+	let secret_query = "SELECT provider_name, key_ref, key_storage, key_encrypted FROM api_keys WHERE id = $1";
+	let (provider_name, key_ref, storage, encrypted) = match sqlx::query_as::<_, (String, String, String, String)>(secret_query)
+		.bind(&api_key_id)
+		.fetch_optional(&data.db_pool)
+		.await
+	{
+		Ok(Some(row)) => row,
+		Ok(None) => throw!("API key {} does not exist", api_key_id),
+		Err(e) => throw!("Error fetching API key {}: {}", api_key_id, e),
+	};
+	let api_key = data.secrets.load_secret(&key_ref, &storage, &encrypted)?;
+	let validation = validate_api_key(&provider_name, &api_key).await?;
+	record_key_validation(&api_key_id, &validation, &data.db_pool).await?;
+
+	let query = "SELECT id, provider_name, label, key_suffix, api_key_valid, validation_status, last_validated_at, created_at FROM api_keys WHERE id = $1";
+	match sqlx::query_as::<_, ApiKey>(query).bind(&api_key_id).fetch_one(&data.db_pool).await {
+		Ok(api_key) => Ok(api_key),
+		Err(e) => throw!("Error fetching API key {}: {}", api_key_id, e),
+	}
+}
+
+/// Only fails for unsupported providers; a bad key is reported through the returned status
+#[cfg(feature = "gui")]
+async fn validate_api_key(provider_name: &str, api_key: &str) -> Result<KeyValidation, String> {
+	let llm: LLMProvider = match LLMProvider::new(provider_name, api_key.to_string()) {
+		Ok(llm) => llm,
+		Err(e) => throw!("{}", e),
 	};
+	let validation = llm.validate_key().await;
+	if !validation.is_valid() {
+		log::warn!("API key for provider {} is not valid: {:?} {:?}", provider_name, validation.status, validation.message);
+	}
+	Ok(validation)
 }
 
 impl sqlx::FromRow<'_, SqliteRow> for Message {
@@ -177,16 +490,20 @@ impl sqlx::FromRow<'_, SqliteRow> for Message {
 			content: row.try_get("content")?,
 			model_name: row.try_get("model_name")?,
 			blocks: None,
+			tool_calls: match row.try_get::<Option<String>, _>("tool_calls")? {
+				Some(tool_calls) => Some(serde_json::from_str(&tool_calls).map_err(|e| sqlx::Error::Decode(Box::new(e)))?),
+				None => None,
+			},
+			tool_call_id: row.try_get("tool_call_id")?,
+			attachments: vec![],
 		})
 	}
 }
 
-#[command]
-#[specta::specta]
-pub async fn get_models(data: DataState<'_>) -> Result<Models, String> {
-	let data = data.0.lock().await;
-	let models_query = "SELECT provider_name, model_name, model_display_name, show, max_tokens, context_window FROM models WHERE provider_name IN (SELECT provider_name FROM providers WHERE api_key != '') OR provider_name = 'local'";
-	let models_query_result = sqlx::query_as::<_, Model>(models_query).fetch_all(&data.db_pool).await;
+/// The models that can be chatted with: visible, not deprecated and with an API key
+pub async fn fetch_models(pool: &SqlitePool) -> Result<Models, String> {
+	let models_query = "SELECT provider_name, model_name, model_display_name, show, max_tokens, context_window, deprecated FROM models WHERE show = TRUE AND deprecated = FALSE AND (provider_name IN (SELECT provider_name FROM api_keys) OR provider_name = 'local')";
+	let models_query_result = sqlx::query_as::<_, Model>(models_query).fetch_all(pool).await;
 	match models_query_result {
 		Ok(models) => Ok(Models(models)),
 		Err(e) => {
@@ -196,26 +513,154 @@ pub async fn get_models(data: DataState<'_>) -> Result<Models, String> {
 	}
 }
 
+pub async fn fetch_all_models(pool: &SqlitePool) -> Result<Models, String> {
+	let models_query =
+		"SELECT provider_name, model_name, model_display_name, show, max_tokens, context_window, deprecated FROM models ORDER BY provider_name, model_name";
+	match sqlx::query_as::<_, Model>(models_query).fetch_all(pool).await {
+		Ok(models) => Ok(Models(models)),
+		Err(e) => throw!("Error fetching models from database: {}", e),
+	}
+}
+
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
-pub async fn get_chats(data: DataState<'_>) -> Result<Chats, String> {
-	let data = data.0.lock().await;
+pub async fn get_models(data: DataState<'_>) -> Result<Models, String> {
+	data.0.chat_service().models().await
+}
+
+/// All models including hidden and deprecated ones, e.g. for the model settings
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn get_all_models(data: DataState<'_>) -> Result<Models, String> {
+	data.0.chat_service().all_models().await
+}
+
+async fn validate_model(model: &Model, pool: &SqlitePool) -> Result<(), String> {
+	if model.model_name.trim().is_empty() {
+		throw!("Please enter a model name");
+	}
+	if model.model_display_name.trim().is_empty() {
+		throw!("Please enter a display name for model {}", model.model_name);
+	}
+	if model.max_tokens == 0 || model.context_window == 0 {
+		throw!("Max tokens and context window of model {} must be greater than 0", model.model_name);
+	}
+	match sqlx::query("SELECT 1 FROM providers WHERE provider_name = $1")
+		.bind(&model.provider_name)
+		.fetch_optional(pool)
+		.await
+	{
+		Ok(Some(_)) => Ok(()),
+		Ok(None) => throw!("Unknown provider: {}", model.provider_name),
+		Err(e) => throw!("Error fetching provider {}: {}", model.provider_name, e),
+	}
+}
+
+/// Adds a custom model, e.g. a dated snapshot like `gpt-4o-2024-08-06`
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn add_model(model: Model, data: DataState<'_>) -> Result<(), String> {
+	insert_custom_model(&model, &data.0.db_pool).await
+}
+
+/// Inserts a model added by hand, which model listings never deprecate
+pub async fn insert_custom_model(model: &Model, pool: &SqlitePool) -> Result<(), String> {
+	validate_model(model, pool).await?;
+	let insert_query = "INSERT INTO models (provider_name, model_name, model_display_name, show, max_tokens, context_window, deprecated, custom) VALUES ($1, $2, $3, $4, $5, $6, FALSE, TRUE)";
+	match sqlx::query(insert_query)
+		.bind(&model.provider_name)
+		.bind(model.model_name.trim())
+		.bind(model.model_display_name.trim())
+		.bind(&model.show)
+		.bind(&model.max_tokens)
+		.bind(&model.context_window)
+		.execute(pool)
+		.await
+	{
+		Ok(_) => Ok(()),
+		Err(sqlx::Error::Database(e)) if e.is_unique_violation() => throw!("Model {} already exists for provider {}", model.model_name, model.provider_name),
+		Err(e) => throw!("Error adding model {}: {}", model.model_name, e),
+	}
+}
+
+/// Updates display name, visibility and token limits of a model, identified by provider and model name
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn update_model(model: Model, data: DataState<'_>) -> Result<(), String> {
+	let data = &data.0;
+	validate_model(&model, &data.db_pool).await?;
+	let update_query = "UPDATE models SET model_display_name = $1, show = $2, max_tokens = $3, context_window = $4 WHERE provider_name = $5 AND model_name = $6";
+	match sqlx::query(update_query)
+		.bind(model.model_display_name.trim())
+		.bind(&model.show)
+		.bind(&model.max_tokens)
+		.bind(&model.context_window)
+		.bind(&model.provider_name)
+		.bind(&model.model_name)
+		.execute(&data.db_pool)
+		.await
+	{
+		Ok(result) if result.rows_affected() == 0 => throw!("Model {} does not exist for provider {}", model.model_name, model.provider_name),
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error updating model {}: {}", model.model_name, e),
+	}
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn delete_model(provider_name: String, model_name: String, data: DataState<'_>) -> Result<(), String> {
+	let data = &data.0;
+	let delete_query = "DELETE FROM models WHERE provider_name = $1 AND model_name = $2";
+	match sqlx::query(delete_query).bind(&provider_name).bind(&model_name).execute(&data.db_pool).await {
+		Ok(result) if result.rows_affected() == 0 => throw!("Model {} does not exist for provider {}", model_name, provider_name),
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error deleting model {}: {}", model_name, e),
+	}
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn set_model_visibility(provider_name: String, model_name: String, show: bool, data: DataState<'_>) -> Result<(), String> {
+	let data = &data.0;
+	let update_query = "UPDATE models SET show = $1 WHERE provider_name = $2 AND model_name = $3";
+	match sqlx::query(update_query).bind(show).bind(&provider_name).bind(&model_name).execute(&data.db_pool).await {
+		Ok(result) if result.rows_affected() == 0 => throw!("Model {} does not exist for provider {}", model_name, provider_name),
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error updating visibility of model {}: {}", model_name, e),
+	}
+}
+
+/// The chats that aren't archived, most recently updated first
+pub async fn fetch_chats(pool: &SqlitePool) -> Result<Chats, String> {
 	let fetch_query = "SELECT id, display_name, creation_date, last_updated FROM chats WHERE archived = 'false' ORDER BY last_updated DESC";
-	let chats = Chats(
-		sqlx::query_as::<_, Chat>(fetch_query)
-			.fetch_all(&data.db_pool)
-			.await
-			.map_err(|e| e.to_string())?,
-	);
+	let chats = Chats(sqlx::query_as::<_, Chat>(fetch_query).fetch_all(pool).await.map_err(|e| e.to_string())?);
 	Ok(chats)
 }
 
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn get_chats(data: DataState<'_>) -> Result<Chats, String> {
+	data.0.chat_service().chats().await
+}
+
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn load_chat(chat_id: String, data: DataState<'_>) -> Result<Vec<Message>, String> {
-	let data = data.0.lock().await;
-	let fetch_query = "SELECT id, role, content, model_name FROM messages WHERE chat_id = $1";
-	let messages_result = sqlx::query_as::<_, Message>(fetch_query).bind(&chat_id).fetch_all(&data.db_pool).await;
+	data.0.chat_service().messages(&chat_id).await
+}
+
+/// The messages of a chat including their rendered blocks
+pub async fn fetch_chat(chat_id: &str, pool: &SqlitePool) -> Result<Vec<Message>, String> {
+	let fetch_query = "SELECT id, role, content, model_name, tool_calls, tool_call_id FROM messages WHERE chat_id = $1";
+	let messages_result = sqlx::query_as::<_, Message>(fetch_query).bind(&chat_id).fetch_all(pool).await;
 
 	match messages_result {
 		Ok(mut messages) => {
@@ -223,7 +668,7 @@ pub async fn load_chat(chat_id: String, data: DataState<'_>) -> Result<Vec<Messa
 			for message in messages.iter_mut() {
 				let _ = match sqlx::query_as::<_, MessageBlock>(message_blocks_fetch_query)
 					.bind(&message.id)
-					.fetch_all(&data.db_pool)
+					.fetch_all(pool)
 					.await
 				{
 					Ok(message_blocks) => message.blocks = Some(MessageBlocks(message_blocks)),
@@ -241,19 +686,23 @@ pub async fn load_chat(chat_id: String, data: DataState<'_>) -> Result<Vec<Messa
 	}
 }
 
-pub async fn insert_message(new_message_id: &str, role: &str, message: &str, chat_id: &str, model_name: &str, data: DataState<'_>) {
-	let insert_message_query: &str = "INSERT INTO messages (id, role, content, chat_id, model_name) VALUES ($1, $2, $3, $4, $5)";
+pub async fn insert_message(message: &Message, chat_id: &str, pool: &SqlitePool) {
+	let insert_message_query: &str =
+		"INSERT INTO messages (id, role, content, chat_id, model_name, tool_calls, tool_call_id) VALUES ($1, $2, $3, $4, $5, $6, $7)";
+	let tool_calls = message.tool_calls.as_ref().and_then(|tool_calls| serde_json::to_string(tool_calls).ok());
 	let _ = sqlx::query(insert_message_query)
-		.bind(&new_message_id)
-		.bind(&role)
-		.bind(&message)
+		.bind(&message.id)
+		.bind(&message.role)
+		.bind(&message.content)
 		.bind(&chat_id)
-		.bind(&model_name)
-		.execute(&data.0.lock().await.db_pool)
+		.bind(&message.model_name)
+		.bind(&tool_calls)
+		.bind(&message.tool_call_id)
+		.execute(pool)
 		.await;
 }
 
-pub async fn insert_message_blocks(message_id: &str, message_blocks: &MessageBlocks, data: DataState<'_>) {
+pub async fn insert_message_blocks(message_id: &str, message_blocks: &MessageBlocks, pool: &SqlitePool) {
 	let insert_message_blocks_query: &str =
 		"INSERT INTO message_blocks (message_id, type_, language, raw_content, rendered_content, copied) VALUES ($1, $2, $3, $4, $5, $6)";
 	for block in message_blocks.iter() {
@@ -263,8 +712,8 @@ pub async fn insert_message_blocks(message_id: &str, message_blocks: &MessageBlo
 			.bind(&block.language)
 			.bind(&block.raw_content)
 			.bind(&block.rendered_content)
-			.bind(0)
-			.execute(&data.0.lock().await.db_pool)
+			.bind(block.copied.unwrap_or(false))
+			.execute(pool)
 			.await;
 		match insert_message_blocks_query_result {
 			Ok(_) => (),
@@ -275,15 +724,46 @@ pub async fn insert_message_blocks(message_id: &str, message_blocks: &MessageBlo
 	}
 }
 
-pub async fn get_chat_display_name(chat_id: &str, data: DataState<'_>) -> Result<Option<(String,)>, sqlx::Error> {
+pub async fn insert_attachments(message_id: &str, chat_id: &str, attachments: &[Attachment], pool: &SqlitePool) -> Result<(), String> {
+	let insert_attachment_query =
+		"INSERT INTO attachments (id, message_id, chat_id, file_name, mime_type, data, path, extracted_text) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
+	for attachment in attachments {
+		if let Err(e) = sqlx::query(insert_attachment_query)
+			.bind(&attachment.id)
+			.bind(message_id)
+			.bind(chat_id)
+			.bind(&attachment.file_name)
+			.bind(&attachment.mime_type)
+			.bind(&attachment.data)
+			.bind(&attachment.path)
+			.bind(&attachment.extracted_text)
+			.execute(pool)
+			.await
+		{
+			throw!("Error inserting attachment {} into database: {}", attachment.file_name, e);
+		}
+	}
+	Ok(())
+}
+
+/// The files attached to the messages of a chat, by message id
+pub async fn fetch_attachments(chat_id: &str, pool: &SqlitePool) -> Result<HashMap<String, Vec<Attachment>>, sqlx::Error> {
+	let attachments_query = "SELECT message_id, id, file_name, mime_type, data, path, extracted_text FROM attachments WHERE chat_id = $1 ORDER BY rowid";
+	let rows = sqlx::query(attachments_query).bind(chat_id).fetch_all(pool).await?;
+	let mut attachments: HashMap<String, Vec<Attachment>> = HashMap::new();
+	for row in rows {
+		let message_id: String = row.try_get("message_id")?;
+		attachments.entry(message_id).or_default().push(Attachment::from_row(&row)?);
+	}
+	Ok(attachments)
+}
+
+pub async fn get_chat_display_name(chat_id: &str, pool: &SqlitePool) -> Result<Option<(String,)>, sqlx::Error> {
 	let chat_display_name_query: &str = "SELECT display_name FROM chats WHERE id = $1";
-	sqlx::query_as(chat_display_name_query)
-		.bind(&chat_id)
-		.fetch_optional(&data.0.lock().await.db_pool)
-		.await
+	sqlx::query_as(chat_display_name_query).bind(&chat_id).fetch_optional(pool).await
 }
 
-pub async fn insert_chat_display_name(chat_id: &str, model_name: &str, display_name: &str, data: DataState<'_>) -> Result<(), String> {
+pub async fn insert_chat_display_name(chat_id: &str, model_name: &str, display_name: &str, pool: &SqlitePool) -> Result<(), String> {
 	let insert_chat_display_name_query =
 		"INSERT INTO chats (id, model, api_key_id, display_name, archived, last_updated) VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP)";
 	match sqlx::query(insert_chat_display_name_query)
@@ -292,7 +772,7 @@ pub async fn insert_chat_display_name(chat_id: &str, model_name: &str, display_n
 		.bind("NA")
 		.bind(&display_name)
 		.bind("false")
-		.execute(&data.0.lock().await.db_pool)
+		.execute(pool)
 		.await
 	{
 		Ok(_) => Ok(()),
@@ -303,23 +783,31 @@ pub async fn insert_chat_display_name(chat_id: &str, model_name: &str, display_n
 	}
 }
 
-pub async fn get_api_key(provider_name: &str, data: DataState<'_>) -> Result<String, String> {
-	let api_key_query: &str = "SELECT api_key FROM providers WHERE provider_name = $1";
-	match sqlx::query_as::<_, (String,)>(api_key_query)
+/// Resolves the key for a chat: the key pinned through `chats.api_key_id` if it belongs to the provider,
+/// otherwise the provider's default key.
+pub async fn get_api_key(provider_name: &str, chat_id: Option<&str>, secrets: &SecretStore, pool: &SqlitePool) -> Result<String, String> {
+	let api_key_query: &str = "SELECT key_ref, key_storage, key_encrypted FROM api_keys WHERE provider_name = $1 AND id = COALESCE(
+			(SELECT api_key_id FROM chats WHERE id = $2 AND api_key_id IN (SELECT id FROM api_keys WHERE provider_name = $1)),
+			(SELECT default_api_key_id FROM providers WHERE provider_name = $1)
+		)";
+	match sqlx::query_as::<_, (String, String, String)>(api_key_query)
 		.bind(&provider_name)
-		.fetch_one(&data.0.lock().await.db_pool)
+		.bind(&chat_id)
+		.fetch_optional(pool)
 		.await
 	{
-		Ok(api_key) => Ok(api_key.0),
+		Ok(Some((key_ref, storage, encrypted))) => secrets.load_secret(&key_ref, &storage, &encrypted),
 		// Models should not be provided if the API key is not set, therefore throw an error
+		Ok(None) => throw!("No API key set for provider {}", &provider_name),
 		Err(e) => throw!("Error fetching API key for provider {}: {}", &provider_name, e),
 	}
 }
 
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn read_api_keys_from_env(data: DataState<'_>) -> Result<(), String> {
-	let data: tokio::sync::MutexGuard<'_, crate::data::Data> = data.0.lock().await;
+	let data = &data.0;
 	dotenv().ok();
 	let development = env::var("DEVELOPMENT").unwrap_or_else(|_| "0".to_string());
 	if development == "0" {
@@ -332,14 +820,15 @@ pub async fn read_api_keys_from_env(data: DataState<'_>) -> Result<(), String> {
 	api_keys.insert("anthropic", env::var("anthropic").unwrap_or("".to_string()));
 	api_keys.insert("mistralai", env::var("mistralai").unwrap_or("".to_string()));
 	api_keys.insert("groqcloud", env::var("groqcloud").unwrap_or("".to_string()));
-	let insert_api_keys_query: &str = "UPDATE providers SET api_key=$1 WHERE provider_name = $2";
-	for (provider_name, api_key) in api_keys.iter() {
-		match sqlx::query(insert_api_keys_query)
-			.bind(&api_key)
-			.bind(&provider_name)
-			.execute(&data.db_pool)
-			.await
-		{
+	let known_providers: Vec<String> = match sqlx::query_as::<_, (String,)>("SELECT provider_name FROM providers").fetch_all(&data.db_pool).await {
+		Ok(rows) => rows.into_iter().map(|(provider_name,)| provider_name).collect(),
+		Err(e) => throw!("Error getting providers: {}", e),
+	};
+	for (provider_name, api_key) in api_keys
+		.iter()
+		.filter(|(provider_name, api_key)| !api_key.is_empty() && known_providers.contains(&provider_name.to_string()))
+	{
+		match set_default_api_key_secret(provider_name, api_key, &data.secrets, &data.db_pool).await {
 			Ok(_) => {
 				//println!("API key for provider {} saved to the database", &provider_name);
 			}
@@ -352,30 +841,33 @@ pub async fn read_api_keys_from_env(data: DataState<'_>) -> Result<(), String> {
 	return Ok(());
 }
 
-pub async fn get_messages(chat_id: &str, data: DataState<'_>) -> Result<MessageHistory, anyhow::Error> {
-	let messages_query: &str = "SELECT id, role, content, model_name FROM messages WHERE chat_id = $1";
-	let messages = sqlx::query_as::<_, Message>(messages_query)
+pub async fn get_messages(chat_id: &str, pool: &SqlitePool) -> Result<MessageHistory, anyhow::Error> {
+	let messages_query: &str = "SELECT id, role, content, model_name, tool_calls, tool_call_id FROM messages WHERE chat_id = $1";
+	let mut messages = sqlx::query_as::<_, Message>(messages_query)
 		.bind(&chat_id)
-		.fetch_all(&data.0.lock().await.db_pool)
+		.fetch_all(pool)
 		.await
 		.map_err(|e| {
 			eprintln!("Error fetching messages from database: {}", e);
 			anyhow::anyhow!("Database error: {}", e)
 		})?;
+	let mut attachments = fetch_attachments(chat_id, pool).await.map_err(|e| anyhow::anyhow!("Database error: {}", e))?;
+	for message in messages.iter_mut() {
+		message.attachments = attachments.remove(&message.id).unwrap_or_default();
+	}
 	Ok(MessageHistory(messages))
 }
 
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn rename_chat(chat_id: String, new_display_name: String, data: DataState<'_>) -> Result<(), String> {
-	let data = data.0.lock().await;
+	data.0.chat_service().rename_chat(&chat_id, &new_display_name).await
+}
+
+pub async fn update_chat_display_name(chat_id: &str, display_name: &str, pool: &SqlitePool) -> Result<(), String> {
 	let rename_chat_query: &str = "UPDATE chats SET display_name = $1 WHERE id = $2";
-	match sqlx::query(rename_chat_query)
-		.bind(&new_display_name)
-		.bind(&chat_id)
-		.execute(&data.db_pool)
-		.await
-	{
+	match sqlx::query(rename_chat_query).bind(&display_name).bind(&chat_id).execute(pool).await {
 		Ok(_) => Ok(()),
 		Err(e) => {
 			eprintln!("Error renaming chat: {}", e);
@@ -384,30 +876,79 @@ pub async fn rename_chat(chat_id: String, new_display_name: String, data: DataSt
 	}
 }
 
+/// The names of the tools the chat may use
+pub async fn fetch_chat_tools(chat_id: &str, pool: &SqlitePool) -> Result<Vec<String>, String> {
+	let query = "SELECT tool_name FROM chat_tools WHERE chat_id = $1";
+	match sqlx::query_scalar::<_, String>(query).bind(chat_id).fetch_all(pool).await {
+		Ok(tool_names) => Ok(tool_names),
+		Err(e) => throw!("Error fetching the tools of chat {}: {}", chat_id, e),
+	}
+}
+
+pub async fn set_chat_tool_enabled(chat_id: &str, tool_name: &str, enabled: bool, pool: &SqlitePool) -> Result<(), String> {
+	let query = match enabled {
+		true => "INSERT INTO chat_tools (chat_id, tool_name) VALUES ($1, $2) ON CONFLICT(chat_id, tool_name) DO NOTHING",
+		false => "DELETE FROM chat_tools WHERE chat_id = $1 AND tool_name = $2",
+	};
+	match sqlx::query(query).bind(chat_id).bind(tool_name).execute(pool).await {
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error saving tool {} for chat {}: {}", tool_name, chat_id, e),
+	}
+}
+
+/// Moves the chat to the top of the chat list
+pub async fn touch_chat(chat_id: &str, pool: &SqlitePool) -> Result<(), String> {
+	let update_last_updated_query: &str = "UPDATE chats SET last_updated = CURRENT_TIMESTAMP WHERE id = $1";
+	match sqlx::query(update_last_updated_query).bind(&chat_id).execute(pool).await {
+		Ok(_) => Ok(()),
+		Err(e) => throw!("Error updating chat {}: {}", chat_id, e),
+	}
+}
+
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn archive_chat(chat_id: String, data: DataState<'_>) -> Result<(), String> {
-	let data = data.0.lock().await;
-	let archive_chat_query: &str = "UPDATE chats SET archived = 'true' WHERE id = $1";
-	match sqlx::query(archive_chat_query).bind(&chat_id).execute(&data.db_pool).await {
-		Ok(_) => Ok(()),
-		Err(e) => {
-			eprintln!("Error archiving chat: {}", e);
-			Err(e.to_string())
-		}
-	}
+	data.0.chat_service().archive_chat(&chat_id).await
 }
 
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn delete_chat(chat_id: String, data: DataState<'_>) -> Result<(), String> {
-	let data = data.0.lock().await;
-	let delete_chat_query: &str = "DELETE FROM chats WHERE id = $1";
-	match sqlx::query(delete_chat_query).bind(&chat_id).execute(&data.db_pool).await {
-		Ok(_) => Ok(()),
-		Err(e) => {
-			eprintln!("Error deleting chat: {}", e);
-			Err(e.to_string())
-		}
+	data.0.chat_service().delete_chat(&chat_id).await
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn mark_block_copied(block_id: i32, data: DataState<'_>) -> Result<(), String> {
+	data.0.chat_service().mark_blocks_copied(&[block_id]).await
+}
+
+/// Asks where to save a block with a save dialog and writes its raw content there. The path always comes from the user,
+/// never from the webview. Returns the path that was written, or `None` if the user cancelled. Saved blocks count as copied.
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn save_block_to_file(block_id: i32, data: DataState<'_>) -> Result<Option<String>, String> {
+	let service = data.0.chat_service();
+	let block = service.message_block(block_id).await?;
+	let file_name = format!("snippet.{}", file_extension_for_language(block.language.as_deref().unwrap_or("plain")));
+	let path = tokio::task::spawn_blocking(move || FileDialogBuilder::new().set_file_name(&file_name).save_file())
+		.await
+		.map_err(|e| e.to_string())?;
+	match path {
+		// The dialog asked before replacing an existing file
+		Some(path) => Ok(Some(service.save_block_to_file(block_id, &path, true).await?.to_string_lossy().to_string())),
+		None => Ok(None),
 	}
 }
+
+/// Returns the code of all code blocks of a message, separated by blank lines, and marks them as copied
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn copy_all_code(message_id: String, data: DataState<'_>) -> Result<String, String> {
+	data.0.chat_service().copy_all_code(&message_id).await
+}
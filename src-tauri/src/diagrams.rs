@@ -0,0 +1,106 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use layout::backends::svg::SVGWriter;
+use layout::gv::parser::ast;
+use layout::gv::{DotParser, GraphBuilder};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::throw;
+
+lazy_static! {
+	/// Only the SVG elements and attributes that the DOT renderer emits
+	static ref SVG_SANITIZER: ammonia::Builder<'static> = {
+		let mut builder = ammonia::Builder::empty();
+		builder
+			.add_tags(&["svg", "g", "defs", "marker", "clipPath", "rect", "path", "polygon", "ellipse", "line", "text", "tspan", "textPath"])
+			.add_generic_attributes(&[
+				"xmlns", "viewBox", "width", "height", "x", "y", "dy", "cx", "cy", "rx", "ry", "d", "points", "fill", "stroke", "stroke-width",
+				"font-size", "text-anchor", "dominant-baseline", "id", "href", "startOffset", "orient", "markerWidth", "markerHeight", "refX",
+				"refY", "marker-start", "marker-end", "clip-path",
+			]);
+		builder
+	};
+	static ref FONT_CLASS: Regex = Regex::new(r#"class="a(\d+)""#).unwrap();
+}
+
+/// Bigger sizes overflow the layout's arithmetic
+const MAX_DOT_SIZE_ATTRIBUTE: usize = 1000;
+
+/// Languages of code fences that are rendered as diagrams
+pub fn is_diagram_language(language: &str) -> bool {
+	matches!(language, "dot" | "graphviz" | "gv" | "mermaid")
+}
+
+/// Renders a diagram to sanitized inline SVG. Callers fall back to the highlighted source on error.
+pub fn render_diagram(language: &str, source: &str) -> Result<String, String> {
+	let svg = match language {
+		"dot" | "graphviz" | "gv" => render_dot(source)?,
+		// There is no pure-Rust Mermaid renderer yet
+		"mermaid" => throw!("Mermaid diagrams can't be rendered yet"),
+		_ => throw!("Unsupported diagram language: {}", language),
+	};
+	Ok(sanitize_svg(&svg, source))
+}
+
+/// The source comes from the models, and a panic in the layout aborts the app in release builds.
+/// So only graphs without the features layout-rs is known to panic on are rendered.
+fn render_dot(source: &str) -> Result<String, String> {
+	let mut parser = DotParser::new(source);
+	let graph = parser.process().map_err(|e| format!("Could not parse DOT graph: {}", e))?;
+	check_dot_statements(&graph.list)?;
+	let mut graph_builder = GraphBuilder::new();
+	graph_builder.visit_graph(&graph);
+	let mut visual_graph = graph_builder.get();
+	// The layout panics on empty graphs
+	if visual_graph.num_nodes() == 0 {
+		throw!("The graph has no nodes");
+	}
+	let mut svg_writer = SVGWriter::new();
+	visual_graph.do_it(false, false, false, &mut svg_writer);
+	Ok(svg_writer.finalize())
+}
+
+/// Rejects record shapes, whose label parser panics on labels like `""` or `"{"`,
+/// and font sizes and widths too big for the layout
+fn check_dot_statements(statements: &ast::StmtList) -> Result<(), String> {
+	for statement in &statements.list {
+		let attributes = match statement {
+			ast::Stmt::Node(node) => &node.list,
+			ast::Stmt::Edge(edge) => &edge.list,
+			ast::Stmt::Attribute(attribute) => &attribute.list,
+			ast::Stmt::SubGraph(subgraph) => {
+				check_dot_statements(&subgraph.list)?;
+				continue;
+			}
+		};
+		for (name, value) in attributes.iter() {
+			match name.as_str() {
+				"shape" if value.eq_ignore_ascii_case("record") || value.eq_ignore_ascii_case("mrecord") => {
+					throw!("Record shapes can't be rendered yet")
+				}
+				"fontsize" | "width" if value.parse::<usize>().map_or(false, |size| size > MAX_DOT_SIZE_ATTRIBUTE) => {
+					throw!("{} {} is too big, at most {} is supported", name, value, MAX_DOT_SIZE_ATTRIBUTE)
+				}
+				_ => (),
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Sanitizes the SVG and makes it safe to inline next to other diagrams: the font classes of the
+/// renderer's `<style>` become attributes, and ids get a per-diagram prefix so references don't collide.
+fn sanitize_svg(svg: &str, source: &str) -> String {
+	let mut hasher = DefaultHasher::new();
+	source.hash(&mut hasher);
+	let prefix = format!("d{:x}-", hasher.finish());
+
+	let svg = FONT_CLASS.replace_all(svg, r#"font-size="$1""#);
+	let svg = svg
+		.replace("id=\"", &format!("id=\"{}", prefix))
+		.replace("href=\"#", &format!("href=\"#{}", prefix))
+		.replace("url(#", &format!("url(#{}", prefix));
+	SVG_SANITIZER.clean(&svg).to_string()
+}
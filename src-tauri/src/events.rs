@@ -0,0 +1,33 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// Receives the events the backend sends to the UI, e.g. `newMessage`. In the app this is the window,
+/// so the core logic can also run without one, e.g. in the CLI and in tests.
+pub trait EventSink: Send + Sync {
+	fn emit_value(&self, event: &str, payload: Value);
+}
+
+impl dyn EventSink {
+	pub fn emit<T: Serialize>(&self, event: &str, payload: T) {
+		match serde_json::to_value(payload) {
+			Ok(payload) => self.emit_value(event, payload),
+			Err(e) => log::error!("Could not serialize payload of event {}: {}", event, e),
+		}
+	}
+}
+
+#[cfg(feature = "gui")]
+impl EventSink for tauri::Window {
+	fn emit_value(&self, event: &str, payload: Value) {
+		if let Err(e) = tauri::Window::emit(self, event, payload) {
+			log::error!("Could not emit event {}: {}", event, e);
+		}
+	}
+}
+
+/// Drops all events, for when there is no UI to update
+pub struct NoEvents;
+
+impl EventSink for NoEvents {
+	fn emit_value(&self, _event: &str, _payload: Value) {}
+}
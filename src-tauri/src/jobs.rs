@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
+use sqlx::prelude::FromRow;
+use sqlx::SqlitePool;
+#[cfg(feature = "gui")]
+use tauri::command;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::data::Data;
+#[cfg(feature = "gui")]
+use crate::data::DataState;
+use crate::providers::{generate_answer, generate_title};
+use crate::settings::rehighlight_code_blocks;
+use crate::throw;
+
+const JOB_COLUMNS: &str = "id, kind, chat_id, payload, status, result, error, attempts, created_at, updated_at";
+/// Finished jobs are kept for a while so the UI can still show what happened to them
+const FINISHED_JOB_RETENTION: &str = "-7 days";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Type, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum JobStatus {
+	Queued,
+	Running,
+	Done,
+	Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Type, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum JobKind {
+	GenerateAnswer,
+	GenerateTitle,
+	Rehighlight,
+}
+
+/// A row of the `jobs` table. `payload` and `result` are JSON.
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow, Type)]
+pub struct Job {
+	pub id: String,
+	pub kind: JobKind,
+	pub chat_id: Option<String>,
+	pub payload: String,
+	pub status: JobStatus,
+	pub result: Option<String>,
+	pub error: Option<String>,
+	pub attempts: i32,
+	pub created_at: String,
+	pub updated_at: String,
+}
+
+/// Hands persisted jobs to the worker. The `jobs` table is the source of truth, the channel only carries ids.
+#[derive(Clone)]
+pub struct JobQueue {
+	sender: UnboundedSender<String>,
+}
+
+impl JobQueue {
+	pub fn new() -> (Self, UnboundedReceiver<String>) {
+		let (sender, receiver) = unbounded_channel();
+		(JobQueue { sender }, receiver)
+	}
+
+	fn notify(&self, job_id: String) {
+		if let Err(e) = self.sender.send(job_id) {
+			log::error!("Job worker is gone, job {} stays queued until the next start", e.0);
+		}
+	}
+}
+
+/// Persists a job and hands it to the worker
+pub async fn enqueue(data: &Data, kind: JobKind, chat_id: Option<&str>, payload: Value) -> Result<Job, String> {
+	let job_id = uuid::Uuid::new_v4().to_string();
+	let insert_job_query = "INSERT INTO jobs (id, kind, chat_id, payload) VALUES ($1, $2, $3, $4)";
+	if let Err(e) = sqlx::query(insert_job_query)
+		.bind(&job_id)
+		.bind(kind)
+		.bind(chat_id)
+		.bind(payload.to_string())
+		.execute(&data.db_pool)
+		.await
+	{
+		throw!("Error queueing job: {}", e);
+	}
+	let job = fetch_job(&job_id, &data.db_pool).await?;
+	data.events.emit("jobChanged", &job);
+	data.jobs.notify(job_id);
+	Ok(job)
+}
+
+async fn fetch_job(job_id: &str, pool: &SqlitePool) -> Result<Job, String> {
+	let query = format!("SELECT {} FROM jobs WHERE id = $1", JOB_COLUMNS);
+	match sqlx::query_as::<_, Job>(&query).bind(job_id).fetch_one(pool).await {
+		Ok(job) => Ok(job),
+		Err(e) => throw!("Error loading job {}: {}", job_id, e),
+	}
+}
+
+/// Moves a queued job to running. Returns `None` if it isn't queued (anymore), e.g. because it was handed to the worker twice.
+async fn start_job(data: &Data, job_id: &str) -> Result<Option<Job>, String> {
+	let query = "UPDATE jobs SET status = 'running', attempts = attempts + 1, updated_at = CURRENT_TIMESTAMP WHERE id = $1 AND status = 'queued'";
+	match sqlx::query(query).bind(job_id).execute(&data.db_pool).await {
+		Ok(result) if result.rows_affected() == 0 => return Ok(None),
+		Ok(_) => (),
+		Err(e) => throw!("Error starting job {}: {}", job_id, e),
+	}
+	let job = fetch_job(job_id, &data.db_pool).await?;
+	data.events.emit("jobChanged", &job);
+	Ok(Some(job))
+}
+
+async fn finish_job(data: &Data, job_id: &str, outcome: Result<Value, String>) -> Result<(), String> {
+	let (status, result, error) = match outcome {
+		Ok(result) => (JobStatus::Done, Some(result.to_string()), None),
+		Err(e) => {
+			log::error!("Job {} failed: {}", job_id, e);
+			(JobStatus::Failed, None, Some(e))
+		}
+	};
+	let query = "UPDATE jobs SET status = $1, result = $2, error = $3, updated_at = CURRENT_TIMESTAMP WHERE id = $4";
+	if let Err(e) = sqlx::query(query).bind(status).bind(&result).bind(&error).bind(job_id).execute(&data.db_pool).await {
+		throw!("Error finishing job {}: {}", job_id, e);
+	}
+	let job = fetch_job(job_id, &data.db_pool).await?;
+	data.events.emit("jobChanged", &job);
+	Ok(())
+}
+
+async fn run_job(data: Arc<Data>, job_id: String) {
+	let job = match start_job(&data, &job_id).await {
+		Ok(Some(job)) => job,
+		Ok(None) => return,
+		Err(e) => {
+			log::error!("{}", e);
+			return;
+		}
+	};
+	let outcome = match job.kind {
+		JobKind::GenerateAnswer => generate_answer(&data, &job).await,
+		JobKind::GenerateTitle => generate_title(&data, &job).await,
+		JobKind::Rehighlight => rehighlight_code_blocks(data.clone()).await.map(|_| Value::Null),
+	};
+	if let Err(e) = finish_job(&data, &job_id, outcome).await {
+		log::error!("{}", e);
+	}
+}
+
+/// Requeues jobs that were interrupted by a crash or quit and cleans up old finished jobs. Returns the ids of the queued jobs.
+/// Running them again is safe: an answer job that already stored its answer doesn't ask again and tools that already ran
+/// don't run again (see `ChatService::answer`), titles and highlighting are just redone.
+pub async fn resume_jobs(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
+	sqlx::query("UPDATE jobs SET status = 'queued', updated_at = CURRENT_TIMESTAMP WHERE status = 'running'")
+		.execute(pool)
+		.await?;
+	sqlx::query("DELETE FROM jobs WHERE status IN ('done', 'failed') AND updated_at < datetime('now', $1)")
+		.bind(FINISHED_JOB_RETENTION)
+		.execute(pool)
+		.await?;
+	let queued_jobs = sqlx::query_as::<_, (String,)>("SELECT id FROM jobs WHERE status = 'queued' ORDER BY created_at")
+		.fetch_all(pool)
+		.await?;
+	Ok(queued_jobs.into_iter().map(|(job_id,)| job_id).collect())
+}
+
+/// Resumes unfinished jobs, then runs jobs as they are queued. Jobs run concurrently, so chats don't wait for each other.
+pub async fn run_worker(data: Arc<Data>, mut receiver: UnboundedReceiver<String>) {
+	match resume_jobs(&data.db_pool).await {
+		Ok(job_ids) => {
+			if !job_ids.is_empty() {
+				log::info!("Resuming {} unfinished jobs", job_ids.len());
+			}
+			for job_id in job_ids {
+				data.jobs.notify(job_id);
+			}
+		}
+		Err(e) => log::error!("Error resuming jobs: {}", e),
+	}
+	while let Some(job_id) = receiver.recv().await {
+		tokio::spawn(run_job(data.clone(), job_id));
+	}
+}
+
+/// Newest first, optionally only the jobs of one chat
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn list_jobs(chat_id: Option<String>, data: DataState<'_>) -> Result<Vec<Job>, String> {
+	let query = format!("SELECT {} FROM jobs WHERE $1 IS NULL OR chat_id = $1 ORDER BY created_at DESC, rowid DESC LIMIT 100", JOB_COLUMNS);
+	match sqlx::query_as::<_, Job>(&query).bind(&chat_id).fetch_all(&data.0.db_pool).await {
+		Ok(jobs) => Ok(jobs),
+		Err(e) => throw!("Error loading jobs: {}", e),
+	}
+}
+
+/// Queues a failed job again
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn retry_job(job_id: String, data: DataState<'_>) -> Result<Job, String> {
+	let query = "UPDATE jobs SET status = 'queued', error = NULL, updated_at = CURRENT_TIMESTAMP WHERE id = $1 AND status = 'failed'";
+	match sqlx::query(query).bind(&job_id).execute(&data.0.db_pool).await {
+		Ok(result) if result.rows_affected() == 0 => throw!("Job {} has not failed", job_id),
+		Ok(_) => (),
+		Err(e) => throw!("Error retrying job {}: {}", job_id, e),
+	}
+	let job = fetch_job(&job_id, &data.0.db_pool).await?;
+	data.0.events.emit("jobChanged", &job);
+	data.0.jobs.notify(job_id);
+	Ok(job)
+}
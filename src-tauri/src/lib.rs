@@ -1,8 +1,19 @@
+pub mod attachments;
 pub mod data;
 pub mod db;
+pub mod diagrams;
+pub mod events;
+pub mod jobs;
 pub mod llm_providers;
+pub mod math;
+pub mod mcp;
 pub mod providers;
+pub mod secrets;
+pub mod service;
 pub mod settings;
+pub mod syntaxes;
+pub mod themes;
+pub mod tools;
 pub mod types;
 pub mod utils;
 
@@ -1,88 +1,288 @@
-use anyhow;
-use anyhow_tauri::{IntoTAResult, TAResult as Result};
-use langchain_rust::language_models::llm::LLM;
-use langchain_rust::{language_models::options::CallOptions, llm::Claude};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+
+use crate::attachments::{images, inline_text};
+use crate::llm_providers::http::{self, send_with_retry};
+use crate::llm_providers::{KeyValidation, LLMConfig, LLMMessage, LLMResponse, ProviderModel, ToolCall, ToolSpec};
+use crate::types::MessageHistory;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Clone)]
+pub struct AnthropicProvider {
+	api_key: String,
+	base_url: String,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct AnthropicChatResponse {
+struct AnthropicChatResponse {
 	content: Vec<MessageContent>,
 	id: String,
 	model: String,
 	role: String,
-	stop_reason: String,
+	stop_reason: Option<String>,
 	stop_sequence: Option<String>,
 	#[serde(rename = "type")]
 	message_type: String,
 	usage: AnthropicUsage,
 }
 
+/// A `text` or a `tool_use` block of an answer
 #[derive(Serialize, Deserialize, Debug)]
-pub struct MessageContent {
+struct MessageContent {
+	#[serde(default)]
 	text: String,
 	#[serde(rename = "type")]
 	content_type: String,
+	id: Option<String>,
+	name: Option<String>,
+	input: Option<Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct AnthropicUsage {
-	input_tokens: i32,
-	output_tokens: i32,
+	input_tokens: u32,
+	output_tokens: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AnthropicErrorResponse {
+/// One event of a streamed message. Only `content_block_delta` carries text, `error` reports a failure mid-stream.
+#[derive(Deserialize, Debug)]
+struct AnthropicStreamEvent {
 	#[serde(rename = "type")]
-	error_type: String,
-	error: AnthropicErrorDetails,
+	event_type: String,
+	delta: Option<AnthropicDelta>,
+	error: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AnthropicErrorDetails {
-	#[serde(rename = "type")]
-	error_type: String,
-	message: String,
+#[derive(Deserialize, Debug)]
+struct AnthropicDelta {
+	text: Option<String>,
 }
 
-pub async fn send_anthropic_message(body: Value, api_key: &str) -> Result<String> {
-	let call_options = CallOptions {
-		candidate_count: None,
-		max_tokens: body["max_tokens"].as_u64().map(|v| v as u32),
-		temperature: body["temperature"].as_f64().map(|v| v as f32),
-		stop_words: None,
-		streaming_func: None,
-		top_k: None,
-		top_p: None,
-		seed: None,
-		min_length: None,
-		max_length: None,
-		n: None,
-		repetition_penalty: None,
-		frequency_penalty: None,
-		presence_penalty: None,
-		functions: None,
-		function_call_behavior: None,
-	};
-
-	let claude = Claude::default().with_api_key(api_key).with_options(call_options);
-	let messages = body["messages"].as_array().ok_or_else(|| anyhow::anyhow!("Missing 'messages' array"))?;
-	let history: String = messages
-		.iter()
-		.map(|msg| format!("{}: {}", msg["role"].as_str().unwrap_or(""), msg["content"].as_str().unwrap_or("")))
-		.collect::<Vec<String>>()
-		.join("\n");
-
-	match claude.invoke(&history).await {
-		Ok(response) => Ok(response),
-		Err(e) => {
-			if e.to_string().to_lowercase().contains("authentication") {
-				eprintln!("Authentication error with Claude: {}", e);
-				anyhow::anyhow!("Invalid API key or authentication failed").into_ta_result()
-			} else {
-				eprintln!("Error invoking Claude: {}", e);
-				anyhow::anyhow!("An error occurred: {}", e).into_ta_result()
+#[derive(Deserialize, Debug)]
+struct AnthropicModelList {
+	data: Vec<AnthropicModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicModel {
+	id: String,
+	display_name: Option<String>,
+}
+
+impl AnthropicProvider {
+	/// `base_url` is the API root, e.g. `https://api.anthropic.com/v1`
+	pub fn new(api_key: &str, base_url: &str) -> Self {
+		Self {
+			api_key: api_key.to_string(),
+			base_url: base_url.trim_end_matches('/').to_string(),
+		}
+	}
+
+	pub async fn list_models(&self) -> Result<Vec<ProviderModel>> {
+		let client = http::client();
+		let request = client
+			.get(format!("{}/models", self.base_url))
+			.query(&[("limit", "1000")])
+			.header("x-api-key", &self.api_key)
+			.header("anthropic-version", ANTHROPIC_VERSION);
+		let response: Response = send_with_retry(request, None)
+			.await
+			.context("Failed to fetch models")?
+			.error_for_status()
+			.context("Failed to fetch models")?;
+
+		let model_list: AnthropicModelList = response.json().await.context("Failed to parse model list")?;
+		Ok(model_list
+			.data
+			.into_iter()
+			.map(|model| ProviderModel {
+				id: model.id,
+				display_name: model.display_name,
+				context_window: None,
+				max_output_tokens: None,
+			})
+			.collect())
+	}
+
+	/// Tool calls become `tool_use` blocks of the assistant's message and results `tool_result` blocks of a user message.
+	/// The results of one answer's calls go into the same message, since user and assistant have to take turns.
+	fn chat_messages(messages: &MessageHistory) -> Vec<Value> {
+		let mut anthropic_messages: Vec<Value> = vec![];
+		for msg in messages.iter() {
+			let (role, mut blocks) = match (msg.role.as_str(), &msg.tool_calls) {
+				("tool", _) => (
+					"user",
+					vec![json!({ "type": "tool_result", "tool_use_id": msg.tool_call_id, "content": msg.content })],
+				),
+				(role, Some(tool_calls)) if !tool_calls.is_empty() => {
+					let mut blocks = vec![];
+					if !msg.content.is_empty() {
+						blocks.push(json!({ "type": "text", "text": msg.content }));
+					}
+					for call in tool_calls {
+						let input: Value = serde_json::from_str(&call.arguments).unwrap_or_else(|_| json!({}));
+						blocks.push(json!({ "type": "tool_use", "id": call.id, "name": call.name, "input": input }));
+					}
+					(role, blocks)
+				}
+				// Images go before the text, which works best according to Anthropic's docs
+				(role, _) if images(msg).next().is_some() => {
+					let mut blocks: Vec<Value> = images(msg)
+						.map(|image| json!({ "type": "image", "source": { "type": "base64", "media_type": image.mime_type, "data": image.base64_data() } }))
+						.collect();
+					blocks.push(json!({ "type": "text", "text": inline_text(msg) }));
+					(role, blocks)
+				}
+				_ => {
+					anthropic_messages.push(json!(LLMMessage {
+						role: msg.role.to_string(),
+						content: inline_text(msg),
+					}));
+					continue;
+				}
+			};
+			match anthropic_messages.last_mut() {
+				Some(last) if last["role"] == role && last["content"].is_array() => {
+					last["content"].as_array_mut().unwrap().append(&mut blocks);
+				}
+				_ => anthropic_messages.push(json!({ "role": role, "content": blocks })),
+			}
+		}
+		anthropic_messages
+	}
+
+	fn chat_request(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, tools: &[ToolSpec], stream: bool) -> RequestBuilder {
+		let mut body = json!({
+			"model": model,
+			"messages": Self::chat_messages(messages),
+			"temperature": config.temperature,
+			"max_tokens": config.max_tokens
+		});
+		if !tools.is_empty() {
+			body["tools"] = tools
+				.iter()
+				.map(|tool| json!({ "name": tool.name, "description": tool.description, "input_schema": tool.parameters }))
+				.collect();
+		}
+		if stream {
+			body["stream"] = json!(true);
+		}
+
+		log::debug!("Sending message to Anthropic: {:?}", body);
+
+		http::client()
+			.post(format!("{}/messages", self.base_url))
+			.header("Content-Type", "application/json")
+			.header("x-api-key", &self.api_key)
+			.header("anthropic-version", ANTHROPIC_VERSION)
+			.json(&body)
+	}
+
+	pub async fn send_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig) -> Result<String> {
+		Ok(self.send_message_with_tools(messages, model, config, &[]).await?.content)
+	}
+
+	pub async fn send_message_with_tools(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, tools: &[ToolSpec]) -> Result<LLMResponse> {
+		let request = self.chat_request(messages, model, config, tools, false);
+		let response: Response = send_with_retry(request, config.max_retries)
+			.await
+			.context("Failed to send message to Anthropic")?;
+
+		let response_text = response.text().await.context("Failed to read response from Anthropic")?;
+		Self::parse_answer(&response_text)
+	}
+
+	fn parse_answer(response_text: &str) -> Result<LLMResponse> {
+		if let Ok(parsed_response) = serde_json::from_str::<AnthropicChatResponse>(response_text) {
+			let answer = LLMResponse {
+				content: parsed_response
+					.content
+					.iter()
+					.filter(|content| content.content_type == "text")
+					.map(|content| content.text.as_str())
+					.collect(),
+				tool_calls: parsed_response
+					.content
+					.iter()
+					.filter(|content| content.content_type == "tool_use")
+					.map(|content| ToolCall {
+						id: content.id.clone().unwrap_or_default(),
+						name: content.name.clone().unwrap_or_default(),
+						arguments: content.input.as_ref().map_or_else(|| "{}".to_string(), |input| input.to_string()),
+					})
+					.collect(),
+			};
+			log::debug!("Answer: {:?}", answer);
+			return Ok(answer);
+		}
+
+		Err(anyhow::anyhow!("Something went wrong when sending message to Anthropic: {}", response_text))
+	}
+
+	pub async fn stream_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, on_delta: &mut (dyn FnMut(&str) + Send)) -> Result<String> {
+		let request = self.chat_request(messages, model, config, &[], true);
+		let response: Response = send_with_retry(request, config.max_retries)
+			.await
+			.context("Failed to send message to Anthropic")?;
+		if !response.status().is_success() {
+			let status = response.status();
+			let response_text = response.text().await.unwrap_or_default();
+			return Err(anyhow!(
+				"Something went wrong when sending message to Anthropic ({}): {}",
+				status,
+				response_text
+			));
+		}
+		// Servers that don't stream answer with the whole completion instead
+		if !http::is_event_stream(&response) {
+			let response_text = response.text().await.context("Failed to read response from Anthropic")?;
+			let answer = Self::parse_answer(&response_text)?.content;
+			on_delta(&answer);
+			return Ok(answer);
+		}
+
+		let mut answer = String::new();
+		http::read_events(response, |data| {
+			let event: AnthropicStreamEvent = serde_json::from_str(data).with_context(|| format!("Unexpected event from Anthropic: {}", data))?;
+			match event.event_type.as_str() {
+				"content_block_delta" => {
+					if let Some(text) = event.delta.and_then(|delta| delta.text) {
+						answer.push_str(&text);
+						on_delta(&text);
+					}
+				}
+				"error" => {
+					return Err(anyhow!(
+						"Something went wrong when sending message to Anthropic: {}",
+						event.error.unwrap_or_default()
+					))
+				}
+				_ => {}
+			}
+			Ok(())
+		})
+		.await
+		.context("Failed to read response from Anthropic")?;
+		log::debug!("Answer: {}", answer);
+		Ok(answer)
+	}
+
+	/// Checks the key against the free model listing endpoint instead of spending tokens on a completion
+	pub async fn validate_key(&self) -> KeyValidation {
+		let request = http::client()
+			.get(format!("{}/models", self.base_url))
+			.header("x-api-key", &self.api_key)
+			.header("anthropic-version", ANTHROPIC_VERSION);
+		match http::send(request).await {
+			Ok(response) => {
+				let status = response.status().as_u16();
+				let body = response.text().await.unwrap_or_default();
+				KeyValidation::from_http(status, &body)
 			}
+			Err(e) => KeyValidation::network_error(e),
 		}
 	}
 }
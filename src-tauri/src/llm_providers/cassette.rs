@@ -0,0 +1,295 @@
+//! Records provider exchanges to cassette files and replays them without network, for deterministic tests and demos.
+//!
+//! Set `BYOK_CASSETTE` to a file to route all provider requests of the app or the CLI through it.
+//! `BYOK_CASSETTE_MODE=record` sends the requests and appends them with their responses to the file,
+//! `replay` (the default) answers them from the file and never touches the network.
+//! Tests can scope a cassette to a future with `Cassette::run` instead.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use reqwest::{Client, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const REDACTED: &str = "REDACTED";
+/// Request headers that carry API keys. Their values are also redacted wherever else they show up.
+const SECRET_HEADERS: [&str; 4] = ["authorization", "x-api-key", "api-key", "cookie"];
+/// The response headers the providers look at, the others only add noise to the cassettes
+const RECORDED_RESPONSE_HEADERS: [&str; 2] = ["content-type", "retry-after"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CassetteMode {
+	Record,
+	Replay,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CassetteFile {
+	interactions: Vec<Interaction>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Interaction {
+	pub request: RecordedRequest,
+	pub response: RecordedResponse,
+}
+
+/// Requests match when method, URL and body are the same. The headers are only kept for reference.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedRequest {
+	pub method: String,
+	pub url: String,
+	#[serde(default)]
+	pub headers: BTreeMap<String, String>,
+	/// JSON bodies are kept as JSON so cassettes are easy to read and edit
+	#[serde(default)]
+	pub body: Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedResponse {
+	pub status: u16,
+	#[serde(default)]
+	pub headers: BTreeMap<String, String>,
+	/// The body in the pieces it arrived in, so a streamed answer keeps its events
+	pub chunks: Vec<String>,
+}
+
+#[derive(Default)]
+struct CassetteState {
+	interactions: Vec<Interaction>,
+	/// Which interactions were replayed, so repeated requests get the recorded responses in order
+	replayed: Vec<bool>,
+}
+
+pub struct Cassette {
+	path: PathBuf,
+	mode: CassetteMode,
+	state: Mutex<CassetteState>,
+}
+
+tokio::task_local! {
+	static CURRENT: Arc<Cassette>;
+}
+
+lazy_static! {
+	static ref ENV_CASSETTE: Option<Arc<Cassette>> = Cassette::from_env().map(Arc::new);
+}
+
+/// Whether requests are answered from a cassette instead of the network
+pub fn is_replaying() -> bool {
+	current().map_or(false, |cassette| cassette.mode() == CassetteMode::Replay)
+}
+
+/// The cassette for requests of the current task: the one of `Cassette::run`, else the one of `BYOK_CASSETTE`
+pub fn current() -> Option<Arc<Cassette>> {
+	CURRENT.try_with(|cassette| cassette.clone()).ok().or_else(|| ENV_CASSETTE.clone())
+}
+
+impl Cassette {
+	/// Starts an empty cassette that overwrites `path` with each recorded interaction
+	pub fn record(path: impl Into<PathBuf>) -> Self {
+		Cassette {
+			path: path.into(),
+			mode: CassetteMode::Record,
+			state: Mutex::new(CassetteState::default()),
+		}
+	}
+
+	pub fn replay(path: impl AsRef<Path>) -> Result<Self, String> {
+		let path = path.as_ref();
+		let content = std::fs::read_to_string(path).map_err(|e| format!("Could not read cassette {:?}: {}", path, e))?;
+		let file: CassetteFile = serde_json::from_str(&content).map_err(|e| format!("Could not parse cassette {:?}: {}", path, e))?;
+		Ok(Cassette {
+			path: path.to_path_buf(),
+			mode: CassetteMode::Replay,
+			state: Mutex::new(CassetteState {
+				replayed: vec![false; file.interactions.len()],
+				interactions: file.interactions,
+			}),
+		})
+	}
+
+	fn from_env() -> Option<Self> {
+		let path = env::var("BYOK_CASSETTE").ok().filter(|path| !path.is_empty())?;
+		let cassette = match env::var("BYOK_CASSETTE_MODE").as_deref() {
+			Ok("record") => Cassette::record(&path),
+			Ok("replay") | Err(_) => Cassette::replay(&path).unwrap_or_else(|e| {
+				// Still replay, so a broken cassette fails the requests instead of sending them
+				log::error!("{}", e);
+				Cassette {
+					path: PathBuf::from(&path),
+					mode: CassetteMode::Replay,
+					state: Mutex::new(CassetteState::default()),
+				}
+			}),
+			Ok(mode) => {
+				log::error!("Unknown BYOK_CASSETTE_MODE {}, expected record or replay", mode);
+				return None;
+			}
+		};
+		log::info!("Using cassette {} in {:?} mode", path, cassette.mode);
+		Some(cassette)
+	}
+
+	pub fn mode(&self) -> CassetteMode {
+		self.mode
+	}
+
+	pub fn interactions(&self) -> Vec<Interaction> {
+		self.state.lock().unwrap().interactions.clone()
+	}
+
+	/// Runs `future` with its provider requests going through this cassette
+	pub async fn run<F: Future>(self: Arc<Self>, future: F) -> F::Output {
+		CURRENT.scope(self, future).await
+	}
+
+	/// Sends the request, or answers it from the cassette when replaying
+	pub async fn send(&self, client: &Client, request: Request) -> reqwest::Result<Response> {
+		let secrets = secrets_of(&request);
+		let recorded_request = RecordedRequest::from_request(&request, &secrets);
+		if self.mode == CassetteMode::Replay {
+			return Ok(self.replay_response(&recorded_request));
+		}
+
+		let mut response = client.execute(request).await?;
+		let mut recorded_response = RecordedResponse {
+			status: response.status().as_u16(),
+			headers: RECORDED_RESPONSE_HEADERS
+				.iter()
+				.filter_map(|name| Some((name.to_string(), response.headers().get(*name)?.to_str().ok()?.to_string())))
+				.collect(),
+			chunks: vec![],
+		};
+		// Characters split across chunks are moved to the next chunk, so every chunk is valid UTF-8
+		let mut pending: Vec<u8> = vec![];
+		while let Some(chunk) = response.chunk().await? {
+			pending.extend_from_slice(&chunk);
+			let valid_up_to = match std::str::from_utf8(&pending) {
+				Ok(_) => pending.len(),
+				Err(e) => e.valid_up_to(),
+			};
+			let text: Vec<u8> = pending.drain(..valid_up_to).collect();
+			if !text.is_empty() {
+				recorded_response.chunks.push(redact(&String::from_utf8_lossy(&text), &secrets));
+			}
+		}
+		if !pending.is_empty() {
+			recorded_response.chunks.push(redact(&String::from_utf8_lossy(&pending), &secrets));
+		}
+
+		let response = recorded_response.to_response();
+		self.save(Interaction {
+			request: recorded_request,
+			response: recorded_response,
+		});
+		Ok(response)
+	}
+
+	fn replay_response(&self, request: &RecordedRequest) -> Response {
+		let mut state = self.state.lock().unwrap();
+		let matches: Vec<usize> = (0..state.interactions.len())
+			.filter(|&index| state.interactions[index].request.matches(request))
+			.collect();
+		// Once all matching responses were replayed, the last one keeps being served
+		match matches.iter().find(|&&index| !state.replayed[index]).or_else(|| matches.last()) {
+			Some(&index) => {
+				state.replayed[index] = true;
+				state.interactions[index].response.to_response()
+			}
+			None => {
+				let message = format!("No recorded response for {} {} in cassette {:?}", request.method, request.url, self.path);
+				log::error!("{}", message);
+				RecordedResponse {
+					status: 404,
+					headers: BTreeMap::from([("content-type".to_string(), "application/json".to_string())]),
+					chunks: vec![json!({ "error": { "message": message, "type": "cassette_error" } }).to_string()],
+				}
+				.to_response()
+			}
+		}
+	}
+
+	fn save(&self, interaction: Interaction) {
+		let mut state = self.state.lock().unwrap();
+		state.interactions.push(interaction);
+		state.replayed.push(false);
+		let file = CassetteFile {
+			interactions: state.interactions.clone(),
+		};
+		let result = serde_json::to_string_pretty(&file)
+			.map_err(|e| e.to_string())
+			.and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+		if let Err(e) = result {
+			log::error!("Could not write cassette {:?}: {}", self.path, e);
+		}
+	}
+}
+
+impl RecordedRequest {
+	fn from_request(request: &Request, secrets: &[String]) -> Self {
+		let body = request
+			.body()
+			.and_then(|body| body.as_bytes())
+			.map(|body| redact(&String::from_utf8_lossy(body), secrets));
+		RecordedRequest {
+			method: request.method().to_string(),
+			url: redact(request.url().as_str(), secrets),
+			headers: request
+				.headers()
+				.iter()
+				.filter_map(|(name, value)| {
+					let value = match SECRET_HEADERS.contains(&name.as_str()) {
+						true => REDACTED.to_string(),
+						false => value.to_str().ok()?.to_string(),
+					};
+					Some((name.to_string(), value))
+				})
+				.collect(),
+			body: match body {
+				Some(body) => serde_json::from_str(&body).unwrap_or(Value::String(body)),
+				None => Value::Null,
+			},
+		}
+	}
+
+	fn matches(&self, other: &RecordedRequest) -> bool {
+		self.method == other.method && self.url == other.url && self.body == other.body
+	}
+}
+
+impl RecordedResponse {
+	fn to_response(&self) -> Response {
+		let mut builder = ::http::Response::builder().status(self.status);
+		for (name, value) in &self.headers {
+			builder = builder.header(name.as_str(), value.as_str());
+		}
+		match builder.body(self.chunks.concat()) {
+			Ok(response) => Response::from(response),
+			Err(e) => {
+				log::error!("Invalid recorded response: {}", e);
+				Response::from(::http::Response::new(String::new()))
+			}
+		}
+	}
+}
+
+/// The API keys sent with the request, without their `Bearer` prefix
+fn secrets_of(request: &Request) -> Vec<String> {
+	SECRET_HEADERS
+		.iter()
+		.filter_map(|name| request.headers().get(*name)?.to_str().ok())
+		.map(|value| value.trim_start_matches("Bearer ").trim().to_string())
+		.filter(|secret| !secret.is_empty())
+		.collect()
+}
+
+fn redact(text: &str, secrets: &[String]) -> String {
+	secrets.iter().fold(text.to_string(), |text, secret| text.replace(secret.as_str(), REDACTED))
+}
@@ -0,0 +1,135 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use reqwest::{Client, Proxy, RequestBuilder, Response, StatusCode};
+
+use crate::llm_providers::cassette;
+
+const BASE_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// More retries only keep the user waiting, at `MAX_RETRY_DELAY` each
+pub const MAX_RETRIES: u32 = 10;
+
+struct HttpConfig {
+	client: Client,
+	max_retries: u32,
+}
+
+lazy_static! {
+	/// Shared by all providers, so proxy and retry settings apply everywhere. Replaced when the settings change.
+	static ref HTTP_CONFIG: RwLock<HttpConfig> = RwLock::new(HttpConfig {
+		client: Client::new(),
+		max_retries: 0,
+	});
+}
+
+/// Builds a client for the proxy. Used to validate the proxy before it's saved.
+pub fn build_client(proxy: Option<&str>) -> Result<Client, String> {
+	let mut builder = Client::builder();
+	if let Some(proxy) = proxy.filter(|proxy| !proxy.trim().is_empty()) {
+		builder = builder.proxy(Proxy::all(proxy.trim()).map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?);
+	}
+	builder.build().map_err(|e| format!("Could not create HTTP client: {}", e))
+}
+
+/// `max_retries` is capped at `MAX_RETRIES`
+pub fn configure(proxy: Option<&str>, max_retries: u32) -> Result<(), String> {
+	let client = build_client(proxy)?;
+	*HTTP_CONFIG.write().unwrap() = HttpConfig {
+		client,
+		max_retries: max_retries.min(MAX_RETRIES),
+	};
+	Ok(())
+}
+
+pub fn client() -> Client {
+	HTTP_CONFIG.read().unwrap().client.clone()
+}
+
+/// Sends the request once, through the active cassette if there is one (see `cassette`)
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+	match cassette::current() {
+		Some(cassette) => {
+			let (client, request) = request.build_split();
+			cassette.send(&client, request?).await
+		}
+		None => request.send().await,
+	}
+}
+
+/// Sends the request, retrying connection errors, 429 and 503 responses with exponential backoff.
+/// A `Retry-After` header in seconds takes precedence over the backoff.
+/// Timeouts and other server errors aren't retried, the server may have processed the request and sending it again costs tokens.
+/// `max_retries` overrides the configured retry count, it is also capped at `MAX_RETRIES`.
+pub async fn send_with_retry(request: RequestBuilder, max_retries: Option<u32>) -> reqwest::Result<Response> {
+	let max_retries = match max_retries {
+		Some(max_retries) => max_retries.min(MAX_RETRIES),
+		None => HTTP_CONFIG.read().unwrap().max_retries,
+	};
+	let mut request = request;
+	let mut attempt = 0;
+	loop {
+		let next_request = request.try_clone();
+		let result = send(request).await;
+		let retry_delay = match &result {
+			Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status() == StatusCode::SERVICE_UNAVAILABLE => {
+				Some(retry_after(response).unwrap_or_else(|| backoff(attempt)))
+			}
+			Err(e) if e.is_connect() => Some(backoff(attempt)),
+			_ => None,
+		};
+		match (retry_delay, next_request) {
+			(Some(retry_delay), Some(next_request)) if attempt < max_retries => {
+				let retry_delay = retry_delay.min(MAX_RETRY_DELAY);
+				log::info!("Request failed, retrying in {:?} (attempt {} of {})", retry_delay, attempt + 1, max_retries);
+				tokio::time::sleep(retry_delay).await;
+				request = next_request;
+				attempt += 1;
+			}
+			_ => return result,
+		}
+	}
+}
+
+pub fn is_event_stream(response: &Response) -> bool {
+	response
+		.headers()
+		.get(reqwest::header::CONTENT_TYPE)
+		.and_then(|content_type| content_type.to_str().ok())
+		.map_or(false, |content_type| content_type.starts_with("text/event-stream"))
+}
+
+/// Reads a server-sent events response, calling `on_data` with the data of each event
+/// until the stream ends or sends `[DONE]`
+pub async fn read_events(mut response: Response, mut on_data: impl FnMut(&str) -> anyhow::Result<()>) -> anyhow::Result<()> {
+	// Lines are split on bytes, so characters that are split across chunks stay intact
+	let mut buffer: Vec<u8> = vec![];
+	while let Some(chunk) = response.chunk().await? {
+		buffer.extend_from_slice(&chunk);
+		while let Some(newline) = buffer.iter().position(|&byte| byte == b'\n') {
+			let line: Vec<u8> = buffer.drain(..=newline).collect();
+			let line = String::from_utf8_lossy(&line);
+			if let Some(data) = line.trim_end().strip_prefix("data:") {
+				let data = data.trim_start();
+				if data == "[DONE]" {
+					return Ok(());
+				}
+				on_data(data)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// The delay before retry number `attempt + 1`, doubling from `BASE_RETRY_DELAY` up to `MAX_RETRY_DELAY`
+pub fn backoff(attempt: u32) -> Duration {
+	2u32.checked_pow(attempt)
+		.and_then(|factor| BASE_RETRY_DELAY.checked_mul(factor))
+		.map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+	let seconds = response.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim().parse::<u64>().ok()?;
+	Some(Duration::from_secs(seconds))
+}
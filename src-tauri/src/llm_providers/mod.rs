@@ -1,6 +1,9 @@
+pub mod anthropic;
+pub mod cassette;
+pub mod http;
 pub mod openai;
 mod traits;
 mod types;
 
-pub use traits::LLMProvider;
-pub use types::{LLMConfig, LLMMessage};
+pub use traits::{default_base_url, LLMProvider};
+pub use types::{KeyStatus, KeyValidation, LLMConfig, LLMMessage, LLMResponse, ProviderModel, ToolCall, ToolSpec};
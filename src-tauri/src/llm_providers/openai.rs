@@ -1,16 +1,19 @@
-use anyhow::{Context, Result};
-use reqwest::{Client, Response};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 
-use crate::llm_providers::{LLMConfig, LLMMessage};
-use crate::types::MessageHistory;
+use crate::attachments::{images, inline_text};
+use crate::llm_providers::http::{self, send_with_retry};
+use crate::llm_providers::{KeyValidation, LLMConfig, LLMMessage, LLMResponse, ProviderModel, ToolCall, ToolSpec};
+use crate::types::{Message, MessageHistory};
 
 /// OpenAIProvider is used as the default implementation of "LLMProvider".
+/// Mistral, Groq and Ollama expose OpenAI-compatible APIs and use this provider with their own base URL.
 #[derive(Clone)]
 pub struct OpenAIProvider {
 	api_key: String,
-	url: String,
+	base_url: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -27,7 +30,7 @@ struct OpenAIChatCompletionResponse {
 #[derive(Serialize, Deserialize, Debug)]
 struct Choice {
 	index: i32,
-	pub message: LLMMessage,
+	pub message: ResponseMessage,
 	logprobs: Option<serde_json::Value>,
 	finish_reason: String,
 }
@@ -39,51 +42,231 @@ struct Usage {
 	total_tokens: u32,
 }
 
+/// `content` is null when the model only calls tools. Mistral sends `tool_calls: null` without calls.
+#[derive(Serialize, Deserialize, Debug)]
+struct ResponseMessage {
+	content: Option<String>,
+	tool_calls: Option<Vec<OpenAIToolCall>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIToolCall {
+	id: String,
+	function: OpenAIFunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIFunctionCall {
+	name: String,
+	arguments: String,
+}
+
+/// One event of a streamed completion
+#[derive(Deserialize, Debug)]
+struct OpenAIChatCompletionChunk {
+	choices: Vec<ChunkChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkChoice {
+	delta: ChunkDelta,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkDelta {
+	content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIModelList {
+	data: Vec<OpenAIModel>,
+}
+
+/// `context_window` and `max_completion_tokens` are reported by Groq, `max_context_length` by Mistral, OpenAI reports neither
+#[derive(Deserialize, Debug)]
+struct OpenAIModel {
+	id: String,
+	context_window: Option<u32>,
+	max_context_length: Option<u32>,
+	max_completion_tokens: Option<u32>,
+}
+
 impl OpenAIProvider {
-	pub fn new(api_key: &str, url: &str) -> Self {
+	/// `base_url` is the API root, e.g. `https://api.openai.com/v1`
+	pub fn new(api_key: &str, base_url: &str) -> Self {
 		Self {
 			api_key: api_key.to_string(),
-			url: url.to_string(),
+			base_url: base_url.trim_end_matches('/').to_string(),
 		}
 	}
 
-	pub async fn send_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig) -> Result<String> {
-		let client = Client::new();
+	/// Checks the key against the free model listing endpoint instead of spending tokens on a completion
+	pub async fn validate_key(&self) -> KeyValidation {
+		let request = http::client()
+			.get(format!("{}/models", self.base_url))
+			.header("Authorization", format!("Bearer {}", &self.api_key));
+		match http::send(request).await {
+			Ok(response) => {
+				let status = response.status().as_u16();
+				let body = response.text().await.unwrap_or_default();
+				KeyValidation::from_http(status, &body)
+			}
+			Err(e) => KeyValidation::network_error(e),
+		}
+	}
 
-		let openai_messages: Vec<LLMMessage> = messages
-			.iter()
-			.map(|msg| LLMMessage {
-				role: msg.role.to_string(),
-				content: msg.content.to_string(),
+	pub async fn list_models(&self) -> Result<Vec<ProviderModel>> {
+		let client = http::client();
+		let request = client
+			.get(format!("{}/models", self.base_url))
+			.header("Authorization", format!("Bearer {}", &self.api_key));
+		let response: Response = send_with_retry(request, None)
+			.await
+			.context("Failed to fetch models")?
+			.error_for_status()
+			.context("Failed to fetch models")?;
+
+		let model_list: OpenAIModelList = response.json().await.context("Failed to parse model list")?;
+		Ok(model_list
+			.data
+			.into_iter()
+			.map(|model| ProviderModel {
+				context_window: model.context_window.or(model.max_context_length),
+				max_output_tokens: model.max_completion_tokens,
+				id: model.id,
+				display_name: None,
 			})
-			.collect();
+			.collect())
+	}
+
+	fn chat_message(msg: &Message) -> Value {
+		match (msg.role.as_str(), &msg.tool_calls) {
+			("tool", _) => json!({
+				"role": "tool",
+				"tool_call_id": msg.tool_call_id,
+				"content": msg.content,
+			}),
+			(_, Some(tool_calls)) if !tool_calls.is_empty() => json!({
+				"role": msg.role,
+				"content": if msg.content.is_empty() { Value::Null } else { json!(msg.content) },
+				"tool_calls": tool_calls
+					.iter()
+					.map(|call| json!({ "id": call.id, "type": "function", "function": { "name": call.name, "arguments": call.arguments } }))
+					.collect::<Vec<_>>(),
+			}),
+			// Images need the list of content parts, which not all OpenAI-compatible APIs take, so other messages stay plain text
+			_ if images(msg).next().is_some() => {
+				let mut parts = vec![json!({ "type": "text", "text": inline_text(msg) })];
+				parts.extend(images(msg).map(|image| {
+					let url = format!("data:{};base64,{}", image.mime_type, image.base64_data());
+					json!({ "type": "image_url", "image_url": { "url": url } })
+				}));
+				json!({ "role": msg.role, "content": parts })
+			}
+			_ => json!(LLMMessage {
+				role: msg.role.to_string(),
+				content: inline_text(msg),
+			}),
+		}
+	}
+
+	fn chat_request(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, tools: &[ToolSpec], stream: bool) -> RequestBuilder {
+		let openai_messages: Vec<Value> = messages.iter().map(Self::chat_message).collect();
 
-		let body = json!({
+		let mut body = json!({
 			"model": model,
 			"messages": openai_messages,
 			"temperature": config.temperature,
 			"max_tokens": config.max_tokens
 		});
+		if !tools.is_empty() {
+			body["tools"] = tools
+				.iter()
+				.map(|tool| json!({ "type": "function", "function": { "name": tool.name, "description": tool.description, "parameters": tool.parameters } }))
+				.collect();
+		}
+		if stream {
+			body["stream"] = json!(true);
+		}
 
 		log::debug!("Sending message to OpenAI: {:?}", body);
 
-		let response: Response = client
-			.post(&self.url)
+		http::client()
+			.post(format!("{}/chat/completions", self.base_url))
 			.header("Content-Type", "application/json")
 			.header("Authorization", format!("Bearer {}", &self.api_key))
 			.json(&body)
-			.send()
-			.await
-			.context("Failed to send message to OpenAI")?;
+	}
+
+	pub async fn send_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig) -> Result<String> {
+		Ok(self.send_message_with_tools(messages, model, config, &[]).await?.content)
+	}
+
+	pub async fn send_message_with_tools(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, tools: &[ToolSpec]) -> Result<LLMResponse> {
+		let request = self.chat_request(messages, model, config, tools, false);
+		let response: Response = send_with_retry(request, config.max_retries).await.context("Failed to send message to OpenAI")?;
 
 		let response_text = response.text().await.context("Failed to read response from OpenAI")?;
+		Self::parse_answer(&response_text)
+	}
 
-		if let Ok(parsed_response) = serde_json::from_str::<OpenAIChatCompletionResponse>(&response_text) {
-			let answer = parsed_response.choices.get(0).ok_or(anyhow::anyhow!("No response"))?.message.content.clone();
-			log::debug!("Answer: {}", answer);
+	fn parse_answer(response_text: &str) -> Result<LLMResponse> {
+		if let Ok(parsed_response) = serde_json::from_str::<OpenAIChatCompletionResponse>(response_text) {
+			let message = &parsed_response.choices.get(0).ok_or(anyhow::anyhow!("No response"))?.message;
+			let answer = LLMResponse {
+				content: message.content.clone().unwrap_or_default(),
+				tool_calls: message
+					.tool_calls
+					.iter()
+					.flatten()
+					.map(|call| ToolCall {
+						id: call.id.clone(),
+						name: call.function.name.clone(),
+						arguments: call.function.arguments.clone(),
+					})
+					.collect(),
+			};
+			log::debug!("Answer: {:?}", answer);
 			return Ok(answer);
 		}
 
 		Err(anyhow::anyhow!("Something went wrong when sending message to OpenAI: {}", response_text))
 	}
+
+	pub async fn stream_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, on_delta: &mut (dyn FnMut(&str) + Send)) -> Result<String> {
+		let request = self.chat_request(messages, model, config, &[], true);
+		let response: Response = send_with_retry(request, config.max_retries).await.context("Failed to send message to OpenAI")?;
+		if !response.status().is_success() {
+			let status = response.status();
+			let response_text = response.text().await.unwrap_or_default();
+			return Err(anyhow!("Something went wrong when sending message to OpenAI ({}): {}", status, response_text));
+		}
+		// Servers that don't stream answer with the whole completion instead
+		if !http::is_event_stream(&response) {
+			let response_text = response.text().await.context("Failed to read response from OpenAI")?;
+			let answer = Self::parse_answer(&response_text)?.content;
+			on_delta(&answer);
+			return Ok(answer);
+		}
+
+		let mut answer = String::new();
+		http::read_events(response, |data| {
+			let chunk: OpenAIChatCompletionChunk = serde_json::from_str(data).with_context(|| format!("Unexpected event from OpenAI: {}", data))?;
+			// The first chunk only carries the role, with empty content
+			if let Some(content) = chunk
+				.choices
+				.get(0)
+				.and_then(|choice| choice.delta.content.as_deref())
+				.filter(|content| !content.is_empty())
+			{
+				answer.push_str(content);
+				on_delta(content);
+			}
+			Ok(())
+		})
+		.await
+		.context("Failed to read response from OpenAI")?;
+		log::debug!("Answer: {}", answer);
+		Ok(answer)
+	}
 }
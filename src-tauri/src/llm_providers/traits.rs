@@ -1,15 +1,19 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 // use async_trait::async_trait;
 
+use crate::llm_providers::anthropic::AnthropicProvider;
 use crate::llm_providers::openai::OpenAIProvider;
-use crate::types::MessageHistory;
+use crate::types::{Message, MessageHistory};
 
-use super::LLMConfig;
+use super::{KeyValidation, LLMConfig, LLMResponse, ProviderModel, ToolCall, ToolSpec};
+
+/// What a call without a result gets as its result, e.g. when the app quit while the tool ran
+const INTERRUPTED_TOOL_RESULT: &str = "Error: the call was interrupted before the tool returned a result";
 
 #[derive(Clone)]
 pub enum LLMProvider {
 	OpenAI(OpenAIProvider),
-	// Anthropic(AnthropicProvider),
+	Anthropic(AnthropicProvider),
 	// Mistral(MistralProvider),
 	// Groq(GroqProvider),
 }
@@ -21,23 +25,155 @@ pub enum LLMProvider {
 // 	fn get_provider_name(&self) -> &'static str;
 // }
 
+/// The API root of a provider, `local` being an Ollama server on this machine
+pub fn default_base_url(provider_name: &str) -> Option<&'static str> {
+	match provider_name {
+		"openai" => Some("https://api.openai.com/v1"),
+		"anthropic" => Some("https://api.anthropic.com/v1"),
+		"mistralai" => Some("https://api.mistral.ai/v1"),
+		"groqcloud" => Some("https://api.groq.com/openai/v1"),
+		"local" => Some("http://localhost:11434/v1"),
+		_ => None,
+	}
+}
+
 impl LLMProvider {
-	pub fn new(provider_name: &str, api_key: String) -> Self {
+	pub fn new(provider_name: &str, api_key: String) -> Result<Self> {
+		match default_base_url(provider_name) {
+			Some(base_url) => Self::with_base_url(provider_name, api_key, base_url),
+			None => Err(anyhow!("Unsupported provider: {}", provider_name)),
+		}
+	}
+
+	/// Talks to `base_url` instead of the provider's API, e.g. to a proxy or a mock server
+	pub fn with_base_url(provider_name: &str, api_key: String, base_url: &str) -> Result<Self> {
 		match provider_name {
-			"openai" => Self::OpenAI(OpenAIProvider::new(&api_key, "https://api.openai.com/v1/chat/completions")),
-			// "anthropic" => Self::OpenAI(OpenAIProvider::new(api_key)),
-			// "mistral" => Self::Mistral(MistralProvider::new(api_key)),
-			// "groq" => Self::Groq(GroqProvider::new(api_key)),
-			_ => panic!("Unsupported provider: {}", provider_name),
+			"anthropic" => Ok(Self::Anthropic(AnthropicProvider::new(&api_key, base_url))),
+			// Mistral, Groq and Ollama speak the OpenAI wire format
+			"openai" | "mistralai" | "groqcloud" | "local" => Ok(Self::OpenAI(OpenAIProvider::new(&api_key, base_url))),
+			_ => Err(anyhow!("Unsupported provider: {}", provider_name)),
 		}
 	}
 
 	pub async fn send_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig) -> Result<String> {
+		let messages = &sendable_history(messages, false);
 		match self {
 			Self::OpenAI(provider) => provider.send_message(messages, model, config).await,
-			// Self::Anthropic(provider) => provider.send_message(messages, model).await,
-			// Self::Mistral(provider) => provider.send_message(messages, model).await,
-			// Self::Groq(provider) => provider.send_message(messages, model).await,
+			Self::Anthropic(provider) => provider.send_message(messages, model, config).await,
+		}
+	}
+
+	/// Offers the tools to the model, which may answer with calls of them instead of text
+	pub async fn send_message_with_tools(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, tools: &[ToolSpec]) -> Result<LLMResponse> {
+		let messages = &sendable_history(messages, !tools.is_empty());
+		match self {
+			Self::OpenAI(provider) => provider.send_message_with_tools(messages, model, config, tools).await,
+			Self::Anthropic(provider) => provider.send_message_with_tools(messages, model, config, tools).await,
+		}
+	}
+
+	/// Like `send_message`, but calls `on_delta` with each piece of the answer as it arrives
+	pub async fn stream_message(&self, messages: &MessageHistory, model: &str, config: &LLMConfig, on_delta: &mut (dyn FnMut(&str) + Send)) -> Result<String> {
+		let messages = &sendable_history(messages, false);
+		match self {
+			Self::OpenAI(provider) => provider.stream_message(messages, model, config, on_delta).await,
+			Self::Anthropic(provider) => provider.stream_message(messages, model, config, on_delta).await,
+		}
+	}
+
+	pub async fn list_models(&self) -> Result<Vec<ProviderModel>> {
+		match self {
+			Self::OpenAI(provider) => provider.list_models().await,
+			Self::Anthropic(provider) => provider.list_models().await,
+		}
+	}
+
+	/// Checks whether the key works without spending tokens
+	pub async fn validate_key(&self) -> KeyValidation {
+		match self {
+			Self::OpenAI(provider) => provider.validate_key().await,
+			Self::Anthropic(provider) => provider.validate_key().await,
+		}
+	}
+}
+
+/// The history in a shape the providers accept. Every call gets a result, OpenAI refuses calls without one,
+/// so calls that never got theirs get `INTERRUPTED_TOOL_RESULT` and results without a call are left out.
+/// Without tools, calls and results become text of the assistant's message, Anthropic refuses tool blocks without tool definitions.
+fn sendable_history(messages: &MessageHistory, with_tools: bool) -> MessageHistory {
+	let mut history: Vec<Message> = vec![];
+	// The calls of the last assistant message that have no result yet
+	let mut open_calls: Vec<ToolCall> = vec![];
+	for message in messages.iter() {
+		if message.role == "tool" {
+			match open_calls.iter().position(|call| message.tool_call_id.as_ref() == Some(&call.id)) {
+				Some(index) => {
+					let call = open_calls.remove(index);
+					push_tool_result(&mut history, message, &call, &message.content, with_tools);
+				}
+				None => log::debug!("Leaving out the result of tool call {:?}, which has no call", message.tool_call_id),
+			}
+			continue;
+		}
+		close_calls(&mut history, &mut open_calls, message, with_tools);
+		match &message.tool_calls {
+			Some(tool_calls) if !tool_calls.is_empty() => {
+				open_calls = tool_calls.clone();
+				if with_tools {
+					history.push(message.clone());
+				} else {
+					let mut text = vec![message.content.clone()];
+					text.extend(tool_calls.iter().map(|call| format!("[Called {} with {}]", call.name, call.arguments)));
+					push_assistant_text(&mut history, message, &text.join("\n"));
+				}
+			}
+			_ if !with_tools && message.role == "assistant" => push_assistant_text(&mut history, message, &message.content),
+			_ => history.push(message.clone()),
+		}
+	}
+	if let Some(last) = messages.last() {
+		close_calls(&mut history, &mut open_calls, last, with_tools);
+	}
+	MessageHistory(history)
+}
+
+fn close_calls(history: &mut Vec<Message>, open_calls: &mut Vec<ToolCall>, message: &Message, with_tools: bool) {
+	for call in open_calls.drain(..) {
+		push_tool_result(history, message, &call, INTERRUPTED_TOOL_RESULT, with_tools);
+	}
+}
+
+fn push_tool_result(history: &mut Vec<Message>, message: &Message, call: &ToolCall, result: &str, with_tools: bool) {
+	if with_tools {
+		history.push(Message {
+			role: "tool".to_string(),
+			content: result.to_string(),
+			tool_calls: None,
+			tool_call_id: Some(call.id.clone()),
+			attachments: vec![],
+			..message.clone()
+		});
+	} else {
+		push_assistant_text(history, message, &format!("[{} returned: {}]", call.name, result));
+	}
+}
+
+/// Appends the text to the last message if it's the assistant's too, since the turns have to alternate
+fn push_assistant_text(history: &mut Vec<Message>, message: &Message, text: &str) {
+	let text = text.trim();
+	match history.last_mut() {
+		Some(last) if last.role == "assistant" => {
+			if !last.content.is_empty() && !text.is_empty() {
+				last.content.push_str("\n\n");
+			}
+			last.content.push_str(text);
 		}
+		_ => history.push(Message {
+			role: "assistant".to_string(),
+			content: text.to_string(),
+			tool_calls: None,
+			tool_call_id: None,
+			..message.clone()
+		}),
 	}
 }
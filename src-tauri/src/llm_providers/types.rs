@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LLMMessage {
@@ -11,6 +13,8 @@ pub struct LLMConfig {
 	pub temperature: f32,
 	pub max_tokens: u32,
 	pub top_p: Option<f32>,
+	/// Overrides the retry count of the settings (see `http::configure`) for this request
+	pub max_retries: Option<u32>,
 }
 
 impl Default for LLMConfig {
@@ -19,10 +23,96 @@ impl Default for LLMConfig {
 			temperature: 0.7,
 			max_tokens: 4096,
 			top_p: None,
+			max_retries: None,
 		}
 	}
 }
 
+/// A tool the model may call, see `tools::Tool`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ToolSpec {
+	pub name: String,
+	pub description: String,
+	/// JSON schema of the arguments object
+	pub parameters: Value,
+}
+
+/// A call of a tool the model asked for. `arguments` is the JSON object as the model wrote it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Type)]
+pub struct ToolCall {
+	pub id: String,
+	pub name: String,
+	pub arguments: String,
+}
+
+/// An answer of a model: its text and the tools it wants to call before it answers for good
+#[derive(Debug, Clone, Default)]
+pub struct LLMResponse {
+	pub content: String,
+	pub tool_calls: Vec<ToolCall>,
+}
+
+/// A model as reported by a provider's model listing endpoint
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProviderModel {
+	pub id: String,
+	pub display_name: Option<String>,
+	pub context_window: Option<u32>,
+	/// The most tokens the model generates per answer
+	pub max_output_tokens: Option<u32>,
+}
+
+/// Outcome of checking an API key against a provider, stored in `api_keys.validation_status`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Type, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum KeyStatus {
+	Valid,
+	Invalid,
+	NoQuota,
+	RateLimited,
+	NetworkError,
+	Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct KeyValidation {
+	pub status: KeyStatus,
+	pub message: Option<String>,
+}
+
+impl KeyValidation {
+	/// A rate limited key is still a working key
+	pub fn is_valid(&self) -> bool {
+		matches!(self.status, KeyStatus::Valid | KeyStatus::RateLimited)
+	}
+
+	pub fn network_error(err: impl ToString) -> Self {
+		Self {
+			status: KeyStatus::NetworkError,
+			message: Some(err.to_string()),
+		}
+	}
+
+	/// Maps the response of a provider's model listing endpoint to a validation result
+	pub fn from_http(status: u16, body: &str) -> Self {
+		let no_quota = body.contains("insufficient_quota") || body.contains("billing") || body.contains("credit balance");
+		let status = match status {
+			200..=299 => KeyStatus::Valid,
+			401 | 403 => KeyStatus::Invalid,
+			402 => KeyStatus::NoQuota,
+			429 if no_quota => KeyStatus::NoQuota,
+			429 => KeyStatus::RateLimited,
+			_ => KeyStatus::Unknown,
+		};
+		let message = match status {
+			KeyStatus::Valid => None,
+			_ => Some(body.chars().take(500).collect()),
+		};
+		Self { status, message }
+	}
+}
+
 // #[derive(Debug)]
 // pub enum LLMError {
 // 	Authentication(String),
@@ -1,5 +1,8 @@
 #![cfg_attr(all(not(debug_assertions), target_os = "windows"), windows_subsystem = "windows")]
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use dotenv::dotenv;
 use tauri::api::{dialog, shell};
 #[cfg(target_os = "macos")]
@@ -8,14 +11,30 @@ use tauri::{command, CustomMenuItem, Manager, Menu, MenuEntry, MenuItem, Submenu
 // use tracing::{debug, error, info, warn};
 // use tracing_subscriber::{fmt, EnvFilter};
 // use tauri_plugin_log::{Target, TargetKind};
+use tokio::sync::RwLock;
 
 use crate::data::{AppPaths, ArcData, Data};
+use crate::events::EventSink;
+use crate::mcp::McpManager;
+use crate::tools::approval::EventApprover;
+use crate::tools::files::ApprovedFolders;
 
+mod attachments;
 mod data;
 mod db;
+mod diagrams;
+mod events;
+mod jobs;
 mod llm_providers;
+mod math;
+mod mcp;
 mod providers;
+mod secrets;
+mod service;
 mod settings;
+mod syntaxes;
+mod themes;
+mod tools;
 mod types;
 mod utils;
 
@@ -61,13 +80,41 @@ async fn main() {
 				db::load_chat,
 				db::load_providers,
 				db::set_api_key,
+				db::get_api_keys,
+				db::add_api_key,
+				db::delete_api_key,
+				db::set_default_api_key,
+				db::set_chat_api_key,
+				db::revalidate_api_key,
 				db::get_models,
+				db::refresh_models,
+				db::get_all_models,
+				db::add_model,
+				db::update_model,
+				db::delete_model,
+				db::set_model_visibility,
 				db::read_api_keys_from_env,
 				db::rename_chat,
 				db::archive_chat,
 				db::delete_chat,
+				db::mark_block_copied,
+				db::save_block_to_file,
+				db::copy_all_code,
+				secrets::unlock_api_keys,
+				jobs::list_jobs,
+				jobs::retry_job,
 				settings::get_settings,
-				settings::apply_and_save_settings
+				settings::apply_and_save_settings,
+				themes::list_code_themes,
+				themes::import_code_theme,
+				syntaxes::list_languages,
+				tools::get_chat_tools,
+				tools::set_chat_tool,
+				tools::list_tool_approvals,
+				tools::answer_tool_approval,
+				mcp::get_mcp_servers,
+				mcp::reconnect_mcp_server,
+				mcp::get_mcp_prompt
 			],
 			"../bindings.ts",
 		)
@@ -84,13 +131,29 @@ async fn main() {
 
 	let app_paths = AppPaths::from_tauri_config(ctx.config());
 
-	let pool = match db::init(&app_paths).await {
+	let secrets = secrets::SecretStore::from_env();
+
+	let pool = match db::init(&app_paths, &secrets).await {
 		Ok(pool) => pool,
 		Err(e) => {
 			error_popup_main_thread(&e);
 			panic!("{}", e);
 		}
 	};
+	themes::load_user_themes(&app_paths.themes_dir);
+	syntaxes::load_user_syntaxes(&app_paths.syntaxes_dir);
+
+	let (mut settings, settings_warning) = settings::Settings::load(&app_paths.settings_file);
+	let default_model_changed = settings.validate_default_model(&pool).await;
+	let mcp_env_stored = settings.store_mcp_env_secrets(&secrets, &pool).await;
+	if default_model_changed || mcp_env_stored {
+		if let Err(e) = settings.save(&app_paths.settings_file) {
+			log::error!("{}", e);
+		}
+	}
+	if let Err(e) = settings::apply_network_settings(&settings) {
+		log::error!("{}", e);
+	}
 
 	let app = tauri::Builder::default()
 		.invoke_handler(tauri::generate_handler![
@@ -100,13 +163,41 @@ async fn main() {
 			db::load_chat,
 			db::load_providers,
 			db::set_api_key,
+			db::get_api_keys,
+			db::add_api_key,
+			db::delete_api_key,
+			db::set_default_api_key,
+			db::set_chat_api_key,
+			db::revalidate_api_key,
 			db::get_models,
+			db::refresh_models,
+			db::get_all_models,
+			db::add_model,
+			db::update_model,
+			db::delete_model,
+			db::set_model_visibility,
 			db::read_api_keys_from_env,
 			db::rename_chat,
 			db::archive_chat,
 			db::delete_chat,
+			db::mark_block_copied,
+			db::save_block_to_file,
+			db::copy_all_code,
+			secrets::unlock_api_keys,
+			jobs::list_jobs,
+			jobs::retry_job,
 			settings::get_settings,
-			settings::apply_and_save_settings
+			settings::apply_and_save_settings,
+			themes::list_code_themes,
+			themes::import_code_theme,
+			syntaxes::list_languages,
+			tools::get_chat_tools,
+			tools::set_chat_tool,
+			tools::list_tool_approvals,
+			tools::answer_tool_approval,
+			mcp::get_mcp_servers,
+			mcp::reconnect_mcp_server,
+			mcp::get_mcp_prompt
 		])
 		.setup(move |app| {
 			let win = WindowBuilder::new(app, "main", WindowUrl::default())
@@ -138,14 +229,32 @@ async fn main() {
 					nsw.setBackgroundColor_(bg_color);
 				}
 			}
-			let settings_path = &app_paths.settings_file.clone();
+			if let Some(settings_warning) = settings_warning {
+				dialog::MessageDialogBuilder::new("Settings reset", settings_warning)
+					.kind(dialog::MessageDialogKind::Warning)
+					.parent(&win)
+					.show(|_button_press| {});
+			}
+			let (job_queue, job_receiver) = jobs::JobQueue::new();
+			let events: Arc<dyn EventSink> = Arc::new(win.clone());
+			let tool_folders = ApprovedFolders::new(&settings.tool_folders);
+			let mcp = McpManager::new(events.clone()).with_secrets(secrets.clone(), pool.clone());
+			mcp.apply_in_background(settings.mcp_servers.clone());
 			let data: Data = Data {
 				db_pool: pool,
 				paths: app_paths,
-				window: win.clone(),
-				settings: settings::Settings::load(&settings_path),
+				events: events.clone(),
+				settings: RwLock::new(settings),
+				secrets,
+				dark_mode: AtomicBool::new(win.theme().map_or(true, |theme| theme == tauri::Theme::Dark)),
+				jobs: job_queue,
+				tools: tools::builtin_tools(&tool_folders),
+				tool_folders,
+				approvals: Arc::new(EventApprover::new(events)),
+				mcp,
 			};
 			app.manage(ArcData::new(data));
+			tauri::async_runtime::spawn(jobs::run_worker(app.state::<ArcData>().0.clone(), job_receiver));
 
 			Ok(())
 		})
@@ -215,8 +324,20 @@ async fn main() {
 		.build(ctx)
 		.expect("Error running tauri app");
 
-	app.run(|_app_handle, e| match e {
+	app.run(|app_handle, e| match e {
 		tauri::RunEvent::WindowEvent { event, .. } => match event {
+			tauri::WindowEvent::ThemeChanged(theme) => {
+				let data = app_handle.state::<ArcData>().0.clone();
+				tauri::async_runtime::spawn(async move {
+					let previous_code_theme = data.code_theme().await;
+					data.dark_mode.store(theme == tauri::Theme::Dark, Ordering::Relaxed);
+					if data.code_theme().await != previous_code_theme {
+						if let Err(e) = jobs::enqueue(&data, jobs::JobKind::Rehighlight, None, serde_json::Value::Null).await {
+							log::error!("{}", e);
+						}
+					}
+				});
+			}
 			tauri::WindowEvent::CloseRequested { api: _api, .. } => {
 				#[cfg(target_os = "macos")]
 				{
@@ -0,0 +1,146 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+lazy_static! {
+	/// Math spans emitted by comrak's `math_dollars` extension, after sanitizing
+	static ref MATH_SPAN: Regex = Regex::new(r#"<span data-math-style="(inline|display)">([^<]*)</span>"#).unwrap();
+	/// `\(...\)` on a single line
+	static ref INLINE_PARENS: Regex = Regex::new(r"\\\((.+?)\\\)").unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MathSegment {
+	Text(String),
+	Display(String),
+}
+
+/// Renders TeX to MathML with KaTeX, which runs in an embedded JS engine and therefore works offline
+pub fn render_tex(tex: &str, display: bool) -> Result<String, String> {
+	let opts = katex::Opts::builder()
+		.output_type(katex::OutputType::Mathml)
+		.display_mode(display)
+		.throw_on_error(true)
+		.build()
+		.map_err(|e| e.to_string())?;
+	katex::render_with_opts(tex, &opts).map_err(|e| e.to_string())
+}
+
+/// Like `render_tex`, but falls back to showing the TeX source if it can't be rendered
+pub fn render_tex_or_source(tex: &str, display: bool) -> String {
+	match render_tex(tex, display) {
+		Ok(mathml) => mathml,
+		Err(e) => {
+			log::debug!("Could not render TeX {}: {}", tex, e);
+			format!("<code class=\"font-mono\">{}</code>", escape_html(tex))
+		}
+	}
+}
+
+/// Splits display math (`$$...$$` and `\[...\]`) out of a text so it can become its own message block.
+/// An opening delimiter without a closing one is left as text, see `next_display_math` for which ones count.
+pub fn split_display_math(text: &str) -> Vec<MathSegment> {
+	let mut segments: Vec<MathSegment> = vec![];
+	// Where the text before the next math starts, and where to look for the next delimiter
+	let mut text_start = 0;
+	let mut position = 0;
+
+	while let Some((start, open, close)) = next_display_math(text, position) {
+		let after_open = start + open.len();
+		match text[after_open..].find(close) {
+			Some(end) if !text[after_open..after_open + end].trim().is_empty() => {
+				let pending_text = &text[text_start..start];
+				if !pending_text.trim().is_empty() {
+					segments.push(MathSegment::Text(pending_text.to_string()));
+				}
+				segments.push(MathSegment::Display(text[after_open..after_open + end].trim().to_string()));
+				position = after_open + end + close.len();
+				text_start = position;
+			}
+			_ => position = after_open,
+		}
+	}
+	let pending_text = &text[text_start..];
+	if !pending_text.trim().is_empty() {
+		segments.push(MathSegment::Text(pending_text.to_string()));
+	}
+	segments
+}
+
+/// The next `$$` or `\[` from `position` on, with its closing delimiter. Inline code spans are skipped, so `` `echo $$` `` stays code,
+/// and `\[` only counts at the start of a line, elsewhere it's usually an escaped bracket like in `arr\[0\]`.
+fn next_display_math(text: &str, mut position: usize) -> Option<(usize, &'static str, &'static str)> {
+	while let Some(offset) = text[position..].find(|c: char| c == '`' || c == '$' || c == '\\') {
+		position += offset;
+		let rest = &text[position..];
+		if rest.starts_with('`') {
+			let ticks = backtick_run(rest);
+			position += ticks;
+			// A code span ends at the next run of as many backticks, without one the backticks are just text
+			if let Some(end) = find_backtick_run(&text[position..], ticks) {
+				position += end + ticks;
+			}
+		} else if rest.starts_with("$$") {
+			return Some((position, "$$", "$$"));
+		} else if rest.starts_with("\\[") && text[..position].rsplit('\n').next().unwrap_or_default().trim().is_empty() {
+			return Some((position, "\\[", "\\]"));
+		} else {
+			position += 1;
+		}
+	}
+	None
+}
+
+/// The number of backticks `text` starts with
+fn backtick_run(text: &str) -> usize {
+	text.len() - text.trim_start_matches('`').len()
+}
+
+/// Where the next run of exactly `length` backticks starts
+fn find_backtick_run(text: &str, length: usize) -> Option<usize> {
+	let mut position = 0;
+	while let Some(offset) = text[position..].find('`') {
+		let start = position + offset;
+		let run = backtick_run(&text[start..]);
+		if run == length {
+			return Some(start);
+		}
+		position = start + run;
+	}
+	None
+}
+
+/// Rewrites `\(...\)` to `$...$` so comrak's dollar math parser picks it up. Code spans are left as they are.
+pub fn normalize_inline_delimiters(text: &str) -> String {
+	let inline_math = |caps: &Captures| format!("${}$", caps[1].trim());
+	let mut normalized = String::with_capacity(text.len());
+	let (mut segment_start, mut position) = (0, 0);
+	while let Some(offset) = text[position..].find('`') {
+		let start = position + offset;
+		let ticks = backtick_run(&text[start..]);
+		position = start + ticks;
+		// A code span ends at the next run of as many backticks, without one the backticks are just text
+		if let Some(end) = find_backtick_run(&text[position..], ticks) {
+			position += end + ticks;
+			normalized.push_str(&INLINE_PARENS.replace_all(&text[segment_start..start], inline_math));
+			normalized.push_str(&text[start..position]);
+			segment_start = position;
+		}
+	}
+	normalized.push_str(&INLINE_PARENS.replace_all(&text[segment_start..], inline_math));
+	normalized
+}
+
+/// Replaces the math spans in rendered Markdown with MathML. The span content is the escaped TeX.
+pub fn render_math_spans(html: &str) -> String {
+	MATH_SPAN
+		.replace_all(html, |caps: &Captures| render_tex_or_source(&unescape_html(&caps[2]), &caps[1] == "display"))
+		.to_string()
+}
+
+pub fn escape_html(text: &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape_html(text: &str) -> String {
+	text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&")
+}
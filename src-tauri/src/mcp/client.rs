@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use specta::Type;
+
+use crate::mcp::transport::Transport;
+use crate::throw;
+
+/// The protocol revision the client speaks, servers answer with the one they pick
+pub const PROTOCOL_VERSION: &str = "2025-03-26";
+/// Stops following `nextCursor` after this many items, in case a server keeps returning pages
+const MAX_LISTED_ITEMS: usize = 1000;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct McpToolInfo {
+	pub name: String,
+	#[serde(default)]
+	pub description: Option<String>,
+	#[serde(default)]
+	pub input_schema: Value,
+	/// Hints like `readOnlyHint`, which tell whether calls change anything
+	#[serde(default)]
+	pub annotations: Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+pub struct McpResource {
+	pub uri: String,
+	pub name: String,
+	#[serde(default)]
+	pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+pub struct McpPrompt {
+	pub name: String,
+	#[serde(default)]
+	pub description: Option<String>,
+	#[serde(default)]
+	pub arguments: Vec<McpPromptArgument>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+pub struct McpPromptArgument {
+	pub name: String,
+	#[serde(default)]
+	pub description: Option<String>,
+	#[serde(default)]
+	pub required: bool,
+}
+
+/// A connected MCP server and what it offers. The lists are read once when connecting.
+pub struct McpClient {
+	/// The name of the server in the settings
+	pub name: String,
+	/// Name and version the server reported
+	pub server_info: String,
+	pub tools: Vec<McpToolInfo>,
+	pub resources: Vec<McpResource>,
+	pub prompts: Vec<McpPrompt>,
+	transport: Transport,
+}
+
+impl McpClient {
+	/// Runs the `initialize` handshake and lists the tools, resources and prompts the server has
+	pub async fn connect(name: &str, transport: Transport) -> Result<Self, String> {
+		let initialize = json!({
+			"protocolVersion": PROTOCOL_VERSION,
+			"capabilities": {},
+			"clientInfo": { "name": "byok", "version": env!("CARGO_PKG_VERSION") }
+		});
+		let initialized = transport.request("initialize", initialize).await?;
+		transport.notify("notifications/initialized", Value::Null).await?;
+		let server_info = &initialized["serverInfo"];
+		let mut client = McpClient {
+			name: name.to_string(),
+			server_info: format!(
+				"{} {}",
+				server_info["name"].as_str().unwrap_or(name),
+				server_info["version"].as_str().unwrap_or_default()
+			)
+			.trim()
+			.to_string(),
+			tools: vec![],
+			resources: vec![],
+			prompts: vec![],
+			transport,
+		};
+		// Servers only answer the lists of the capabilities they announced
+		let capabilities = &initialized["capabilities"];
+		if capabilities.get("tools").is_some() {
+			client.tools = client.list("tools/list", "tools").await?;
+		}
+		if capabilities.get("resources").is_some() {
+			client.resources = client.list("resources/list", "resources").await?;
+		}
+		if capabilities.get("prompts").is_some() {
+			client.prompts = client.list("prompts/list", "prompts").await?;
+		}
+		Ok(client)
+	}
+
+	/// Reads all pages of a list
+	async fn list<T: DeserializeOwned>(&self, method: &str, field: &str) -> Result<Vec<T>, String> {
+		let mut items = vec![];
+		let mut cursor: Option<String> = None;
+		loop {
+			let params = match &cursor {
+				Some(cursor) => json!({ "cursor": cursor }),
+				None => json!({}),
+			};
+			let mut page = self.transport.request(method, params).await?;
+			match serde_json::from_value::<Vec<T>>(page[field].take()) {
+				Ok(page_items) => items.extend(page_items),
+				Err(e) => throw!("Invalid answer to {} from the MCP server {}: {}", method, self.name, e),
+			}
+			match page["nextCursor"].as_str() {
+				Some(next_cursor) if !next_cursor.is_empty() && items.len() < MAX_LISTED_ITEMS => cursor = Some(next_cursor.to_string()),
+				_ => return Ok(items),
+			}
+		}
+	}
+
+	/// Calls a tool by its name on the server. Results the server marks as errors become `Err`.
+	pub async fn call_tool(&self, tool_name: &str, arguments: Value) -> Result<String, String> {
+		let result = self
+			.transport
+			.request("tools/call", json!({ "name": tool_name, "arguments": arguments }))
+			.await?;
+		let text = content_text(&result["content"]);
+		match result["isError"].as_bool() {
+			Some(true) => Err(text),
+			_ => Ok(text),
+		}
+	}
+
+	pub async fn read_resource(&self, uri: &str) -> Result<String, String> {
+		let result = self.transport.request("resources/read", json!({ "uri": uri })).await?;
+		let contents: Vec<String> = result["contents"].as_array().into_iter().flatten().map(resource_text).collect();
+		match contents.is_empty() {
+			true => throw!("The MCP server {} returned nothing for {}", self.name, uri),
+			false => Ok(contents.join("\n\n")),
+		}
+	}
+
+	/// Fills in a prompt template. Returns the text of its messages.
+	pub async fn get_prompt(&self, prompt_name: &str, arguments: &BTreeMap<String, String>) -> Result<String, String> {
+		let result = self
+			.transport
+			.request("prompts/get", json!({ "name": prompt_name, "arguments": arguments }))
+			.await?;
+		let messages: Vec<String> = result["messages"]
+			.as_array()
+			.into_iter()
+			.flatten()
+			.map(|message| content_text(&message["content"]))
+			.collect();
+		Ok(messages.join("\n\n"))
+	}
+}
+
+/// The text of content blocks, which are a list in tool results and a single block in prompt messages.
+/// Images and audio can't be passed on as text, so they are only mentioned.
+fn content_text(content: &Value) -> String {
+	let blocks: Vec<&Value> = match content {
+		Value::Array(blocks) => blocks.iter().collect(),
+		Value::Null => vec![],
+		block => vec![block],
+	};
+	let texts: Vec<String> = blocks
+		.into_iter()
+		.map(|block| match block["type"].as_str().unwrap_or_default() {
+			"text" => block["text"].as_str().unwrap_or_default().to_string(),
+			"resource" => resource_text(&block["resource"]),
+			"resource_link" => format!("[Resource {}]", block["uri"].as_str().unwrap_or_default()),
+			type_ => format!("[{} content ({}) left out]", type_, block["mimeType"].as_str().unwrap_or("unknown type")),
+		})
+		.collect();
+	texts.join("\n")
+}
+
+fn resource_text(resource: &Value) -> String {
+	match resource["text"].as_str() {
+		Some(text) => text.to_string(),
+		None => format!(
+			"[Binary resource {} ({}) left out]",
+			resource["uri"].as_str().unwrap_or_default(),
+			resource["mimeType"].as_str().unwrap_or("unknown type")
+		),
+	}
+}
@@ -0,0 +1,398 @@
+//! Client for Model Context Protocol servers, which offer tools, resources and prompts to the models.
+//! The servers in `Settings::mcp_servers` are started (stdio) or reached (HTTP) in the background, their tools are offered
+//! to the chats alongside the built-in ones, named `<server>__<tool>`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use sqlx::SqlitePool;
+#[cfg(feature = "gui")]
+use tauri::command;
+
+#[cfg(feature = "gui")]
+use crate::data::DataState;
+use crate::events::EventSink;
+use crate::secrets::{secret_ref, SecretStore};
+use crate::throw;
+use crate::tools::ToolRegistry;
+
+pub mod client;
+pub mod tools;
+pub mod transport;
+
+use client::{McpClient, McpPrompt, McpResource};
+use tools::{client_tools, tool_name_collisions};
+use transport::{HttpTransport, StreamTransport, Transport};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+#[serde(default)]
+pub struct McpServerConfig {
+	/// Unique name, which prefixes the names of the server's tools
+	pub name: String,
+	/// Starts a stdio server, e.g. `npx`
+	pub command: Option<String>,
+	pub args: Vec<String>,
+	/// Environment variables for the command, e.g. the server's API token. Saved settings only hold references to the values,
+	/// which are stored with `SecretStore::save_secret`.
+	pub env: BTreeMap<String, String>,
+	/// URL of a streamable HTTP server, used when there is no command
+	pub url: Option<String>,
+	pub enabled: bool,
+}
+impl Default for McpServerConfig {
+	fn default() -> Self {
+		McpServerConfig {
+			name: String::new(),
+			command: None,
+			args: vec![],
+			env: BTreeMap::new(),
+			url: None,
+			enabled: true,
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum McpServerState {
+	Disabled,
+	Connecting,
+	Connected,
+	Failed,
+}
+
+/// A configured server and what it offers, for the settings
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+pub struct McpServerStatus {
+	pub name: String,
+	pub state: McpServerState,
+	pub error: Option<String>,
+	/// Name and version the server reported
+	pub server_info: Option<String>,
+	/// The names the models call the server's tools by
+	pub tools: Vec<String>,
+	pub resources: Vec<McpResource>,
+	pub prompts: Vec<McpPrompt>,
+}
+
+/// Trims the configs and checks that every server has a unique name and a command or an HTTP URL
+pub fn validate_servers(servers: Vec<McpServerConfig>) -> Result<Vec<McpServerConfig>, String> {
+	let mut valid_servers: Vec<McpServerConfig> = vec![];
+	for mut server in servers {
+		server.name = server.name.trim().to_string();
+		server.command = server.command.map(|command| command.trim().to_string()).filter(|command| !command.is_empty());
+		server.url = server.url.map(|url| url.trim().to_string()).filter(|url| !url.is_empty());
+		if server.name.is_empty() || !server.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+			throw!("MCP server names may only contain letters, digits, _ and -, {:?} doesn't", server.name);
+		}
+		if valid_servers.iter().any(|valid_server| valid_server.name == server.name) {
+			throw!("There are two MCP servers named {}", server.name);
+		}
+		match (&server.command, &server.url) {
+			(Some(_), _) => (),
+			(None, Some(url)) => match url::Url::parse(url) {
+				Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => (),
+				_ => throw!("{} is not an HTTP URL", url),
+			},
+			(None, None) => throw!("The MCP server {} needs a command or a URL", server.name),
+		}
+		valid_servers.push(server);
+	}
+	Ok(valid_servers)
+}
+
+fn env_secret_refs(servers: &[McpServerConfig]) -> BTreeSet<&str> {
+	servers
+		.iter()
+		.flat_map(|server| server.env.values())
+		.map(|value| value.as_str())
+		.filter(|value| secret_ref(value).is_some())
+		.collect()
+}
+
+/// Replaces the `env` values of the servers with references to secrets, so the settings file doesn't hold tokens in plain text.
+/// References the `previous` servers hold are kept, any other value is stored as a new secret.
+pub async fn store_env_secrets(servers: &mut [McpServerConfig], previous: &[McpServerConfig], secrets: &SecretStore, pool: &SqlitePool) -> Result<(), String> {
+	let known_refs = env_secret_refs(previous);
+	let mut stored_refs: Vec<String> = vec![];
+	for value in servers.iter_mut().flat_map(|server| server.env.values_mut()) {
+		if known_refs.contains(value.as_str()) {
+			continue;
+		}
+		match secrets.save_secret(value, pool).await {
+			Ok(reference) => {
+				stored_refs.push(reference.clone());
+				*value = reference;
+			}
+			Err(e) => {
+				// No settings will reference the secrets stored so far
+				for reference in &stored_refs {
+					let _ = secrets.remove_secret(reference, pool).await;
+				}
+				throw!("Could not store the environment variables of the MCP servers securely: {}", e);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Deletes the secrets the `previous` servers referenced in `env` and `servers` don't, e.g. of a removed server
+pub async fn remove_unused_env_secrets(previous: &[McpServerConfig], servers: &[McpServerConfig], secrets: &SecretStore, pool: &SqlitePool) {
+	let used_refs = env_secret_refs(servers);
+	for reference in env_secret_refs(previous).difference(&used_refs) {
+		if let Err(e) = secrets.remove_secret(reference, pool).await {
+			log::warn!("{}", e);
+		}
+	}
+}
+
+/// The config with the secrets its `env` references filled in, to start the server with
+pub async fn resolve_env_secrets(config: &McpServerConfig, secrets: &SecretStore, pool: &SqlitePool) -> Result<McpServerConfig, String> {
+	let mut config = config.clone();
+	for (name, value) in config.env.iter_mut() {
+		if secret_ref(value).is_some() {
+			*value = match secrets.read_secret(value, pool).await {
+				Ok(secret) => secret,
+				Err(e) => throw!("Could not read the environment variable {} of the MCP server {}: {}", name, config.name, e),
+			};
+		}
+	}
+	Ok(config)
+}
+
+/// Starts or reaches the server and runs the handshake
+pub async fn connect(config: &McpServerConfig) -> Result<McpClient, String> {
+	let transport = match (&config.command, &config.url) {
+		(Some(command), _) => Transport::Stream(StreamTransport::spawn(&config.name, command, &config.args, &config.env)?),
+		(None, Some(url)) => Transport::Http(HttpTransport::new(url)),
+		(None, None) => throw!("The MCP server {} needs a command or a URL", config.name),
+	};
+	McpClient::connect(&config.name, transport).await
+}
+
+enum ServerState {
+	Disabled,
+	Connecting,
+	Connected(Arc<McpClient>),
+	Failed(String),
+}
+
+struct McpServer {
+	config: McpServerConfig,
+	state: ServerState,
+}
+
+/// The configured servers and their connections. Emits `mcpServersChanged` with the statuses whenever a server
+/// connects, fails or the configs change.
+#[derive(Clone)]
+pub struct McpManager {
+	servers: Arc<RwLock<BTreeMap<String, McpServer>>>,
+	events: Arc<dyn EventSink>,
+	/// Where the secrets the servers' `env` references are read from
+	secrets: Option<(SecretStore, SqlitePool)>,
+}
+
+impl McpManager {
+	pub fn new(events: Arc<dyn EventSink>) -> Self {
+		McpManager {
+			servers: Arc::new(RwLock::new(BTreeMap::new())),
+			events,
+			secrets: None,
+		}
+	}
+
+	/// Fills in the secrets the servers' `env` references before starting them. Without, `env` is passed as it is.
+	pub fn with_secrets(mut self, secrets: SecretStore, pool: SqlitePool) -> Self {
+		self.secrets = Some((secrets, pool));
+		self
+	}
+
+	/// Connects the servers that are new or changed and drops the connections to the others.
+	/// Returns once all servers connected or failed.
+	pub async fn apply(&self, configs: &[McpServerConfig]) {
+		let new_configs: Vec<McpServerConfig> = {
+			let mut servers = self.servers.write().unwrap();
+			// Dropping a connection stops the server once running generations are done with its tools
+			servers.retain(|_, server| configs.contains(&server.config));
+			let new_configs: Vec<McpServerConfig> = configs.iter().filter(|config| !servers.contains_key(&config.name)).cloned().collect();
+			for config in &new_configs {
+				let state = match config.enabled {
+					true => ServerState::Connecting,
+					false => ServerState::Disabled,
+				};
+				servers.insert(config.name.clone(), McpServer { config: config.clone(), state });
+			}
+			new_configs
+		};
+		self.emit_statuses();
+		join_all(new_configs.into_iter().filter(|config| config.enabled).map(|config| self.connect(config))).await;
+	}
+
+	/// Connects in the background, starting a server can take a while (e.g. `npx` downloading it)
+	#[cfg(feature = "gui")]
+	pub fn apply_in_background(&self, configs: Vec<McpServerConfig>) {
+		let manager = self.clone();
+		tauri::async_runtime::spawn(async move { manager.apply(&configs).await });
+	}
+
+	/// Restarts a server, e.g. after it failed or crashed
+	pub async fn reconnect(&self, name: &str) -> Result<(), String> {
+		let config = match self.servers.write().unwrap().get_mut(name) {
+			Some(server) if server.config.enabled => {
+				server.state = ServerState::Connecting;
+				server.config.clone()
+			}
+			Some(_) => throw!("The MCP server {} is turned off", name),
+			None => throw!("Unknown MCP server {}", name),
+		};
+		self.emit_statuses();
+		self.connect(config).await;
+		Ok(())
+	}
+
+	async fn connect(&self, config: McpServerConfig) {
+		let client = match &self.secrets {
+			Some((secrets, pool)) => match resolve_env_secrets(&config, secrets, pool).await {
+				Ok(resolved_config) => connect(&resolved_config).await,
+				Err(e) => Err(e),
+			},
+			None => connect(&config).await,
+		};
+		let state = match client {
+			Ok(client) => {
+				log::info!(
+					"Connected to the MCP server {} ({}), it has {} tools",
+					config.name,
+					client.server_info,
+					client.tools.len()
+				);
+				ServerState::Connected(Arc::new(client))
+			}
+			Err(e) => {
+				log::warn!("Could not connect to the MCP server {}: {}", config.name, e);
+				ServerState::Failed(e)
+			}
+		};
+		// The configs may have changed while connecting
+		if let Some(server) = self.servers.write().unwrap().get_mut(&config.name).filter(|server| server.config == config) {
+			server.state = state;
+		}
+		self.emit_statuses();
+	}
+
+	/// The tools of all connected servers
+	pub fn tools(&self) -> ToolRegistry {
+		let mut tools = ToolRegistry::default();
+		for client in self.clients() {
+			tools.extend(client_tools(&client));
+		}
+		tools
+	}
+
+	pub fn client(&self, name: &str) -> Result<Arc<McpClient>, String> {
+		match self.servers.read().unwrap().get(name).map(|server| &server.state) {
+			Some(ServerState::Connected(client)) => Ok(client.clone()),
+			Some(_) => throw!("The MCP server {} is not connected", name),
+			None => throw!("Unknown MCP server {}", name),
+		}
+	}
+
+	fn clients(&self) -> Vec<Arc<McpClient>> {
+		let servers = self.servers.read().unwrap();
+		servers
+			.values()
+			.filter_map(|server| match &server.state {
+				ServerState::Connected(client) => Some(client.clone()),
+				_ => None,
+			})
+			.collect()
+	}
+
+	pub fn statuses(&self) -> Vec<McpServerStatus> {
+		let servers = self.servers.read().unwrap();
+		// The tools of all connected servers, to find names that clash across servers
+		let server_tools: BTreeMap<&str, Vec<String>> = servers
+			.values()
+			.filter_map(|server| match &server.state {
+				ServerState::Connected(client) => Some((server.config.name.as_str(), client_tools(client).names())),
+				_ => None,
+			})
+			.collect();
+		servers
+			.values()
+			.map(|server| {
+				let mut status = McpServerStatus {
+					name: server.config.name.clone(),
+					state: McpServerState::Disabled,
+					error: None,
+					server_info: None,
+					tools: vec![],
+					resources: vec![],
+					prompts: vec![],
+				};
+				match &server.state {
+					ServerState::Disabled => (),
+					ServerState::Connecting => status.state = McpServerState::Connecting,
+					ServerState::Failed(error) => {
+						status.state = McpServerState::Failed;
+						status.error = Some(error.clone());
+					}
+					ServerState::Connected(client) => {
+						status.state = McpServerState::Connected;
+						status.server_info = Some(client.server_info.clone());
+						status.tools = server_tools[server.config.name.as_str()].clone();
+						status.resources = client.resources.clone();
+						status.prompts = client.prompts.clone();
+						let mut collisions = tool_name_collisions(client);
+						for (other_server, other_tools) in server_tools.iter().filter(|(name, _)| **name != server.config.name) {
+							for tool in status.tools.iter().filter(|tool| other_tools.contains(tool)) {
+								collisions.push(format!(
+									"The MCP server {} has a tool named {} as well, only one of them is offered",
+									other_server, tool
+								));
+							}
+						}
+						if !collisions.is_empty() {
+							status.error = Some(collisions.join("; "));
+						}
+					}
+				}
+				status
+			})
+			.collect()
+	}
+
+	fn emit_statuses(&self) {
+		self.events.emit("mcpServersChanged", self.statuses());
+	}
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn get_mcp_servers(data: DataState<'_>) -> Result<Vec<McpServerStatus>, String> {
+	Ok(data.0.mcp.statuses())
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn reconnect_mcp_server(name: String, data: DataState<'_>) -> Result<(), String> {
+	data.0.mcp.reconnect(&name).await
+}
+
+/// Fills in a prompt of a server, e.g. to start a message with it
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn get_mcp_prompt(
+	server_name: String,
+	prompt_name: String,
+	prompt_arguments: BTreeMap<String, String>,
+	data: DataState<'_>,
+) -> Result<String, String> {
+	data.0.mcp.client(&server_name)?.get_prompt(&prompt_name, &prompt_arguments).await
+}
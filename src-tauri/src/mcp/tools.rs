@@ -0,0 +1,189 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::mcp::client::{McpClient, McpToolInfo};
+use crate::throw;
+use crate::tools::{Tool, ToolRegistry};
+
+/// Providers only accept tool names of letters, digits, `_` and `-` up to this length
+const MAX_TOOL_NAME_LENGTH: usize = 64;
+/// Resources beyond this many aren't listed in the description of the resource tool, the model can still read them by URI
+const MAX_DESCRIBED_RESOURCES: usize = 50;
+const READ_RESOURCE_TOOL: &str = "read_resource";
+
+/// The name a tool of an MCP server is offered to the models as, `<server>__<tool>`, so tools of different servers can't clash.
+/// Other characters are replaced by `_`, and names beyond the length limit are cut and end in a hash of the full name.
+pub fn tool_name(server_name: &str, tool_name: &str) -> String {
+	let full_name = format!("{}__{}", server_name, tool_name);
+	let name: String = full_name
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+		.collect();
+	match name.len() > MAX_TOOL_NAME_LENGTH {
+		true => with_hash(&name, &full_name),
+		false => name,
+	}
+}
+
+/// FNV-1a, which unlike `DefaultHasher` stays the same across builds. The chats store the names of the tools they use.
+fn name_hash(text: &str) -> String {
+	let hash = text.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+	format!("{:08x}", hash)
+}
+
+/// Cuts `name` so it still fits the length limit with a hash of `original` appended
+fn with_hash(name: &str, original: &str) -> String {
+	let hash = name_hash(original);
+	format!("{}_{}", &name[..name.len().min(MAX_TOOL_NAME_LENGTH - hash.len() - 1)], hash)
+}
+
+/// The names of the server's tools, in the order of `McpClient::tools`. Tools whose names only differ in the characters
+/// `tool_name` replaces, or that would clash with the resource tool, get a hash of their own name appended.
+/// Returns the collisions alongside, for the server's status.
+fn unique_tool_names(client: &McpClient) -> (Vec<String>, Vec<String>) {
+	let mut names: Vec<String> = client.tools.iter().map(|tool| tool_name(&client.name, &tool.name)).collect();
+	let resource_tool_name = tool_name(&client.name, READ_RESOURCE_TOOL);
+	let mut collisions: Vec<String> = vec![];
+	for name in names.iter().cloned().collect::<BTreeSet<String>>() {
+		let colliding: Vec<usize> = (0..names.len()).filter(|&index| names[index] == name).collect();
+		let mut originals: Vec<&str> = colliding.iter().map(|&index| client.tools[index].name.as_str()).collect();
+		if !client.resources.is_empty() && name == resource_tool_name {
+			originals.push("the resource tool");
+		} else if colliding.len() < 2 {
+			continue;
+		}
+		collisions.push(format!(
+			"The tools {} would all be named {}, so they got a hash suffix",
+			originals.join(", "),
+			name
+		));
+		for index in colliding {
+			names[index] = with_hash(&name, &format!("{}__{}", client.name, client.tools[index].name));
+		}
+	}
+	let mut seen: BTreeSet<&str> = BTreeSet::new();
+	for (name, tool) in names.iter().zip(&client.tools) {
+		if !seen.insert(name) {
+			collisions.push(format!("The tool {} is listed twice, only one of them is offered", tool.name));
+		}
+	}
+	(names, collisions)
+}
+
+/// The tools of a connected server, plus a tool to read its resources if it has any
+pub fn client_tools(client: &Arc<McpClient>) -> ToolRegistry {
+	let mut tools = ToolRegistry::default();
+	let (names, _) = unique_tool_names(client);
+	for (name, tool) in names.into_iter().zip(&client.tools) {
+		tools.register(McpTool {
+			name,
+			description: match &tool.description {
+				Some(description) => format!("{} (from the MCP server {})", description, client.name),
+				None => format!("{} from the MCP server {}", tool.name, client.name),
+			},
+			client: client.clone(),
+			tool: tool.clone(),
+		});
+	}
+	if !client.resources.is_empty() {
+		let mut resources: Vec<String> = client
+			.resources
+			.iter()
+			.take(MAX_DESCRIBED_RESOURCES)
+			.map(|resource| match &resource.description {
+				Some(description) => format!("{} ({}): {}", resource.uri, resource.name, description),
+				None => format!("{} ({})", resource.uri, resource.name),
+			})
+			.collect();
+		if client.resources.len() > MAX_DESCRIBED_RESOURCES {
+			resources.push(format!("and {} more", client.resources.len() - MAX_DESCRIBED_RESOURCES));
+		}
+		tools.register(McpResourceTool {
+			name: tool_name(&client.name, READ_RESOURCE_TOOL),
+			description: format!(
+				"Reads a resource of the MCP server {}. Available resources:\n{}",
+				client.name,
+				resources.join("\n")
+			),
+			client: client.clone(),
+		});
+	}
+	tools
+}
+
+/// The tool names of the server that collided and were changed or left out, see `unique_tool_names`
+pub fn tool_name_collisions(client: &McpClient) -> Vec<String> {
+	unique_tool_names(client).1
+}
+
+/// A tool of an MCP server. Calls need the user's approval unless the server marks the tool as read-only.
+pub struct McpTool {
+	name: String,
+	description: String,
+	client: Arc<McpClient>,
+	tool: McpToolInfo,
+}
+
+#[async_trait]
+impl Tool for McpTool {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn description(&self) -> &str {
+		&self.description
+	}
+
+	fn parameters(&self) -> Value {
+		match &self.tool.input_schema {
+			Value::Object(_) => self.tool.input_schema.clone(),
+			_ => json!({ "type": "object", "properties": {} }),
+		}
+	}
+
+	fn needs_approval(&self) -> bool {
+		self.tool.annotations["readOnlyHint"].as_bool() != Some(true)
+	}
+
+	async fn call(&self, arguments: Value) -> Result<String, String> {
+		self.client.call_tool(&self.tool.name, arguments).await
+	}
+}
+
+/// Reads resources of an MCP server by URI
+pub struct McpResourceTool {
+	name: String,
+	description: String,
+	client: Arc<McpClient>,
+}
+
+#[async_trait]
+impl Tool for McpResourceTool {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn description(&self) -> &str {
+		&self.description
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"uri": { "type": "string", "description": "URI of the resource" }
+			},
+			"required": ["uri"]
+		})
+	}
+
+	async fn call(&self, arguments: Value) -> Result<String, String> {
+		match arguments["uri"].as_str() {
+			Some(uri) => self.client.read_resource(uri).await,
+			None => throw!("uri must be a string"),
+		}
+	}
+}
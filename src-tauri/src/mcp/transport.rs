@@ -0,0 +1,330 @@
+//! JSON-RPC 2.0 connections to MCP servers. Stdio servers exchange one JSON message per line,
+//! HTTP servers use the streamable HTTP transport, which answers each POST with JSON or an event stream.
+
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::llm_providers::http::{is_event_stream, read_events};
+use crate::throw;
+
+/// Tool calls may take a while, but a server that hangs shouldn't block a generation forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+/// Stdio servers only see these environment variables and their configured `env`, so API keys loaded from `.env` don't leak into them
+const INHERITED_ENV_VARS: [&str; 16] = [
+	"PATH",
+	"HOME",
+	"USER",
+	"LOGNAME",
+	"SHELL",
+	"TERM",
+	"LANG",
+	"TMPDIR",
+	"APPDATA",
+	"LOCALAPPDATA",
+	"USERPROFILE",
+	"USERNAME",
+	"SYSTEMROOT",
+	"SYSTEMDRIVE",
+	"TEMP",
+	"PROCESSOR_ARCHITECTURE",
+];
+
+type Writer = Arc<tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+/// The requests waiting for an answer by id, `None` once the server closed the connection
+type Responses = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Result<Value, String>>>>>>;
+
+pub enum Transport {
+	Stream(StreamTransport),
+	Http(HttpTransport),
+}
+
+impl Transport {
+	/// Sends a request and waits for its result. Error responses become `Err`.
+	pub async fn request(&self, method: &str, params: Value) -> Result<Value, String> {
+		match self {
+			Transport::Stream(transport) => transport.request(method, params).await,
+			Transport::Http(transport) => transport.request(method, params).await,
+		}
+	}
+
+	/// Sends a notification, which has no answer
+	pub async fn notify(&self, method: &str, params: Value) -> Result<(), String> {
+		let message = json_rpc_message(None, method, params);
+		match self {
+			Transport::Stream(transport) => write_message(&transport.writer, &message).await,
+			Transport::Http(transport) => transport.post(&message).await.map(|_| ()),
+		}
+	}
+}
+
+/// A server speaking newline-delimited JSON over a pair of byte streams, usually the stdin and stdout of its process
+pub struct StreamTransport {
+	name: String,
+	writer: Writer,
+	responses: Responses,
+	next_id: AtomicU64,
+	reader: JoinHandle<()>,
+	/// Kept so the process is stopped along with the transport
+	_child: Option<Child>,
+}
+
+impl StreamTransport {
+	/// Starts a stdio server. Its stderr goes to the log.
+	pub fn spawn(name: &str, command: &str, args: &[String], env: &BTreeMap<String, String>) -> Result<Self, String> {
+		let mut child = match Command::new(command)
+			.args(args)
+			.env_clear()
+			.envs(INHERITED_ENV_VARS.iter().filter_map(|name| Some((*name, std::env::var_os(name)?))))
+			.envs(env)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()
+		{
+			Ok(child) => child,
+			Err(e) => throw!("Could not start the MCP server {} ({}): {}", name, command, e),
+		};
+		let (stdin, stdout, stderr) = match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+			(Some(stdin), Some(stdout), Some(stderr)) => (stdin, stdout, stderr),
+			_ => throw!("Could not connect to the MCP server {}", name),
+		};
+		let server_name = name.to_string();
+		tokio::spawn(async move {
+			let mut lines = BufReader::new(stderr).lines();
+			while let Ok(Some(line)) = lines.next_line().await {
+				log::info!("MCP server {}: {}", server_name, line);
+			}
+		});
+		let mut transport = Self::new(name, stdout, stdin);
+		transport._child = Some(child);
+		Ok(transport)
+	}
+
+	/// Talks to a server over any pair of streams, e.g. one running in the same process
+	pub fn new(name: &str, reader: impl AsyncRead + Send + Unpin + 'static, writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+		let writer: Writer = Arc::new(tokio::sync::Mutex::new(Box::new(writer)));
+		let responses: Responses = Arc::new(Mutex::new(Some(HashMap::new())));
+		let reader = tokio::spawn(read_messages(name.to_string(), reader, writer.clone(), responses.clone()));
+		StreamTransport {
+			name: name.to_string(),
+			writer,
+			responses,
+			next_id: AtomicU64::new(1),
+			reader,
+			_child: None,
+		}
+	}
+
+	async fn request(&self, method: &str, params: Value) -> Result<Value, String> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let (sender, receiver) = oneshot::channel();
+		match self.responses.lock().unwrap().as_mut() {
+			Some(responses) => responses.insert(id, sender),
+			None => throw!("The MCP server {} closed the connection", self.name),
+		};
+		write_message(&self.writer, &json_rpc_message(Some(id), method, params)).await?;
+		match tokio::time::timeout(REQUEST_TIMEOUT, receiver).await {
+			Ok(Ok(result)) => result,
+			Ok(Err(_)) => throw!("The MCP server {} closed the connection", self.name),
+			Err(_) => {
+				if let Some(responses) = self.responses.lock().unwrap().as_mut() {
+					responses.remove(&id);
+				}
+				let cancelled = json_rpc_message(None, "notifications/cancelled", json!({ "requestId": id, "reason": "Timed out" }));
+				let _ = write_message(&self.writer, &cancelled).await;
+				throw!(
+					"The MCP server {} did not answer {} within {} seconds",
+					self.name,
+					method,
+					REQUEST_TIMEOUT.as_secs()
+				)
+			}
+		}
+	}
+}
+
+impl Drop for StreamTransport {
+	fn drop(&mut self) {
+		self.reader.abort();
+	}
+}
+
+/// Passes answers on to the waiting requests and replies to the server's own requests, until the server closes the connection
+async fn read_messages(name: String, reader: impl AsyncRead + Unpin, writer: Writer, responses: Responses) {
+	let mut lines = BufReader::new(reader).lines();
+	loop {
+		let line = match lines.next_line().await {
+			Ok(Some(line)) => line,
+			Ok(None) => break,
+			Err(e) => {
+				log::warn!("Could not read from the MCP server {}: {}", name, e);
+				break;
+			}
+		};
+		if line.trim().is_empty() {
+			continue;
+		}
+		let message = match serde_json::from_str::<Value>(&line) {
+			Ok(message) => message,
+			Err(e) => {
+				log::warn!("The MCP server {} sent invalid JSON ({}): {}", name, e, line);
+				continue;
+			}
+		};
+		if let Some(reply) = handle_message(&name, message, &responses) {
+			if let Err(e) = write_message(&writer, &reply).await {
+				log::warn!("{}", e);
+			}
+		}
+	}
+	// Dropping the senders fails the requests that are still waiting
+	responses.lock().unwrap().take();
+}
+
+/// Routes an answer to its request. Returns the reply to a request of the server: pings are answered,
+/// anything else is unsupported since the client declares no capabilities.
+fn handle_message(name: &str, message: Value, responses: &Responses) -> Option<Value> {
+	let method = message.get("method").and_then(Value::as_str).map(str::to_string);
+	match (method.as_deref(), message.get("id").cloned()) {
+		(Some("ping"), Some(id)) => Some(json!({ "jsonrpc": "2.0", "id": id, "result": {} })),
+		(Some(method), Some(id)) => Some(json!({
+			"jsonrpc": "2.0",
+			"id": id,
+			"error": { "code": -32601, "message": format!("Method not found: {}", method) }
+		})),
+		(Some(method), None) => {
+			log::debug!("The MCP server {} sent {}", name, method);
+			None
+		}
+		(None, Some(id)) => {
+			let sender = id.as_u64().and_then(|request_id| responses.lock().unwrap().as_mut()?.remove(&request_id));
+			match sender {
+				Some(sender) => {
+					let _ = sender.send(response_result(message));
+				}
+				None => log::warn!("The MCP server {} answered the unknown request {}", name, id),
+			}
+			None
+		}
+		(None, None) => {
+			log::warn!("The MCP server {} sent a message without method or id: {}", name, message);
+			None
+		}
+	}
+}
+
+fn json_rpc_message(id: Option<u64>, method: &str, params: Value) -> Value {
+	let mut message = json!({ "jsonrpc": "2.0", "method": method });
+	if let Some(id) = id {
+		message["id"] = json!(id);
+	}
+	if !params.is_null() {
+		message["params"] = params;
+	}
+	message
+}
+
+fn response_result(mut response: Value) -> Result<Value, String> {
+	match response.get("error") {
+		Some(error) => Err(format!(
+			"{} (error {})",
+			error["message"].as_str().unwrap_or("Unknown error"),
+			error["code"].as_i64().unwrap_or_default()
+		)),
+		None => Ok(response["result"].take()),
+	}
+}
+
+async fn write_message(writer: &Writer, message: &Value) -> Result<(), String> {
+	// serde_json doesn't write newlines, so every message stays on one line
+	let mut line = message.to_string();
+	line.push('\n');
+	let mut writer = writer.lock().await;
+	writer
+		.write_all(line.as_bytes())
+		.await
+		.map_err(|e| format!("Could not write to the MCP server: {}", e))?;
+	writer.flush().await.map_err(|e| format!("Could not write to the MCP server: {}", e))
+}
+
+/// A server reachable over HTTP. Servers that keep sessions send an `Mcp-Session-Id` with the answer to `initialize`,
+/// which goes along with all later requests.
+pub struct HttpTransport {
+	url: String,
+	client: reqwest::Client,
+	next_id: AtomicU64,
+	session_id: Mutex<Option<String>>,
+}
+
+impl HttpTransport {
+	/// MCP servers run locally or in the user's network, so they don't go through the providers' proxy
+	pub fn new(url: &str) -> Self {
+		HttpTransport {
+			url: url.to_string(),
+			client: reqwest::Client::new(),
+			next_id: AtomicU64::new(1),
+			session_id: Mutex::new(None),
+		}
+	}
+
+	async fn post(&self, message: &Value) -> Result<reqwest::Response, String> {
+		let mut request = self
+			.client
+			.post(&self.url)
+			.header(reqwest::header::ACCEPT, "application/json, text/event-stream")
+			.timeout(REQUEST_TIMEOUT)
+			.json(message);
+		if let Some(session_id) = self.session_id.lock().unwrap().clone() {
+			request = request.header("Mcp-Session-Id", session_id);
+		}
+		let response = match request.send().await {
+			Ok(response) => response,
+			Err(e) => throw!("Could not reach the MCP server at {}: {}", self.url, e),
+		};
+		if let Some(session_id) = response.headers().get("Mcp-Session-Id").and_then(|session_id| session_id.to_str().ok()) {
+			*self.session_id.lock().unwrap() = Some(session_id.to_string());
+		}
+		let status = response.status();
+		if !status.is_success() {
+			let body = response.text().await.unwrap_or_default();
+			throw!("The MCP server at {} answered with {}: {}", self.url, status, body.trim());
+		}
+		Ok(response)
+	}
+
+	async fn request(&self, method: &str, params: Value) -> Result<Value, String> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let response = self.post(&json_rpc_message(Some(id), method, params)).await?;
+		if !is_event_stream(&response) {
+			return match response.json::<Value>().await {
+				Ok(message) => response_result(message),
+				Err(e) => throw!("Invalid answer to {} from the MCP server at {}: {}", method, self.url, e),
+			};
+		}
+		// The stream may carry the server's notifications before the answer
+		let mut answer = None;
+		let read = read_events(response, |data| {
+			let message: Value = serde_json::from_str(data)?;
+			if message.get("method").is_none() && message["id"].as_u64() == Some(id) {
+				answer = Some(message);
+			}
+			Ok(())
+		})
+		.await;
+		match (answer, read) {
+			(Some(answer), _) => response_result(answer),
+			(None, Err(e)) => throw!("Could not read the answer to {} from the MCP server at {}: {}", method, self.url, e),
+			(None, Ok(_)) => throw!("The MCP server at {} did not answer {}", self.url, method),
+		}
+	}
+}
@@ -0,0 +1,232 @@
+use std::env;
+use std::sync::{Arc, RwLock};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sqlx::SqlitePool;
+#[cfg(feature = "gui")]
+use tauri::command;
+
+#[cfg(feature = "gui")]
+use crate::data::DataState;
+use crate::db::set_default_api_key_secret;
+use crate::throw;
+
+const KEYRING_SERVICE: &str = "byok";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const MASKED_SUFFIX_LEN: usize = 4;
+
+/// Where an API key is stored. Persisted in `api_keys.key_storage`.
+pub const STORAGE_KEYRING: &str = "keyring";
+pub const STORAGE_ENCRYPTED: &str = "encrypted";
+/// Prefixes the references that stand in for secrets in the settings, e.g. in `McpServerConfig::env`
+pub const SECRET_REF_PREFIX: &str = "secret:";
+
+/// Holds the passphrase used for the encrypted-column fallback when the OS keyring
+/// (Secret Service on Linux, Keychain on macOS, Credential Manager on Windows) is not available.
+#[derive(Default, Clone)]
+pub struct SecretStore {
+	passphrase: Arc<RwLock<Option<String>>>,
+	skip_keyring: bool,
+}
+
+impl SecretStore {
+	pub fn from_env() -> Self {
+		SecretStore {
+			passphrase: Arc::new(RwLock::new(env::var("BYOK_PASSPHRASE").ok().filter(|p| !p.is_empty()))),
+			skip_keyring: false,
+		}
+	}
+
+	/// Never touches the OS keyring and stores new keys in the encrypted column only, e.g. for tests
+	pub fn without_keyring(passphrase: &str) -> Self {
+		SecretStore {
+			passphrase: Arc::new(RwLock::new(Some(passphrase.to_string()))),
+			skip_keyring: true,
+		}
+	}
+
+	pub fn set_passphrase(&self, passphrase: String) {
+		*self.passphrase.write().unwrap() = Some(passphrase);
+	}
+
+	fn keyring_entry(key_ref: &str) -> Result<keyring::Entry, String> {
+		keyring::Entry::new(KEYRING_SERVICE, key_ref).map_err(|e| format!("Could not open keyring entry {}: {}", key_ref, e))
+	}
+
+	/// Stores the key in the OS keyring under `key_ref`, falling back to the passphrase-encrypted column.
+	/// Returns the values for the `key_storage` and `key_encrypted` columns of `api_keys`.
+	pub fn store_secret(&self, key_ref: &str, api_key: &str) -> Result<(&'static str, String), String> {
+		if self.skip_keyring {
+			return Ok((STORAGE_ENCRYPTED, self.encrypt(api_key)?));
+		}
+		let keyring_result = Self::keyring_entry(key_ref).and_then(|entry| entry.set_password(api_key).map_err(|e| e.to_string()));
+		match keyring_result {
+			Ok(_) => Ok((STORAGE_KEYRING, String::new())),
+			Err(e) => {
+				log::warn!("OS keyring unavailable for {}, using encrypted column: {}", key_ref, e);
+				Ok((STORAGE_ENCRYPTED, self.encrypt(api_key)?))
+			}
+		}
+	}
+
+	pub fn load_secret(&self, key_ref: &str, storage: &str, encrypted: &str) -> Result<String, String> {
+		match storage {
+			STORAGE_KEYRING => Self::keyring_entry(key_ref)?
+				.get_password()
+				.map_err(|e| format!("Could not read API key {} from keyring: {}", key_ref, e)),
+			STORAGE_ENCRYPTED => self.decrypt(encrypted),
+			_ => throw!("No API key stored for {}", key_ref),
+		}
+	}
+
+	pub fn delete_secret(&self, key_ref: &str) {
+		if self.skip_keyring {
+			return;
+		}
+		if let Ok(entry) = Self::keyring_entry(key_ref) {
+			// The entry may simply not exist, which is fine
+			let _ = entry.delete_password();
+		}
+	}
+
+	/// Stores a secret in the `secrets` table and returns the reference to keep instead of it
+	pub async fn save_secret(&self, secret: &str, pool: &SqlitePool) -> Result<String, String> {
+		let key_ref = format!("secret-{}", uuid::Uuid::new_v4());
+		let (storage, encrypted) = self.store_secret(&key_ref, secret)?;
+		let query = "INSERT INTO secrets (key_ref, key_storage, key_encrypted) VALUES ($1, $2, $3)";
+		match sqlx::query(query).bind(&key_ref).bind(storage).bind(&encrypted).execute(pool).await {
+			Ok(_) => Ok(format!("{}{}", SECRET_REF_PREFIX, key_ref)),
+			Err(e) => {
+				self.delete_secret(&key_ref);
+				throw!("Error saving secret: {}", e)
+			}
+		}
+	}
+
+	/// The secret a reference returned by `save_secret` stands for
+	pub async fn read_secret(&self, reference: &str, pool: &SqlitePool) -> Result<String, String> {
+		let key_ref = match secret_ref(reference) {
+			Some(key_ref) => key_ref,
+			None => throw!("{} is not a reference to a secret", reference),
+		};
+		let query = "SELECT key_storage, key_encrypted FROM secrets WHERE key_ref = $1";
+		match sqlx::query_as::<_, (String, String)>(query).bind(key_ref).fetch_optional(pool).await {
+			Ok(Some((storage, encrypted))) => self.load_secret(key_ref, &storage, &encrypted),
+			Ok(None) => throw!("The secret {} doesn't exist", key_ref),
+			Err(e) => throw!("Error reading secret {}: {}", key_ref, e),
+		}
+	}
+
+	pub async fn remove_secret(&self, reference: &str, pool: &SqlitePool) -> Result<(), String> {
+		let key_ref = match secret_ref(reference) {
+			Some(key_ref) => key_ref,
+			None => throw!("{} is not a reference to a secret", reference),
+		};
+		self.delete_secret(key_ref);
+		match sqlx::query("DELETE FROM secrets WHERE key_ref = $1").bind(key_ref).execute(pool).await {
+			Ok(_) => Ok(()),
+			Err(e) => throw!("Error deleting secret {}: {}", key_ref, e),
+		}
+	}
+
+	/// Moves API keys that are still stored in plain text in `providers.api_key` into secure storage,
+	/// where they become the default key of their provider.
+	/// Keys that cannot be moved yet (no keyring and no passphrase) are left in place and retried on the next call.
+	pub async fn migrate_plaintext_api_keys(&self, pool: &SqlitePool) -> Result<(), String> {
+		let query = "SELECT provider_name, api_key FROM providers WHERE api_key IS NOT NULL AND api_key != ''";
+		let plaintext_keys = match sqlx::query_as::<_, (String, String)>(query).fetch_all(pool).await {
+			Ok(keys) => keys,
+			Err(e) => throw!("Error reading plaintext API keys: {}", e),
+		};
+		for (provider_name, api_key) in plaintext_keys {
+			let result = match set_default_api_key_secret(&provider_name, &api_key, self, pool).await {
+				Ok(_) => sqlx::query("UPDATE providers SET api_key = '' WHERE provider_name = $1")
+					.bind(&provider_name)
+					.execute(pool)
+					.await
+					.map(|_| ())
+					.map_err(|e| e.to_string()),
+				Err(e) => Err(e),
+			};
+			match result {
+				Ok(_) => log::info!("Moved API key for provider {} out of the database", provider_name),
+				Err(e) => log::warn!("Could not move API key for provider {} yet: {}", provider_name, e),
+			}
+		}
+		Ok(())
+	}
+
+	fn derive_key(&self, salt: &[u8]) -> Result<[u8; 32], String> {
+		let passphrase = match self.passphrase.read().unwrap().clone() {
+			Some(passphrase) => passphrase,
+			None => throw!("API keys are locked, please enter your passphrase"),
+		};
+		let mut key = [0u8; 32];
+		Argon2::default()
+			.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+			.map_err(|e| format!("Could not derive encryption key: {}", e))?;
+		Ok(key)
+	}
+
+	/// Encrypts with AES-256-GCM. The result is `base64(salt || nonce || ciphertext)`.
+	fn encrypt(&self, plaintext: &str) -> Result<String, String> {
+		let mut salt = [0u8; SALT_LEN];
+		OsRng.fill_bytes(&mut salt);
+		let key = self.derive_key(&salt)?;
+		let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+		let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+		let ciphertext = cipher.encrypt(&nonce, plaintext.as_bytes()).map_err(|e| format!("Could not encrypt API key: {}", e))?;
+
+		let mut payload = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+		payload.extend_from_slice(&salt);
+		payload.extend_from_slice(&nonce);
+		payload.extend_from_slice(&ciphertext);
+		Ok(BASE64.encode(payload))
+	}
+
+	fn decrypt(&self, encoded: &str) -> Result<String, String> {
+		let payload = BASE64.decode(encoded).map_err(|e| format!("Corrupt encrypted API key: {}", e))?;
+		if payload.len() <= SALT_LEN + NONCE_LEN {
+			throw!("Corrupt encrypted API key");
+		}
+		let (salt, rest) = payload.split_at(SALT_LEN);
+		let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+		let key = self.derive_key(salt)?;
+		let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+		let plaintext = match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+			Ok(plaintext) => plaintext,
+			Err(_) => throw!("Could not decrypt API key, the passphrase is probably wrong"),
+		};
+		String::from_utf8(plaintext).map_err(|e| e.to_string())
+	}
+}
+
+/// The `key_ref` of a value that references a secret stored with `SecretStore::save_secret`
+pub fn secret_ref(value: &str) -> Option<&str> {
+	value.strip_prefix(SECRET_REF_PREFIX)
+}
+
+/// Returns only the last few characters of a key, e.g. `••••••••abcd`, so full keys never reach the webview.
+pub fn mask_api_key(api_key: &str) -> String {
+	let chars: Vec<char> = api_key.chars().collect();
+	if chars.len() <= MASKED_SUFFIX_LEN * 2 {
+		return "•".repeat(8);
+	}
+	let suffix: String = chars[chars.len() - MASKED_SUFFIX_LEN..].iter().collect();
+	format!("{}{}", "•".repeat(8), suffix)
+}
+
+/// Sets the passphrase for the encrypted fallback and moves any leftover plaintext keys.
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn unlock_api_keys(passphrase: String, data: DataState<'_>) -> Result<(), String> {
+	data.0.secrets.set_passphrase(passphrase);
+	data.0.secrets.migrate_plaintext_api_keys(&data.0.db_pool).await
+}
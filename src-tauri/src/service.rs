@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sqlx::SqlitePool;
+
+use crate::attachments::render_attachment_block;
+use crate::db::{
+	fetch_all_models, fetch_chat, fetch_chat_tools, fetch_chats, fetch_models, get_api_key, get_chat_display_name, get_messages, insert_attachments,
+	insert_chat_display_name, insert_message, insert_message_blocks, set_chat_tool_enabled, touch_chat, update_chat_display_name,
+};
+use crate::events::EventSink;
+use crate::llm_providers::{cassette, LLMConfig, LLMProvider, ToolCall};
+use crate::secrets::SecretStore;
+use crate::throw;
+use crate::tools::approval::{DenyAll, ToolApprovalRequest, ToolApprover};
+use crate::tools::{ChatTool, ToolRegistry, MAX_TOOL_ITERATIONS};
+use crate::types::{Attachment, Chats, Message, MessageBlock, MessageBlocks, MessageHistory, Models};
+use crate::utils::{file_extension_for_language, render_message, render_tool_block};
+
+/// Chats are named after their first answer, until then they have a placeholder name with this prefix
+pub const UNNAMED_CHAT_PREFIX: &str = "unnamed_new_chat_";
+
+/// The chat logic, independent of Tauri. The commands, the job worker and the CLI go through it,
+/// and tests can run it on an in-memory database (see `db::init_in_memory`).
+#[derive(Clone)]
+pub struct ChatService {
+	pub pool: SqlitePool,
+	pub secrets: SecretStore,
+	pub events: Arc<dyn EventSink>,
+	/// API roots that replace the default ones of their providers, see `with_base_url`
+	pub base_urls: HashMap<String, String>,
+	/// The tools the models can use, none by default. Each chat only gets the ones the user turned on for it.
+	pub tools: ToolRegistry,
+	/// Asked before side-effecting tools run, declines all calls by default
+	pub approver: Arc<dyn ToolApprover>,
+}
+
+impl ChatService {
+	pub fn new(pool: SqlitePool, secrets: SecretStore, events: Arc<dyn EventSink>) -> Self {
+		ChatService {
+			pool,
+			secrets,
+			events,
+			base_urls: HashMap::new(),
+			tools: ToolRegistry::default(),
+			approver: Arc::new(DenyAll),
+		}
+	}
+
+	pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+		self.tools = tools;
+		self
+	}
+
+	pub fn with_approver(mut self, approver: Arc<dyn ToolApprover>) -> Self {
+		self.approver = approver;
+		self
+	}
+
+	/// Sends the requests for a provider to `base_url`, e.g. a proxy, another Ollama host or a mock server
+	pub fn with_base_url(mut self, provider_name: &str, base_url: &str) -> Self {
+		self.base_urls.insert(provider_name.to_string(), base_url.to_string());
+		self
+	}
+
+	pub async fn chats(&self) -> Result<Chats, String> {
+		fetch_chats(&self.pool).await
+	}
+
+	pub async fn messages(&self, chat_id: &str) -> Result<Vec<Message>, String> {
+		fetch_chat(chat_id, &self.pool).await
+	}
+
+	pub async fn models(&self) -> Result<Models, String> {
+		fetch_models(&self.pool).await
+	}
+
+	pub async fn all_models(&self) -> Result<Models, String> {
+		fetch_all_models(&self.pool).await
+	}
+
+	pub async fn rename_chat(&self, chat_id: &str, display_name: &str) -> Result<(), String> {
+		update_chat_display_name(chat_id, display_name, &self.pool).await
+	}
+
+	pub async fn archive_chat(&self, chat_id: &str) -> Result<(), String> {
+		let archive_chat_query: &str = "UPDATE chats SET archived = 'true' WHERE id = $1";
+		match sqlx::query(archive_chat_query).bind(chat_id).execute(&self.pool).await {
+			Ok(_) => Ok(()),
+			Err(e) => throw!("Error archiving chat {}: {}", chat_id, e),
+		}
+	}
+
+	/// Deletes the chat with everything that belongs to it, all or nothing
+	pub async fn delete_chat(&self, chat_id: &str) -> Result<(), String> {
+		match delete_chat_rows(chat_id, &self.pool).await {
+			Ok(_) => Ok(()),
+			Err(e) => throw!("Error deleting chat {}: {}", chat_id, e),
+		}
+	}
+
+	/// Pins a key to a chat. `None` makes the chat use the default key of its provider again.
+	pub async fn set_chat_api_key(&self, chat_id: &str, api_key_id: Option<&str>) -> Result<(), String> {
+		let query = "UPDATE chats SET api_key_id = $1 WHERE id = $2";
+		match sqlx::query(query).bind(api_key_id.unwrap_or("NA")).bind(chat_id).execute(&self.pool).await {
+			Ok(_) => Ok(()),
+			Err(e) => throw!("Error pinning API key to chat {}: {}", chat_id, e),
+		}
+	}
+
+	pub async fn mark_blocks_copied(&self, block_ids: &[i32]) -> Result<(), String> {
+		let query = "UPDATE message_blocks SET copied = TRUE WHERE id IN (SELECT value FROM json_each($1))";
+		let block_ids = serde_json::to_string(block_ids).map_err(|e| e.to_string())?;
+		match sqlx::query(query).bind(&block_ids).execute(&self.pool).await {
+			Ok(_) => Ok(()),
+			Err(e) => throw!("Error marking message blocks as copied: {}", e),
+		}
+	}
+
+	pub async fn message_block(&self, block_id: i32) -> Result<MessageBlock, String> {
+		let query = "SELECT id, type_, language, raw_content, rendered_content, copied FROM message_blocks WHERE id = $1";
+		match sqlx::query_as::<_, MessageBlock>(query).bind(block_id).fetch_optional(&self.pool).await {
+			Ok(Some(block)) => Ok(block),
+			Ok(None) => throw!("Message block {} does not exist", block_id),
+			Err(e) => throw!("Error loading message block {}: {}", block_id, e),
+		}
+	}
+
+	/// Writes the raw content of a block to a file. If `path` has no extension, one is derived from the block's language.
+	/// An existing file is only replaced with `overwrite`, which is meant for paths from a save dialog that already asked the user,
+	/// and never when the extension was added. Returns the path that was written. Saved blocks count as copied.
+	pub async fn save_block_to_file(&self, block_id: i32, path: &Path, overwrite: bool) -> Result<PathBuf, String> {
+		let block = self.message_block(block_id).await?;
+		let mut path = path.to_path_buf();
+		let mut overwrite = overwrite;
+		if path.extension().is_none() {
+			path.set_extension(file_extension_for_language(block.language.as_deref().unwrap_or("plain")));
+			overwrite = false;
+		}
+		let mut options = std::fs::OpenOptions::new();
+		if overwrite {
+			options.write(true).create(true).truncate(true);
+		} else {
+			options.write(true).create_new(true);
+		}
+		let written = options.open(&path).and_then(|mut file| file.write_all(block.raw_content.as_bytes()));
+		match written {
+			Ok(()) => (),
+			Err(e) if e.kind() == ErrorKind::AlreadyExists => throw!("{} already exists", path.display()),
+			Err(e) => throw!("Could not write {:?}: {}", path, e),
+		}
+		self.mark_blocks_copied(&[block_id]).await?;
+		Ok(path)
+	}
+
+	/// Returns the code of all code blocks of a message, separated by blank lines, and marks them as copied
+	pub async fn copy_all_code(&self, message_id: &str) -> Result<String, String> {
+		let query =
+			"SELECT id, type_, language, raw_content, rendered_content, copied FROM message_blocks WHERE message_id = $1 AND type_ = 'code' ORDER BY id";
+		let blocks = match sqlx::query_as::<_, MessageBlock>(query).bind(message_id).fetch_all(&self.pool).await {
+			Ok(blocks) => blocks,
+			Err(e) => throw!("Error loading code blocks of message {}: {}", message_id, e),
+		};
+		if blocks.is_empty() {
+			throw!("Message {} has no code blocks", message_id);
+		}
+		let block_ids: Vec<i32> = blocks.iter().filter_map(|block| block.id).collect();
+		self.mark_blocks_copied(&block_ids).await?;
+		Ok(blocks.iter().map(|block| block.raw_content.trim_end()).collect::<Vec<_>>().join("\n\n"))
+	}
+
+	/// The provider with the key for the chat, see `get_api_key`
+	pub async fn llm(&self, provider_name: &str, chat_id: Option<&str>) -> Result<LLMProvider, String> {
+		let mut api_key: String = "".to_string();
+		if provider_name != "local" {
+			api_key = match get_api_key(provider_name, chat_id, &self.secrets, &self.pool).await {
+				Ok(api_key) => api_key,
+				// Replayed answers need no key, so demos also run on machines without one
+				Err(_) if cassette::is_replaying() => String::new(),
+				Err(e) => return Err(e),
+			};
+		}
+		let llm = match self.base_urls.get(provider_name) {
+			Some(base_url) => LLMProvider::with_base_url(provider_name, api_key, base_url),
+			None => LLMProvider::new(provider_name, api_key),
+		};
+		llm.map_err(|e| e.to_string())
+	}
+
+	/// Stores a message with its rendered blocks, creating the chat with a placeholder name if it's new.
+	/// Emits `newChat` for a new chat and `newMessage`. Returns the id of the message.
+	pub async fn add_message(&self, chat_id: &str, role: &str, content: &str, model_name: &str, code_theme: &str) -> Result<String, String> {
+		let blocks = render_message(content, code_theme).await;
+		self.store_message(chat_id, new_message(role, content, model_name), &blocks).await
+	}
+
+	/// Stores a user message with the files attached to it, each shown as an `attachment` block after the text
+	pub async fn add_message_with_attachments(
+		&self,
+		chat_id: &str,
+		content: &str,
+		attachments: Vec<Attachment>,
+		model_name: &str,
+		code_theme: &str,
+	) -> Result<String, String> {
+		let mut blocks = render_message(content, code_theme).await;
+		blocks.extend(attachments.iter().map(render_attachment_block));
+		let message = Message {
+			attachments,
+			..new_message("user", content, model_name)
+		};
+		self.store_message(chat_id, message, &blocks).await
+	}
+
+	/// Stores an answer that calls tools, with a `tool_call` block for each call after the text
+	pub async fn add_tool_calls(&self, chat_id: &str, content: &str, tool_calls: &[ToolCall], model_name: &str, code_theme: &str) -> Result<String, String> {
+		let mut blocks = render_message(content, code_theme).await;
+		for call in tool_calls {
+			blocks.push(render_tool_block("tool_call", &call.name, &call.arguments, code_theme));
+		}
+		let message = Message {
+			tool_calls: Some(tool_calls.to_vec()),
+			..new_message("assistant", content, model_name)
+		};
+		self.store_message(chat_id, message, &blocks).await
+	}
+
+	/// Stores the result of a tool call as a `tool` message
+	pub async fn add_tool_result(&self, chat_id: &str, call: &ToolCall, result: &str, model_name: &str, code_theme: &str) -> Result<String, String> {
+		let blocks = MessageBlocks(vec![render_tool_block("tool_result", &call.name, result, code_theme)]);
+		let message = Message {
+			tool_call_id: Some(call.id.clone()),
+			..new_message("tool", result, model_name)
+		};
+		self.store_message(chat_id, message, &blocks).await
+	}
+
+	async fn store_message(&self, chat_id: &str, message: Message, blocks: &MessageBlocks) -> Result<String, String> {
+		let model_name = &message.model_name;
+		let chat_is_new = match get_chat_display_name(chat_id, &self.pool).await {
+			Ok(display_name) => display_name.is_none(),
+			Err(e) => throw!("Error fetching display name from database: {}", e),
+		};
+		if chat_is_new {
+			insert_chat_display_name(chat_id, model_name, &format!("{}{}", UNNAMED_CHAT_PREFIX, chat_id), &self.pool).await?;
+			self.events.emit("newChat", chat_id);
+		}
+
+		insert_message(&message, chat_id, &self.pool).await;
+		insert_attachments(&message.id, chat_id, &message.attachments, &self.pool).await?;
+		insert_message_blocks(&message.id, blocks, &self.pool).await;
+		self.events.emit("newMessage", chat_id);
+		Ok(message.id)
+	}
+
+	/// Whether the chat still has its placeholder name
+	pub async fn is_unnamed(&self, chat_id: &str) -> Result<bool, String> {
+		match get_chat_display_name(chat_id, &self.pool).await {
+			Ok(Some((display_name,))) => Ok(display_name.starts_with(UNNAMED_CHAT_PREFIX)),
+			Ok(None) => throw!("Chat {} not found in the database", chat_id),
+			Err(e) => throw!("Error fetching display name from database: {}", e),
+		}
+	}
+
+	/// Moves the chat to the top of the chat list
+	pub async fn touch_chat(&self, chat_id: &str) -> Result<(), String> {
+		touch_chat(chat_id, &self.pool).await
+	}
+
+	/// Sends the messages of the chat to the model and returns its answer, without storing it
+	pub async fn complete(&self, llm: &LLMProvider, chat_id: &str, model_name: &str) -> Result<String, String> {
+		let messages: MessageHistory = get_messages(chat_id, &self.pool).await.map_err(|e| e.to_string())?;
+		match llm.send_message(&messages, model_name, &LLMConfig::default()).await {
+			Ok(answer) => Ok(answer),
+			Err(e) => throw!("Error sending message to LLM: {}", e),
+		}
+	}
+
+	/// The tools the chat may use
+	pub async fn chat_tools(&self, chat_id: &str) -> Result<ToolRegistry, String> {
+		let chat_tools = fetch_chat_tools(chat_id, &self.pool).await?;
+		Ok(self.tools.filter(|tool| chat_tools.iter().any(|tool_name| tool_name == tool.name())))
+	}
+
+	/// All tools with whether the chat may use them
+	pub async fn chat_tool_settings(&self, chat_id: &str) -> Result<Vec<ChatTool>, String> {
+		let enabled = self.chat_tools(chat_id).await?;
+		Ok(self
+			.tools
+			.iter()
+			.map(|tool| ChatTool {
+				name: tool.name().to_string(),
+				description: tool.description().to_string(),
+				enabled: enabled.get(tool.name()).is_some(),
+				needs_approval: tool.needs_approval(),
+			})
+			.collect())
+	}
+
+	pub async fn set_chat_tool(&self, chat_id: &str, tool_name: &str, enabled: bool) -> Result<(), String> {
+		if self.tools.get(tool_name).is_none() {
+			throw!("Unknown tool {}, available tools are: {}", tool_name, self.tools.names().join(", "));
+		}
+		set_chat_tool_enabled(chat_id, tool_name, enabled, &self.pool).await
+	}
+
+	/// Runs the call if the chat may use the tool and, for side-effecting tools, the user approves it
+	async fn run_tool(&self, chat_id: &str, tools: &ToolRegistry, call: &ToolCall) -> Result<String, String> {
+		let needs_approval = tools.get(&call.name).map_or(false, |tool| tool.needs_approval());
+		if needs_approval {
+			let request = ToolApprovalRequest {
+				id: uuid::Uuid::new_v4().to_string(),
+				chat_id: chat_id.to_string(),
+				tool_name: call.name.clone(),
+				arguments: call.arguments.clone(),
+			};
+			if !self.approver.approve(&request).await {
+				throw!("The user did not allow running {}", call.name);
+			}
+		}
+		tools.call(call).await
+	}
+
+	/// Sends the messages of the chat to the model and runs the tools it calls, until it answers without calling any.
+	/// The answers that call tools and the results are stored as messages, the final answer is returned without storing it.
+	pub async fn complete_with_tools(&self, llm: &LLMProvider, chat_id: &str, model_name: &str, code_theme: &str) -> Result<String, String> {
+		let tools = self.chat_tools(chat_id).await?;
+		let specs = tools.specs();
+		for _ in 0..MAX_TOOL_ITERATIONS {
+			let messages: MessageHistory = get_messages(chat_id, &self.pool).await.map_err(|e| e.to_string())?;
+			let response = match llm.send_message_with_tools(&messages, model_name, &LLMConfig::default(), &specs).await {
+				Ok(response) => response,
+				Err(e) => throw!("Error sending message to LLM: {}", e),
+			};
+			if response.tool_calls.is_empty() {
+				return Ok(response.content);
+			}
+
+			self.add_tool_calls(chat_id, &response.content, &response.tool_calls, model_name, code_theme)
+				.await?;
+			for call in &response.tool_calls {
+				log::debug!("Calling tool {} with {}", call.name, call.arguments);
+				// The model sees failures as results, so it can fix its arguments or do without the tool
+				let result = match self.run_tool(chat_id, &tools, call).await {
+					Ok(result) => result,
+					Err(e) => format!("Error: {}", e),
+				};
+				self.add_tool_result(chat_id, call, &result, model_name, code_theme).await?;
+			}
+		}
+		throw!("The model was still calling tools after {} steps, stopped it", MAX_TOOL_ITERATIONS)
+	}
+
+	/// Runs `complete_with_tools` and stores the answer under `answer_id`, returning the id. Runs nothing if a message with that id
+	/// is already stored, so a turn that is run again after the app quit doesn't answer twice. Calls and results stored before
+	/// the app quit are sent along, so their tools don't run again, calls without a result are sent as interrupted.
+	pub async fn answer(&self, llm: &LLMProvider, chat_id: &str, answer_id: &str, model_name: &str, code_theme: &str) -> Result<String, String> {
+		match sqlx::query("SELECT 1 FROM messages WHERE id = $1")
+			.bind(answer_id)
+			.fetch_optional(&self.pool)
+			.await
+		{
+			Ok(Some(_)) => {
+				log::info!("Answer {} of chat {} is already stored", answer_id, chat_id);
+				return Ok(answer_id.to_string());
+			}
+			Ok(None) => (),
+			Err(e) => throw!("Error fetching message {}: {}", answer_id, e),
+		}
+		let answer = self.complete_with_tools(llm, chat_id, model_name, code_theme).await?;
+		let blocks = render_message(&answer, code_theme).await;
+		let message = Message {
+			id: answer_id.to_string(),
+			..new_message("assistant", &answer, model_name)
+		};
+		self.store_message(chat_id, message, &blocks).await
+	}
+
+	/// Like `complete`, but calls `on_delta` with each piece of the answer as it arrives
+	pub async fn complete_streaming(
+		&self,
+		llm: &LLMProvider,
+		chat_id: &str,
+		model_name: &str,
+		on_delta: &mut (dyn FnMut(&str) + Send),
+	) -> Result<String, String> {
+		let messages: MessageHistory = get_messages(chat_id, &self.pool).await.map_err(|e| e.to_string())?;
+		match llm.stream_message(&messages, model_name, &LLMConfig::default(), on_delta).await {
+			Ok(answer) => Ok(answer),
+			Err(e) => throw!("Error sending message to LLM: {}", e),
+		}
+	}
+
+	/// Asks the model for a short name for the chat, based on its first message and answer, and emits `newChat`
+	pub async fn name_chat(&self, llm: &LLMProvider, chat_id: &str, model_name: &str) -> Result<String, String> {
+		const MAX_DISPLAY_NAME_LENGTH: u32 = 32;
+
+		let messages = get_messages(chat_id, &self.pool).await.map_err(|e| e.to_string())?;
+		let first_message_with_role = |role: &str| {
+			messages
+				.iter()
+				.find(|message| message.role == role && message.tool_calls.is_none())
+				.map(|message| message.content.clone())
+		};
+		let (msg, answer) = match (first_message_with_role("user"), first_message_with_role("assistant")) {
+			(Some(msg), Some(answer)) => (msg, answer),
+			_ => throw!("Chat {} has no answer to name it after", chat_id),
+		};
+
+		let display_name_messages: MessageHistory = MessageHistory(vec![Message {
+			id: "".to_string(),
+			role: "user".to_string(),
+			content: format!(
+				"Please respond with the topic of the thread for these two messages:
+				'user': '{msg}',
+				'assistant': '{answer}'
+				Your response will be used to name the chat, therefore omit any other content from your response, keep it short and use the language used in the prompt.
+				Do not use quotation marks. Capitalize the first letter of your answer. It is okay if your answer consists of keywords, it does not need to be a complete sentence."
+			),
+			model_name: model_name.to_string(),
+			blocks: None,
+			tool_calls: None,
+			tool_call_id: None,
+			attachments: vec![],
+		}]);
+
+		let llm_config = LLMConfig {
+			temperature: 0.0,
+			max_tokens: MAX_DISPLAY_NAME_LENGTH,
+			top_p: None,
+			max_retries: None,
+		};
+
+		let new_chat_display_name = match llm.send_message(&display_name_messages, model_name, &llm_config).await {
+			Ok(answer) => answer,
+			Err(e) => throw!("Error sending message to LLM: {}", e),
+		};
+		log::debug!("New chat display name: {}", new_chat_display_name);
+
+		self.rename_chat(chat_id, &new_chat_display_name).await?;
+		// emit event saying there are new chats
+		self.events.emit("newChat", ());
+		Ok(new_chat_display_name)
+	}
+
+	/// A whole turn at once: stores the prompt with its attachments, streams the answer to `on_delta` and stores it, then names or touches the chat.
+	/// With tools, the answer is passed to `on_delta` at once after the tool calls.
+	/// The app runs these steps as jobs instead, see `providers::get_message`.
+	pub async fn ask(
+		&self,
+		chat_id: &str,
+		provider_name: &str,
+		model_name: &str,
+		prompt: &str,
+		attachments: Vec<Attachment>,
+		code_theme: &str,
+		on_delta: &mut (dyn FnMut(&str) + Send),
+	) -> Result<String, String> {
+		self.add_message_with_attachments(chat_id, prompt, attachments, model_name, code_theme).await?;
+		let llm = self.llm(provider_name, Some(chat_id)).await?;
+		let answer = match self.chat_tools(chat_id).await?.is_empty() {
+			true => self.complete_streaming(&llm, chat_id, model_name, on_delta).await?,
+			false => {
+				let answer = self.complete_with_tools(&llm, chat_id, model_name, code_theme).await?;
+				on_delta(&answer);
+				answer
+			}
+		};
+		self.add_message(chat_id, "assistant", &answer, model_name, code_theme).await?;
+
+		if self.is_unnamed(chat_id).await? {
+			if let Err(e) = self.name_chat(&llm, chat_id, model_name).await {
+				log::warn!("Could not name chat {}: {}", chat_id, e);
+			}
+		} else {
+			self.touch_chat(chat_id).await?;
+		}
+		Ok(answer)
+	}
+}
+
+async fn delete_chat_rows(chat_id: &str, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+	let queries = [
+		"DELETE FROM message_blocks WHERE message_id IN (SELECT id FROM messages WHERE chat_id = $1)",
+		"DELETE FROM attachments WHERE chat_id = $1",
+		"DELETE FROM messages WHERE chat_id = $1",
+		"DELETE FROM chat_tools WHERE chat_id = $1",
+		"DELETE FROM jobs WHERE chat_id = $1",
+		"DELETE FROM chats WHERE id = $1",
+	];
+	let mut transaction = pool.begin().await?;
+	for query in queries {
+		sqlx::query(query).bind(chat_id).execute(&mut *transaction).await?;
+	}
+	transaction.commit().await
+}
+
+fn new_message(role: &str, content: &str, model_name: &str) -> Message {
+	Message {
+		id: uuid::Uuid::new_v4().to_string(),
+		role: role.to_string(),
+		content: content.to_string(),
+		model_name: model_name.to_string(),
+		blocks: None,
+		tool_calls: None,
+		tool_call_id: None,
+		attachments: vec![],
+	}
+}
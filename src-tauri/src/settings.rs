@@ -1,86 +1,392 @@
 use std::path::PathBuf;
+#[cfg(feature = "gui")]
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use specta::Type;
+use sqlx::SqlitePool;
+#[cfg(feature = "gui")]
 use tauri::command;
 
+use crate::data::Data;
+#[cfg(feature = "gui")]
 use crate::data::DataState;
+use crate::db::DEFAULT_MODELS;
+#[cfg(feature = "gui")]
+use crate::jobs::{enqueue, JobKind};
+use crate::llm_providers::http;
+#[cfg(feature = "gui")]
+use crate::mcp::{remove_unused_env_secrets, validate_servers};
+use crate::mcp::{store_env_secrets, McpServerConfig};
+use crate::secrets::SecretStore;
+use crate::themes::{theme_exists, DEFAULT_CODE_THEME};
+use crate::throw;
 use crate::types::MessageBlock;
 use crate::utils::highlight_code;
 
+const REHIGHLIGHT_BATCH_SIZE: usize = 200;
+
+/// Bump this and add a migration to `SETTINGS_MIGRATIONS` when the meaning of a field changes.
+/// New fields don't need a migration, they get their default.
+pub const SETTINGS_VERSION: u32 = 2;
+
+/// `SETTINGS_MIGRATIONS[n]` migrates the raw settings from version `n + 1` to `n + 2`
+const SETTINGS_MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[migrate_settings_v1_to_v2];
+
+/// Version 1 files predate the `version` field. Their default model was hardcoded to one that
+/// isn't in `DEFAULT_MODELS`, so it's reset to the default.
+fn migrate_settings_v1_to_v2(settings: &mut Map<String, Value>) {
+	if settings.get("default_model").and_then(Value::as_str) == Some("claude-3-opus-20240229") {
+		settings.remove("default_model");
+		settings.remove("default_provider");
+	}
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Type)]
+#[serde(default)]
 pub struct Settings {
+	pub version: u32,
 	pub default_model: String,
 	pub default_provider: String,
 	pub code_theme: String,
+	/// Used instead of `code_theme` while the system appearance is light
+	pub code_theme_light: Option<String>,
+	/// How often failed requests to providers are retried (connection errors, 429 and 503 responses), at most `http::MAX_RETRIES`
+	pub max_retries: u32,
+	/// Proxy URL for all requests to providers, e.g. `http://localhost:8080`
+	pub proxy: Option<String>,
+	/// Folders the file and shell tools may access
+	pub tool_folders: Vec<String>,
+	/// Model Context Protocol servers whose tools the chats can use
+	pub mcp_servers: Vec<McpServerConfig>,
+}
+impl Default for Settings {
+	fn default() -> Self {
+		let default_model = DEFAULT_MODELS.iter().find(|model| model.provider_name == "anthropic").unwrap_or(&DEFAULT_MODELS[0]);
+		Settings {
+			version: SETTINGS_VERSION,
+			default_model: default_model.model_name.clone(),
+			default_provider: default_model.provider_name.clone(),
+			code_theme: DEFAULT_CODE_THEME.to_string(),
+			code_theme_light: None,
+			max_retries: 2,
+			proxy: None,
+			tool_folders: vec![],
+			mcp_servers: vec![],
+		}
+	}
 }
 impl Settings {
-	pub fn load(settings_file: &PathBuf) -> Self {
-		let settings = match std::fs::read_to_string(settings_file) {
-			Ok(settings) => settings,
-			Err(_) => {
-				let default_settings = Settings {
-					default_model: "claude-3-opus-20240229".to_string(),
-					default_provider: "anthropic".to_string(),
-					code_theme: "base16-eighties.dark".to_string(),
-				};
-				let settings = serde_json::to_string(&default_settings).unwrap();
-				std::fs::write(settings_file, &settings).unwrap();
-				settings
+	/// Loads the settings file, migrating it from older versions. A corrupt file is backed up and replaced by the defaults,
+	/// in which case a warning for the user is returned alongside.
+	pub fn load(settings_file: &PathBuf) -> (Self, Option<String>) {
+		let contents = match std::fs::read_to_string(settings_file) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				let settings = Settings::default();
+				if let Err(e) = settings.save(settings_file) {
+					log::error!("{}", e);
+				}
+				return (settings, None);
+			}
+			Err(e) => {
+				let warning = format!("Your settings could not be read and the defaults are used for now: {}", e);
+				log::error!("{}", warning);
+				return (Settings::default(), Some(warning));
 			}
 		};
-		serde_json::from_str(&settings).unwrap()
+
+		let (mut settings, migrated) = match Self::parse(&contents) {
+			Ok(parsed) => parsed,
+			Err(e) => {
+				log::error!("Corrupt settings file {:?}: {}", settings_file, e);
+				return (Settings::default(), Some(Self::backup_and_reset(settings_file, &e)));
+			}
+		};
+		// A theme may have been removed from the themes directory or mistyped in settings.json
+		if !theme_exists(&settings.code_theme) {
+			log::warn!("Unknown code theme {}, using {}", settings.code_theme, DEFAULT_CODE_THEME);
+			settings.code_theme = DEFAULT_CODE_THEME.to_string();
+		}
+		if settings.code_theme_light.as_deref().map_or(false, |theme| !theme_exists(theme)) {
+			log::warn!("Unknown light code theme {:?}, using {}", settings.code_theme_light, settings.code_theme);
+			settings.code_theme_light = None;
+		}
+		if migrated {
+			if let Err(e) = settings.save(settings_file) {
+				log::error!("{}", e);
+			}
+		}
+		(settings, None)
 	}
-	pub fn save(&self, settings_file: &PathBuf) {
-		let settings = serde_json::to_string(&self).unwrap();
-		std::fs::write(settings_file, &settings).unwrap();
+
+	/// Parses the settings and runs the migrations they need. Returns whether any migration ran.
+	fn parse(contents: &str) -> Result<(Self, bool), String> {
+		let mut raw_settings = match serde_json::from_str::<Value>(contents).map_err(|e| e.to_string())? {
+			Value::Object(raw_settings) => raw_settings,
+			_ => throw!("The settings are not a JSON object"),
+		};
+		let version = match raw_settings.get("version") {
+			Some(version) => match version.as_u64() {
+				Some(version) if version >= 1 => version as u32,
+				_ => throw!("Invalid settings version {}", version),
+			},
+			None => 1,
+		};
+		if version > SETTINGS_VERSION {
+			log::warn!("The settings were written by a newer version of the app (version {}), unknown fields are ignored", version);
+		}
+		for migration in SETTINGS_MIGRATIONS.iter().skip(version as usize - 1) {
+			migration(&mut raw_settings);
+		}
+		raw_settings.insert("version".to_string(), Value::from(SETTINGS_VERSION.max(version)));
+		let settings: Settings = serde_json::from_value(Value::Object(raw_settings)).map_err(|e| e.to_string())?;
+		Ok((settings, version < SETTINGS_VERSION))
+	}
+
+	/// Moves the corrupt file aside and writes the defaults. Returns the warning for the user.
+	fn backup_and_reset(settings_file: &PathBuf, error: &str) -> String {
+		let backup_file = settings_file.with_extension(format!("json.{}.bak", chrono::Local::now().format("%Y%m%d%H%M%S")));
+		let backup_note = match std::fs::rename(settings_file, &backup_file) {
+			Ok(_) => format!("The old file was saved as {}.", backup_file.display()),
+			Err(e) => format!("The old file could not be backed up: {}", e),
+		};
+		if let Err(e) = Settings::default().save(settings_file) {
+			log::error!("{}", e);
+		}
+		format!("Your settings file was invalid and has been reset to the defaults ({}). {}", error, backup_note)
+	}
+
+	pub fn save(&self, settings_file: &PathBuf) -> Result<(), String> {
+		let settings = serde_json::to_string_pretty(&self).map_err(|e| e.to_string())?;
+		std::fs::write(settings_file, &settings).map_err(|e| format!("Could not save settings to {:?}: {}", settings_file, e))
+	}
+
+	/// Makes sure the default model exists and is visible, otherwise falls back to a model of the same provider,
+	/// or any model. Returns whether the settings changed.
+	pub async fn validate_default_model(&mut self, pool: &SqlitePool) -> bool {
+		let models = match default_model_candidates(pool).await {
+			Ok(models) => models,
+			Err(e) => {
+				log::error!("Could not validate the default model: {}", e);
+				return false;
+			}
+		};
+		if models.iter().any(|(provider_name, model_name)| provider_name == &self.default_provider && model_name == &self.default_model) {
+			return false;
+		}
+		let fallback = models
+			.iter()
+			.find(|(provider_name, _)| provider_name == &self.default_provider)
+			.or_else(|| models.first());
+		match fallback {
+			Some((provider_name, model_name)) => {
+				log::warn!(
+					"Default model {}/{} is not available, using {}/{}",
+					self.default_provider,
+					self.default_model,
+					provider_name,
+					model_name
+				);
+				self.default_provider = provider_name.clone();
+				self.default_model = model_name.clone();
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Moves the `env` values of the MCP servers that settings from older versions hold in plain text into secure storage.
+	/// Values that can't be moved yet (no keyring and no passphrase) are left in place and retried on the next start.
+	/// Returns whether the settings changed.
+	pub async fn store_mcp_env_secrets(&mut self, secrets: &SecretStore, pool: &SqlitePool) -> bool {
+		let mut mcp_servers = self.mcp_servers.clone();
+		match store_env_secrets(&mut mcp_servers, &self.mcp_servers, secrets, pool).await {
+			Ok(_) if mcp_servers != self.mcp_servers => {
+				self.mcp_servers = mcp_servers;
+				true
+			}
+			Ok(_) => false,
+			Err(e) => {
+				log::warn!("{}", e);
+				false
+			}
+		}
+	}
+
+	/// The code theme for the given system appearance
+	pub fn code_theme_for(&self, dark_mode: bool) -> &str {
+		match (&self.code_theme_light, dark_mode) {
+			(Some(code_theme_light), false) => code_theme_light,
+			_ => &self.code_theme,
+		}
 	}
 }
 
+/// The models that can be the default model: shown and not deprecated
+async fn default_model_candidates(pool: &SqlitePool) -> Result<Vec<(String, String)>, sqlx::Error> {
+	let query = "SELECT provider_name, model_name FROM models WHERE show = TRUE AND deprecated = FALSE ORDER BY provider_name, model_name";
+	sqlx::query_as::<_, (String, String)>(query).fetch_all(pool).await
+}
+
+/// Points the shared HTTP client at the configured proxy and sets the retry count
+pub fn apply_network_settings(settings: &Settings) -> Result<(), String> {
+	http::configure(settings.proxy.as_deref(), settings.max_retries)
+}
+
+/// Returns the settings the app is running with, `data.settings` is the only source of truth
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn get_settings(data: DataState<'_>) -> Result<Settings, String> {
-	let data = data.0.lock().await;
-	Ok(Settings::load(&data.paths.settings_file))
+	Ok(data.0.settings.read().await.clone())
 }
 
+/// Validates, applies and saves all settings, then lets the subsystems that own the changed fields react
+/// and emits `settingsChanged` with the new settings.
+#[cfg(feature = "gui")]
 #[command]
 #[specta::specta]
 pub async fn apply_and_save_settings(new_settings: Settings, data: DataState<'_>) -> Result<(), String> {
-	let mut data = data.0.lock().await;
-	if data.settings.code_theme != new_settings.code_theme {
-		data.settings.code_theme = new_settings.code_theme;
-		let fetch_code_message_blocks_query =
-			"SELECT id, type_, language, raw_content, rendered_content, copied FROM message_blocks WHERE type_ = 'code'".to_string();
-		let code_message_blocks_result = sqlx::query_as::<_, MessageBlock>(&fetch_code_message_blocks_query)
-			.fetch_all(&data.db_pool)
-			.await;
-		match code_message_blocks_result {
-			Ok(mut code_message_blocks) => {
-				code_message_blocks = code_message_blocks
-					.par_iter_mut()
-					.map(|block| {
-						block.rendered_content =
-							highlight_code(&block.raw_content, &block.language.as_deref().unwrap_or("plain"), &data.settings.code_theme).unwrap();
-						block.to_owned()
-					})
-					.collect();
-				let update_code_message_blocks_query = "UPDATE message_blocks SET rendered_content = $1 WHERE id = $2".to_string();
-				for block in code_message_blocks {
-					let _update_code_message_blocks_result = sqlx::query(&update_code_message_blocks_query)
-						.bind(&block.rendered_content)
-						.bind(&block.id)
-						.execute(&data.db_pool)
-						.await;
-				}
-			}
-			Err(err) => {
-				eprintln!("Error fetching code message blocks from database: {}", err);
-			}
+	let mut new_settings = new_settings;
+	new_settings.version = SETTINGS_VERSION;
+	new_settings.proxy = new_settings.proxy.map(|proxy| proxy.trim().to_string()).filter(|proxy| !proxy.is_empty());
+	new_settings.max_retries = new_settings.max_retries.min(http::MAX_RETRIES);
+	for code_theme in std::iter::once(&new_settings.code_theme).chain(new_settings.code_theme_light.iter()) {
+		if !theme_exists(code_theme) {
+			throw!("Unknown code theme: {}", code_theme);
+		}
+	}
+	http::build_client(new_settings.proxy.as_deref())?;
+	// The order matters, the first folder is where the shell tool runs by default
+	let mut tool_folders: Vec<String> = vec![];
+	for folder in new_settings.tool_folders.iter().map(|folder| folder.trim()).filter(|folder| !folder.is_empty()) {
+		if !PathBuf::from(folder).is_dir() {
+			throw!("{} is not a folder", folder);
+		}
+		if !tool_folders.iter().any(|known_folder| known_folder == folder) {
+			tool_folders.push(folder.to_string());
 		}
 	}
+	new_settings.tool_folders = tool_folders;
+	new_settings.mcp_servers = validate_servers(new_settings.mcp_servers)?;
+	// An unchanged default model may have been hidden or deprecated since, which must not block saving other settings
+	let model_changed = {
+		let settings = data.0.settings.read().await;
+		new_settings.default_provider != settings.default_provider || new_settings.default_model != settings.default_model
+	};
+	if model_changed {
+		let models = match default_model_candidates(&data.0.db_pool).await {
+			Ok(models) => models,
+			Err(e) => throw!("Error validating the default model: {}", e),
+		};
+		if !models
+			.iter()
+			.any(|(provider_name, model_name)| provider_name == &new_settings.default_provider && model_name == &new_settings.default_model)
+		{
+			throw!("Unknown model {} of provider {}", new_settings.default_model, new_settings.default_provider);
+		}
+	}
+
+	let mut settings = data.0.settings.write().await;
+	store_env_secrets(&mut new_settings.mcp_servers, &settings.mcp_servers, &data.0.secrets, &data.0.db_pool).await?;
+	if let Err(e) = new_settings.save(&data.0.paths.settings_file) {
+		remove_unused_env_secrets(&new_settings.mcp_servers, &settings.mcp_servers, &data.0.secrets, &data.0.db_pool).await;
+		return Err(e);
+	}
+	let dark_mode = data.0.dark_mode.load(Ordering::Relaxed);
+	let previous_settings = std::mem::replace(&mut *settings, new_settings.clone());
+	let theme_changed = settings.code_theme_for(dark_mode) != previous_settings.code_theme_for(dark_mode);
+	drop(settings);
+	remove_unused_env_secrets(&previous_settings.mcp_servers, &new_settings.mcp_servers, &data.0.secrets, &data.0.db_pool).await;
 
-	data.settings.save(&data.paths.settings_file);
+	if new_settings.proxy != previous_settings.proxy || new_settings.max_retries != previous_settings.max_retries {
+		apply_network_settings(&new_settings)?;
+	}
+	if new_settings.tool_folders != previous_settings.tool_folders {
+		data.0.tool_folders.set(&new_settings.tool_folders);
+	}
+	if new_settings.mcp_servers != previous_settings.mcp_servers {
+		data.0.mcp.apply_in_background(new_settings.mcp_servers.clone());
+	}
+	// Re-highlighting the whole history can take a while, so it runs in the background
+	if theme_changed {
+		enqueue(&data.0, JobKind::Rehighlight, None, Value::Null).await?;
+	}
+	// The default model is only used by the frontend, which picks it up from the event
+	data.0.events.emit("settingsChanged", &new_settings);
 	Ok(())
 }
+
+#[derive(Serialize, Clone, Debug)]
+struct RehighlightProgress {
+	done: usize,
+	total: usize,
+}
+
+/// Runs `JobKind::Rehighlight` jobs: re-highlights all stored code blocks with the current code theme, in batches.
+/// Emits `codeThemeProgress` after each batch and `codeThemeApplied` when done.
+/// Stops early if the theme is changed again, since a newer job will take over.
+pub async fn rehighlight_code_blocks(data: Arc<Data>) -> Result<(), String> {
+	let db_pool = &data.db_pool;
+	let code_theme = data.code_theme().await;
+	let fetch_code_message_blocks_query = "SELECT id, type_, language, raw_content, rendered_content, copied FROM message_blocks WHERE type_ = 'code'";
+	let code_message_blocks = match sqlx::query_as::<_, MessageBlock>(fetch_code_message_blocks_query).fetch_all(db_pool).await {
+		Ok(code_message_blocks) => code_message_blocks,
+		Err(err) => throw!("Error fetching code message blocks from database: {}", err),
+	};
+
+	let total = code_message_blocks.len();
+	let mut done = 0;
+	for batch in code_message_blocks.chunks(REHIGHLIGHT_BATCH_SIZE) {
+		if data.code_theme().await != code_theme {
+			log::info!("Code theme changed again, stopping re-highlighting with {}", code_theme);
+			return Ok(());
+		}
+		let batch_len = batch.len();
+		let batch = batch.to_vec();
+		let batch_theme = code_theme.clone();
+		let highlighted = tokio::task::spawn_blocking(move || {
+			batch
+				.into_par_iter()
+				.filter_map(|block| {
+					match highlight_code(&block.raw_content, block.language.as_deref().unwrap_or("plain"), &batch_theme) {
+						Ok(rendered_content) => Some((block.id, rendered_content)),
+						Err(err) => {
+							log::warn!("Could not re-highlight code block {:?}: {}", block.id, err);
+							None
+						}
+					}
+				})
+				.collect::<Vec<_>>()
+		})
+		.await;
+		let highlighted = match highlighted {
+			Ok(highlighted) => highlighted,
+			Err(err) => throw!("Re-highlighting code blocks failed: {}", err),
+		};
+
+		if let Err(err) = update_rendered_content(&highlighted, db_pool).await {
+			throw!("Error updating code message blocks: {}", err);
+		}
+		done += batch_len;
+		data.events.emit("codeThemeProgress", RehighlightProgress { done, total });
+	}
+	data.events.emit("codeThemeApplied", &code_theme);
+	Ok(())
+}
+
+async fn update_rendered_content(blocks: &[(Option<i32>, String)], db_pool: &SqlitePool) -> Result<(), sqlx::Error> {
+	let mut transaction = db_pool.begin().await?;
+	for (id, rendered_content) in blocks {
+		sqlx::query("UPDATE message_blocks SET rendered_content = $1 WHERE id = $2")
+			.bind(rendered_content)
+			.bind(id)
+			.execute(&mut *transaction)
+			.await?;
+	}
+	transaction.commit().await
+}
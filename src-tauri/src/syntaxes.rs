@@ -0,0 +1,93 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use syntect::parsing::{SyntaxDefinition, SyntaxSet};
+#[cfg(feature = "gui")]
+use tauri::command;
+
+use crate::throw;
+
+const SYNTAX_EXTENSION: &str = "sublime-syntax";
+
+lazy_static! {
+	/// syntect's defaults lack many common languages (TOML, TypeScript, Svelte, Dockerfile, ...),
+	/// so this starts with the extended set that bat uses. User syntaxes are added on startup.
+	static ref SYNTAX_SET: RwLock<Arc<SyntaxSet>> = RwLock::new(Arc::new(two_face::syntax::extra_newlines()));
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Type)]
+pub struct Language {
+	pub name: String,
+	pub extensions: Vec<String>,
+}
+
+/// The shared syntax set. Loading it takes a while, so it is loaded once.
+pub fn syntax_set() -> Arc<SyntaxSet> {
+	SYNTAX_SET.read().unwrap().clone()
+}
+
+/// Adds the `.sublime-syntax` files in the syntaxes directory to the shared syntax set.
+/// Files that can't be parsed are skipped.
+pub fn load_user_syntaxes(syntaxes_dir: &Path) {
+	if let Err(e) = std::fs::create_dir_all(syntaxes_dir) {
+		log::error!("Could not create syntaxes directory {:?}: {}", syntaxes_dir, e);
+		return;
+	}
+	let entries = match std::fs::read_dir(syntaxes_dir) {
+		Ok(entries) => entries,
+		Err(e) => {
+			log::error!("Could not read syntaxes directory {:?}: {}", syntaxes_dir, e);
+			return;
+		}
+	};
+	let mut user_syntaxes: Vec<SyntaxDefinition> = vec![];
+	for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+		if path.extension().map_or(true, |extension| extension != SYNTAX_EXTENSION) {
+			continue;
+		}
+		match load_syntax_file(&path) {
+			Ok(syntax) => user_syntaxes.push(syntax),
+			Err(e) => log::warn!("{}", e),
+		}
+	}
+	if user_syntaxes.is_empty() {
+		return;
+	}
+
+	let mut builder = (*syntax_set()).clone().into_builder();
+	for syntax in user_syntaxes {
+		log::info!("Loaded syntax {}", syntax.name);
+		builder.add(syntax);
+	}
+	*SYNTAX_SET.write().unwrap() = Arc::new(builder.build());
+}
+
+fn load_syntax_file(path: &Path) -> Result<SyntaxDefinition, String> {
+	let source = match std::fs::read_to_string(path) {
+		Ok(source) => source,
+		Err(e) => throw!("Could not read syntax {:?}: {}", path, e),
+	};
+	let fallback_name = path.file_stem().map(|name| name.to_string_lossy().to_string());
+	SyntaxDefinition::load_from_str(&source, true, fallback_name.as_deref()).map_err(|e| format!("Could not load syntax {:?}: {}", path, e))
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn list_languages() -> Result<Vec<Language>, String> {
+	let syntax_set = syntax_set();
+	let mut languages: Vec<Language> = syntax_set
+		.syntaxes()
+		.iter()
+		.filter(|syntax| !syntax.hidden)
+		.map(|syntax| Language {
+			name: syntax.name.clone(),
+			extensions: syntax.file_extensions.clone(),
+		})
+		.collect();
+	languages.sort_by_key(|language| language.name.to_lowercase());
+	Ok(languages)
+}
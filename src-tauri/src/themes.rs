@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use syntect::highlighting::{Theme, ThemeSet};
+#[cfg(feature = "gui")]
+use tauri::command;
+
+#[cfg(feature = "gui")]
+use crate::data::DataState;
+use crate::throw;
+
+pub const DEFAULT_CODE_THEME: &str = "base16-eighties.dark";
+const THEME_EXTENSION: &str = "tmTheme";
+
+lazy_static! {
+	/// The built-in themes plus the ones imported into the themes directory
+	static ref THEME_SET: RwLock<ThemeSet> = RwLock::new(ThemeSet::load_defaults());
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Type)]
+pub struct CodeTheme {
+	pub name: String,
+	pub dark: bool,
+	pub custom: bool,
+}
+
+/// Loads the `.tmTheme` files in the themes directory. Themes that can't be parsed are skipped.
+pub fn load_user_themes(themes_dir: &Path) {
+	if let Err(e) = std::fs::create_dir_all(themes_dir) {
+		log::error!("Could not create themes directory {:?}: {}", themes_dir, e);
+		return;
+	}
+	let entries = match std::fs::read_dir(themes_dir) {
+		Ok(entries) => entries,
+		Err(e) => {
+			log::error!("Could not read themes directory {:?}: {}", themes_dir, e);
+			return;
+		}
+	};
+	let mut theme_set = THEME_SET.write().unwrap();
+	for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+		if path.extension().map_or(true, |extension| extension != THEME_EXTENSION) {
+			continue;
+		}
+		match load_theme_file(&path) {
+			Ok((name, theme)) => {
+				theme_set.themes.insert(name, theme);
+			}
+			Err(e) => log::warn!("{}", e),
+		}
+	}
+}
+
+fn load_theme_file(path: &Path) -> Result<(String, Theme), String> {
+	let name = match path.file_stem() {
+		Some(name) => name.to_string_lossy().to_string(),
+		None => throw!("Invalid theme file name: {:?}", path),
+	};
+	let theme = ThemeSet::get_theme(path).map_err(|e| format!("Could not load theme {:?}: {}", path, e))?;
+	Ok((name, theme))
+}
+
+pub fn theme_exists(name: &str) -> bool {
+	THEME_SET.read().unwrap().themes.contains_key(name)
+}
+
+/// Runs `f` with the named theme, falling back to the default theme if it doesn't exist
+pub fn with_theme<T>(name: &str, f: impl FnOnce(&Theme) -> T) -> T {
+	let theme_set = THEME_SET.read().unwrap();
+	match theme_set.themes.get(name) {
+		Some(theme) => f(theme),
+		None => {
+			log::warn!("Unknown code theme {}, using {}", name, DEFAULT_CODE_THEME);
+			f(&theme_set.themes[DEFAULT_CODE_THEME])
+		}
+	}
+}
+
+/// A theme is dark if its background is. Themes without a background get the dark default background.
+#[cfg(feature = "gui")]
+fn is_dark(theme: &Theme) -> bool {
+	match theme.settings.background {
+		Some(bg) => 0.2126 * bg.r as f32 + 0.7152 * bg.g as f32 + 0.0722 * (bg.b as f32) < 128.0,
+		None => true,
+	}
+}
+
+#[cfg(feature = "gui")]
+fn is_custom(name: &str, themes_dir: &Path) -> bool {
+	themes_dir.join(format!("{}.{}", name, THEME_EXTENSION)).exists()
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn list_code_themes(data: DataState<'_>) -> Result<Vec<CodeTheme>, String> {
+	let themes_dir = data.0.paths.themes_dir.clone();
+	let theme_set = THEME_SET.read().unwrap();
+	let mut themes: Vec<CodeTheme> = theme_set
+		.themes
+		.iter()
+		.map(|(name, theme)| CodeTheme {
+			name: name.clone(),
+			dark: is_dark(theme),
+			custom: is_custom(name, &themes_dir),
+		})
+		.collect();
+	themes.sort_by_key(|theme| theme.name.to_lowercase());
+	Ok(themes)
+}
+
+/// Copies a `.tmTheme` file into the themes directory and makes it available. The file name becomes the theme name.
+/// Importing a file with the name of an imported theme replaces it, built-in themes can't be replaced.
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn import_code_theme(path: String, data: DataState<'_>) -> Result<CodeTheme, String> {
+	let themes_dir = data.0.paths.themes_dir.clone();
+	let (name, theme) = load_theme_file(Path::new(&path))?;
+	if theme_exists(&name) && !is_custom(&name, &themes_dir) {
+		throw!("There already is a built-in theme called {}", name);
+	}
+	if let Err(e) = std::fs::create_dir_all(&themes_dir) {
+		throw!("Could not create themes directory: {}", e);
+	}
+	if let Err(e) = std::fs::copy(&path, themes_dir.join(format!("{}.{}", name, THEME_EXTENSION))) {
+		throw!("Could not import theme {}: {}", name, e);
+	}
+	let code_theme = CodeTheme {
+		name: name.clone(),
+		dark: is_dark(&theme),
+		custom: true,
+	};
+	THEME_SET.write().unwrap().themes.insert(name, theme);
+	Ok(code_theme)
+}
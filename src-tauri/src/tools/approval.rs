@@ -0,0 +1,91 @@
+//! Side-effecting tools only run after the user approved the call. The app asks the UI with an event
+//! and waits for `answer_tool_approval`, the CLI asks on the terminal.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::sync::oneshot;
+
+use crate::events::EventSink;
+use crate::throw;
+
+/// Unanswered requests are declined after this long, so a generation doesn't wait forever for a closed window
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+pub struct ToolApprovalRequest {
+	pub id: String,
+	pub chat_id: String,
+	pub tool_name: String,
+	/// The arguments as the model wrote them, e.g. the shell command
+	pub arguments: String,
+}
+
+#[async_trait]
+pub trait ToolApprover: Send + Sync {
+	/// Whether the call may run
+	async fn approve(&self, request: &ToolApprovalRequest) -> bool;
+}
+
+/// Declines every call, for when there is nobody to ask
+pub struct DenyAll;
+
+#[async_trait]
+impl ToolApprover for DenyAll {
+	async fn approve(&self, _request: &ToolApprovalRequest) -> bool {
+		false
+	}
+}
+
+/// Asks the UI with a `toolApprovalRequested` event and waits for `answer`.
+/// Emits `toolApprovalExpired` with the id when nobody answered in time.
+pub struct EventApprover {
+	events: Arc<dyn EventSink>,
+	pending: Mutex<HashMap<String, (ToolApprovalRequest, oneshot::Sender<bool>)>>,
+}
+
+impl EventApprover {
+	pub fn new(events: Arc<dyn EventSink>) -> Self {
+		EventApprover {
+			events,
+			pending: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// The requests waiting for an answer, e.g. for a reloaded window
+	pub fn pending(&self) -> Vec<ToolApprovalRequest> {
+		self.pending.lock().unwrap().values().map(|(request, _)| request.clone()).collect()
+	}
+
+	pub fn answer(&self, request_id: &str, approved: bool) -> Result<(), String> {
+		match self.pending.lock().unwrap().remove(request_id) {
+			// The generation may have stopped waiting in the meantime
+			Some((_, sender)) => {
+				let _ = sender.send(approved);
+				Ok(())
+			}
+			None => throw!("The tool call {} is not waiting for approval anymore", request_id),
+		}
+	}
+}
+
+#[async_trait]
+impl ToolApprover for EventApprover {
+	async fn approve(&self, request: &ToolApprovalRequest) -> bool {
+		let (sender, receiver) = oneshot::channel();
+		self.pending.lock().unwrap().insert(request.id.clone(), (request.clone(), sender));
+		self.events.emit("toolApprovalRequested", request);
+		match tokio::time::timeout(APPROVAL_TIMEOUT, receiver).await {
+			Ok(Ok(approved)) => approved,
+			_ => {
+				self.pending.lock().unwrap().remove(&request.id);
+				self.events.emit("toolApprovalExpired", &request.id);
+				false
+			}
+		}
+	}
+}
@@ -0,0 +1,255 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::throw;
+use crate::tools::Tool;
+
+/// Expressions come from the models, which may pass on text from files or MCP servers.
+/// Longer or deeper nested ones are refused, so the recursive parser can't overflow the stack.
+const MAX_EXPRESSION_LENGTH: usize = 1000;
+const MAX_NESTING_DEPTH: usize = 256;
+
+/// Evaluates arithmetic, since models are unreliable at it
+pub struct CalculatorTool;
+
+#[async_trait]
+impl Tool for CalculatorTool {
+	fn name(&self) -> &str {
+		"calculator"
+	}
+
+	fn description(&self) -> &str {
+		"Evaluates an arithmetic expression. Supports + - * / % ^, parentheses, the constants pi and e \
+		and the functions sqrt, abs, ln, log (base 10), exp, sin, cos, tan (radians), floor, ceil and round."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"expression": { "type": "string", "description": "The expression, e.g. (2 + 3) * sqrt(16)" }
+			},
+			"required": ["expression"]
+		})
+	}
+
+	async fn call(&self, arguments: Value) -> Result<String, String> {
+		match arguments["expression"].as_str() {
+			Some(expression) => Ok(format_number(evaluate(expression)?)),
+			None => throw!("expression must be a string"),
+		}
+	}
+}
+
+/// Whole numbers without a fraction, so `2 + 3` is `5` and not `5.0`
+fn format_number(number: f64) -> String {
+	if number.fract() == 0.0 && number.abs() < 1e15 {
+		format!("{}", number as i64)
+	} else {
+		number.to_string()
+	}
+}
+
+pub fn evaluate(expression: &str) -> Result<f64, String> {
+	if expression.len() > MAX_EXPRESSION_LENGTH {
+		throw!("The expression is too long, at most {} characters are supported", MAX_EXPRESSION_LENGTH);
+	}
+	let tokens = tokenize(expression)?;
+	let mut parser = Parser { tokens, position: 0, depth: 0 };
+	let result = parser.expression()?;
+	if let Some(token) = parser.tokens.get(parser.position) {
+		throw!("Unexpected {:?} in {}", token, expression);
+	}
+	if !result.is_finite() {
+		throw!("{} is not a finite number", expression);
+	}
+	Ok(result)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Number(f64),
+	Name(String),
+	Operator(char),
+	Open,
+	Close,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+	let mut tokens = vec![];
+	let mut chars = expression.chars().peekable();
+	while let Some(&c) = chars.peek() {
+		match c {
+			c if c.is_whitespace() => {
+				chars.next();
+			}
+			'0'..='9' | '.' => {
+				let mut number = String::new();
+				while let Some(&c) = chars.peek() {
+					// Exponents like 1e-3, but not the constant e after a number
+					let exponent_sign = (c == '-' || c == '+') && number.ends_with(['e', 'E']);
+					if !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign) {
+						break;
+					}
+					number.push(c);
+					chars.next();
+				}
+				match number.parse() {
+					Ok(number) => tokens.push(Token::Number(number)),
+					Err(_) => throw!("Invalid number {}", number),
+				}
+			}
+			c if c.is_alphabetic() => {
+				let mut name = String::new();
+				while let Some(&c) = chars.peek() {
+					if !c.is_alphanumeric() {
+						break;
+					}
+					name.push(c);
+					chars.next();
+				}
+				tokens.push(Token::Name(name.to_lowercase()));
+			}
+			'+' | '-' | '*' | '/' | '%' | '^' => {
+				tokens.push(Token::Operator(c));
+				chars.next();
+			}
+			'(' => {
+				tokens.push(Token::Open);
+				chars.next();
+			}
+			')' => {
+				tokens.push(Token::Close);
+				chars.next();
+			}
+			c => throw!("Unexpected character {}", c),
+		}
+	}
+	Ok(tokens)
+}
+
+/// Recursive descent: expression = term (+|- term)*, term = factor (*|/|% factor)*,
+/// factor = -factor | power, power = atom (^ factor)?
+struct Parser {
+	tokens: Vec<Token>,
+	position: usize,
+	/// How many `factor`s are being parsed, every nesting (parentheses, signs, powers, function arguments) goes through one
+	depth: usize,
+}
+
+impl Parser {
+	fn next(&mut self) -> Option<Token> {
+		let token = self.tokens.get(self.position).cloned();
+		self.position += 1;
+		token
+	}
+
+	fn peek_operator(&self, operators: &[char]) -> Option<char> {
+		match self.tokens.get(self.position) {
+			Some(Token::Operator(operator)) if operators.contains(operator) => Some(*operator),
+			_ => None,
+		}
+	}
+
+	fn expression(&mut self) -> Result<f64, String> {
+		let mut value = self.term()?;
+		while let Some(operator) = self.peek_operator(&['+', '-']) {
+			self.position += 1;
+			let right = self.term()?;
+			value = if operator == '+' { value + right } else { value - right };
+		}
+		Ok(value)
+	}
+
+	fn term(&mut self) -> Result<f64, String> {
+		let mut value = self.factor()?;
+		while let Some(operator) = self.peek_operator(&['*', '/', '%']) {
+			self.position += 1;
+			let right = self.factor()?;
+			value = match operator {
+				'*' => value * right,
+				_ if right == 0.0 => throw!("Division by zero"),
+				'/' => value / right,
+				_ => value % right,
+			};
+		}
+		Ok(value)
+	}
+
+	fn factor(&mut self) -> Result<f64, String> {
+		self.depth += 1;
+		if self.depth > MAX_NESTING_DEPTH {
+			throw!("The expression is nested too deeply, at most {} levels are supported", MAX_NESTING_DEPTH);
+		}
+		let value = match self.peek_operator(&['-', '+']) {
+			Some(operator) => {
+				self.position += 1;
+				let value = self.factor()?;
+				if operator == '-' {
+					-value
+				} else {
+					value
+				}
+			}
+			None => self.power()?,
+		};
+		self.depth -= 1;
+		Ok(value)
+	}
+
+	fn power(&mut self) -> Result<f64, String> {
+		let base = self.atom()?;
+		match self.peek_operator(&['^']) {
+			// Right-associative, and -2^2 is -(2^2)
+			Some(_) => {
+				self.position += 1;
+				Ok(base.powf(self.factor()?))
+			}
+			None => Ok(base),
+		}
+	}
+
+	fn atom(&mut self) -> Result<f64, String> {
+		match self.next() {
+			Some(Token::Number(number)) => Ok(number),
+			Some(Token::Open) => {
+				let value = self.expression()?;
+				match self.next() {
+					Some(Token::Close) => Ok(value),
+					_ => throw!("Missing )"),
+				}
+			}
+			Some(Token::Name(name)) => match name.as_str() {
+				"pi" => Ok(std::f64::consts::PI),
+				"e" => Ok(std::f64::consts::E),
+				_ => {
+					let function: fn(f64) -> f64 = match name.as_str() {
+						"sqrt" => f64::sqrt,
+						"abs" => f64::abs,
+						"ln" => f64::ln,
+						"log" => f64::log10,
+						"exp" => f64::exp,
+						"sin" => f64::sin,
+						"cos" => f64::cos,
+						"tan" => f64::tan,
+						"floor" => f64::floor,
+						"ceil" => f64::ceil,
+						"round" => f64::round,
+						_ => throw!("Unknown function or constant {}", name),
+					};
+					match self.next() {
+						Some(Token::Open) => (),
+						_ => throw!("Missing ( after {}", name),
+					}
+					let argument = self.expression()?;
+					match self.next() {
+						Some(Token::Close) => Ok(function(argument)),
+						_ => throw!("Missing ) after the argument of {}", name),
+					}
+				}
+			},
+			Some(token) => throw!("Unexpected {:?}", token),
+			None => throw!("The expression ended early"),
+		}
+	}
+}
@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+use chrono::{Local, Utc};
+use serde_json::{json, Value};
+
+use crate::tools::Tool;
+
+/// Models don't know the current date on their own
+pub struct CurrentTimeTool;
+
+#[async_trait]
+impl Tool for CurrentTimeTool {
+	fn name(&self) -> &str {
+		"current_time"
+	}
+
+	fn description(&self) -> &str {
+		"Returns the current date and time in the user's time zone and in UTC"
+	}
+
+	fn parameters(&self) -> Value {
+		json!({ "type": "object", "properties": {} })
+	}
+
+	async fn call(&self, _arguments: Value) -> Result<String, String> {
+		let now = Local::now();
+		Ok(format!(
+			"Local: {} ({})\nUTC: {}",
+			now.format("%Y-%m-%d %H:%M:%S %:z"),
+			now.format("%A"),
+			Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+		))
+	}
+}
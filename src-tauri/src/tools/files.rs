@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+
+use crate::throw;
+use crate::tools::Tool;
+
+/// Larger files are cut off, they would not fit the context window anyway
+const MAX_FILE_BYTES: usize = 200_000;
+const MAX_DIRECTORY_ENTRIES: usize = 500;
+
+/// The folders the user allowed the file and shell tools to access (`Settings::tool_folders`).
+/// Shared between the tools and the settings, which update it when the user changes the folders.
+#[derive(Clone, Default)]
+pub struct ApprovedFolders(Arc<RwLock<Vec<PathBuf>>>);
+
+impl ApprovedFolders {
+	pub fn new(folders: &[String]) -> Self {
+		let approved_folders = ApprovedFolders::default();
+		approved_folders.set(folders);
+		approved_folders
+	}
+
+	/// Folders that don't exist are left out, they can't contain anything to read
+	pub fn set(&self, folders: &[String]) {
+		let folders = folders
+			.iter()
+			.filter_map(|folder| match Path::new(folder).canonicalize() {
+				Ok(folder) => Some(folder),
+				Err(e) => {
+					log::warn!("Approved folder {} is not accessible: {}", folder, e);
+					None
+				}
+			})
+			.collect();
+		*self.0.write().unwrap() = folders;
+	}
+
+	pub fn list(&self) -> Vec<PathBuf> {
+		self.0.read().unwrap().clone()
+	}
+
+	/// Resolves `path` to an existing path inside an approved folder. Relative paths are relative to the approved folders,
+	/// symlinks and `..` are resolved before checking, so they can't lead outside.
+	pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+		let folders = self.list();
+		if folders.is_empty() {
+			throw!("No folders are approved for tools, the user can add them in the settings");
+		}
+		let candidates: Vec<PathBuf> = match Path::new(path).is_absolute() {
+			true => vec![PathBuf::from(path)],
+			false => folders.iter().map(|folder| folder.join(path)).collect(),
+		};
+		let resolved = match candidates.iter().find_map(|candidate| candidate.canonicalize().ok()) {
+			Some(resolved) => resolved,
+			None => throw!("{} does not exist", path),
+		};
+		match folders.iter().any(|folder| resolved.starts_with(folder)) {
+			true => Ok(resolved),
+			false => throw!("{} is outside of the approved folders", path),
+		}
+	}
+
+	/// The first approved folder, where relative paths start
+	pub fn default_folder(&self) -> Result<PathBuf, String> {
+		match self.list().into_iter().next() {
+			Some(folder) => Ok(folder),
+			None => throw!("No folders are approved for tools, the user can add them in the settings"),
+		}
+	}
+}
+
+pub struct ReadFileTool {
+	pub folders: ApprovedFolders,
+}
+
+#[async_trait]
+impl Tool for ReadFileTool {
+	fn name(&self) -> &str {
+		"read_file"
+	}
+
+	fn description(&self) -> &str {
+		"Reads a text file in one of the folders the user approved. Use list_directory to find files."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"path": { "type": "string", "description": "Absolute path, or relative to an approved folder" }
+			},
+			"required": ["path"]
+		})
+	}
+
+	async fn call(&self, arguments: Value) -> Result<String, String> {
+		let path = match arguments["path"].as_str() {
+			Some(path) => self.folders.resolve(path)?,
+			None => throw!("path must be a string"),
+		};
+		let file = match tokio::fs::File::open(&path).await {
+			Ok(file) => file,
+			Err(e) => throw!("Could not read {}: {}", path.display(), e),
+		};
+		// One byte more than is kept tells whether the file is longer, without reading all of a huge file
+		let mut content = vec![];
+		if let Err(e) = file.take(MAX_FILE_BYTES as u64 + 1).read_to_end(&mut content).await {
+			throw!("Could not read {}: {}", path.display(), e);
+		}
+		let truncated = content.len() > MAX_FILE_BYTES;
+		let content = &content[..content.len().min(MAX_FILE_BYTES)];
+		let mut text = match std::str::from_utf8(content) {
+			Ok(text) => text.to_string(),
+			// Cutting may have split the last character
+			Err(e) if truncated && e.error_len().is_none() => String::from_utf8_lossy(&content[..e.valid_up_to()]).to_string(),
+			Err(_) => throw!("{} is not a text file", path.display()),
+		};
+		if truncated {
+			text.push_str(&format!("\n[Cut off after {} bytes]", MAX_FILE_BYTES));
+		}
+		Ok(text)
+	}
+}
+
+pub struct ListDirectoryTool {
+	pub folders: ApprovedFolders,
+}
+
+#[async_trait]
+impl Tool for ListDirectoryTool {
+	fn name(&self) -> &str {
+		"list_directory"
+	}
+
+	fn description(&self) -> &str {
+		"Lists the files and folders in a folder the user approved. Without a path, lists the approved folders."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"path": { "type": "string", "description": "Absolute path, or relative to an approved folder" }
+			}
+		})
+	}
+
+	async fn call(&self, arguments: Value) -> Result<String, String> {
+		let path = match arguments["path"].as_str().filter(|path| !path.is_empty()) {
+			Some(path) => self.folders.resolve(path)?,
+			None => {
+				let folders = self.folders.list();
+				if folders.is_empty() {
+					throw!("No folders are approved for tools, the user can add them in the settings");
+				}
+				return Ok(folders.iter().map(|folder| format!("{}/", folder.display())).collect::<Vec<_>>().join("\n"));
+			}
+		};
+		let mut reader = match tokio::fs::read_dir(&path).await {
+			Ok(reader) => reader,
+			Err(e) => throw!("Could not list {}: {}", path.display(), e),
+		};
+		let mut entries = vec![];
+		while let Ok(Some(entry)) = reader.next_entry().await {
+			let name = entry.file_name().to_string_lossy().to_string();
+			entries.push(match entry.metadata().await {
+				Ok(metadata) if metadata.is_dir() => format!("{}/", name),
+				Ok(metadata) => format!("{} ({} bytes)", name, metadata.len()),
+				Err(_) => name,
+			});
+		}
+		entries.sort();
+		let total = entries.len();
+		entries.truncate(MAX_DIRECTORY_ENTRIES);
+		if total > MAX_DIRECTORY_ENTRIES {
+			entries.push(format!("[{} more entries]", total - MAX_DIRECTORY_ENTRIES));
+		}
+		match entries.is_empty() {
+			true => Ok(format!("{} is empty", path.display())),
+			false => Ok(entries.join("\n")),
+		}
+	}
+}
@@ -0,0 +1,179 @@
+//! Tools the models can call. A tool describes its arguments with a JSON schema and runs in the backend,
+//! `ChatService::complete_with_tools` sends the results back to the model until it answers.
+//! Chats only get the tools the user turned on for them, side-effecting tools also need the user's approval for every call.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use specta::Type;
+#[cfg(feature = "gui")]
+use tauri::command;
+
+#[cfg(feature = "gui")]
+use crate::data::DataState;
+use crate::llm_providers::{ToolCall, ToolSpec};
+
+pub mod approval;
+pub mod calculator;
+pub mod clock;
+pub mod files;
+pub mod shell;
+
+#[cfg(feature = "gui")]
+use approval::ToolApprovalRequest;
+use calculator::CalculatorTool;
+use clock::CurrentTimeTool;
+use files::{ApprovedFolders, ListDirectoryTool, ReadFileTool};
+use shell::ShellTool;
+
+/// How often a model may call tools before answering, so a model that keeps calling them can't loop forever
+pub const MAX_TOOL_ITERATIONS: usize = 8;
+
+#[async_trait]
+pub trait Tool: Send + Sync {
+	/// Unique name the model calls the tool by, e.g. `read_file`
+	fn name(&self) -> &str;
+	/// Tells the model what the tool does and when to use it
+	fn description(&self) -> &str;
+	/// JSON schema of the arguments object
+	fn parameters(&self) -> Value;
+	/// Whether the tool changes anything, so the user has to approve each call
+	fn needs_approval(&self) -> bool {
+		false
+	}
+	/// Runs the tool. Errors are passed on to the model, which may try again.
+	async fn call(&self, arguments: Value) -> Result<String, String>;
+}
+
+/// The tools that come with the app. The file and shell tools are limited to `folders`.
+/// The shell is only offered where its commands can run in a sandbox.
+pub fn builtin_tools(folders: &ApprovedFolders) -> ToolRegistry {
+	let mut tools = ToolRegistry::default()
+		.with(CalculatorTool)
+		.with(CurrentTimeTool)
+		.with(ReadFileTool { folders: folders.clone() })
+		.with(ListDirectoryTool { folders: folders.clone() });
+	if shell::sandbox_available() {
+		tools.register(ShellTool { folders: folders.clone() });
+	}
+	tools
+}
+
+/// A tool and whether a chat may use it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Type)]
+pub struct ChatTool {
+	pub name: String,
+	pub description: String,
+	pub enabled: bool,
+	pub needs_approval: bool,
+}
+
+/// The tools offered to the models, by name
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+	tools: BTreeMap<String, Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+	/// Adds the tool, replacing one with the same name
+	pub fn register(&mut self, tool: impl Tool + 'static) {
+		self.tools.insert(tool.name().to_string(), Arc::new(tool));
+	}
+
+	pub fn with(mut self, tool: impl Tool + 'static) -> Self {
+		self.register(tool);
+		self
+	}
+
+	/// Adds the tools of `other`, replacing those with the same names
+	pub fn extend(&mut self, other: ToolRegistry) {
+		self.tools.extend(other.tools);
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.tools.is_empty()
+	}
+
+	pub fn names(&self) -> Vec<String> {
+		self.tools.keys().cloned().collect()
+	}
+
+	pub fn get(&self, name: &str) -> Option<Arc<dyn Tool>> {
+		self.tools.get(name).cloned()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Tool>> {
+		self.tools.values()
+	}
+
+	/// The tools for which `keep` returns true
+	pub fn filter(&self, keep: impl Fn(&dyn Tool) -> bool) -> ToolRegistry {
+		ToolRegistry {
+			tools: self
+				.tools
+				.iter()
+				.filter(|(_, tool)| keep(tool.as_ref()))
+				.map(|(name, tool)| (name.clone(), tool.clone()))
+				.collect(),
+		}
+	}
+
+	/// The schemas of all tools, ordered by name so requests stay the same between runs
+	pub fn specs(&self) -> Vec<ToolSpec> {
+		self.tools
+			.values()
+			.map(|tool| ToolSpec {
+				name: tool.name().to_string(),
+				description: tool.description().to_string(),
+				parameters: tool.parameters(),
+			})
+			.collect()
+	}
+
+	/// Runs the call. Models send `""` instead of `{}` for tools without arguments.
+	pub async fn call(&self, call: &ToolCall) -> Result<String, String> {
+		let tool = match self.tools.get(&call.name) {
+			Some(tool) => tool,
+			None => return Err(format!("Unknown tool {}, available tools are: {}", call.name, self.names().join(", "))),
+		};
+		let arguments = match call.arguments.trim() {
+			"" => json!({}),
+			arguments => serde_json::from_str(arguments).map_err(|e| format!("Arguments of {} are not valid JSON: {}", call.name, e))?,
+		};
+		tool.call(arguments).await
+	}
+}
+
+/// All tools with whether the chat may use them
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn get_chat_tools(chat_id: String, data: DataState<'_>) -> Result<Vec<ChatTool>, String> {
+	data.0.chat_service().chat_tool_settings(&chat_id).await
+}
+
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn set_chat_tool(chat_id: String, tool_name: String, enabled: bool, data: DataState<'_>) -> Result<(), String> {
+	data.0.chat_service().set_chat_tool(&chat_id, &tool_name, enabled).await
+}
+
+/// The tool calls waiting for the user's approval, e.g. after reloading the window
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn list_tool_approvals(data: DataState<'_>) -> Result<Vec<ToolApprovalRequest>, String> {
+	Ok(data.0.approvals.pending())
+}
+
+/// Answers a `toolApprovalRequested` event
+#[cfg(feature = "gui")]
+#[command]
+#[specta::specta]
+pub async fn answer_tool_approval(request_id: String, approved: bool, data: DataState<'_>) -> Result<(), String> {
+	data.0.approvals.answer(&request_id, approved)
+}
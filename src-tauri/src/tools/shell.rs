@@ -0,0 +1,174 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use tokio::process::Command;
+
+use crate::throw;
+use crate::tools::files::ApprovedFolders;
+use crate::tools::Tool;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_OUTPUT_CHARS: usize = 20_000;
+/// The only environment variables commands see, so API keys loaded from `.env` don't leak into them
+const PASSED_ENV_VARS: [&str; 5] = ["PATH", "HOME", "LANG", "USER", "TMPDIR"];
+const NO_SANDBOX: &str = "Shell commands can't run sandboxed on this machine, on Linux they need bubblewrap (bwrap)";
+#[cfg(target_os = "macos")]
+const SANDBOX_EXEC: &str = "/usr/bin/sandbox-exec";
+
+#[cfg(not(target_os = "macos"))]
+lazy_static! {
+	/// Bubblewrap, if it's installed and may create namespaces here (some containers and distributions don't allow it)
+	static ref BWRAP: Option<std::path::PathBuf> = find_bwrap();
+}
+
+/// Runs shell commands in an approved folder. Every call needs the user's approval.
+/// Commands get no stdin, a clean environment and a timeout, and run in a sandbox that only allows writing
+/// to the working directory and temporary files, and no network: `sandbox-exec` on macOS, bubblewrap on Linux.
+/// Where there is no sandbox (Windows, Linux without bubblewrap) the tool isn't offered, see `sandbox_available`.
+pub struct ShellTool {
+	pub folders: ApprovedFolders,
+}
+
+#[async_trait]
+impl Tool for ShellTool {
+	fn name(&self) -> &str {
+		"run_shell_command"
+	}
+
+	fn description(&self) -> &str {
+		"Runs a shell command in one of the folders the user approved and returns its output. \
+		The user has to approve every command. Commands can't read input and are stopped after 60 seconds."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"command": { "type": "string", "description": "The command line, run with sh -c" },
+				"working_dir": { "type": "string", "description": "Approved folder to run in, the first approved folder by default" }
+			},
+			"required": ["command"]
+		})
+	}
+
+	fn needs_approval(&self) -> bool {
+		true
+	}
+
+	async fn call(&self, arguments: Value) -> Result<String, String> {
+		if !sandbox_available() {
+			throw!("{}", NO_SANDBOX);
+		}
+		let command_line = match arguments["command"].as_str().filter(|command| !command.trim().is_empty()) {
+			Some(command_line) => command_line,
+			None => throw!("command must be a non-empty string"),
+		};
+		let working_dir = match arguments["working_dir"].as_str().filter(|dir| !dir.is_empty()) {
+			Some(dir) => self.folders.resolve(dir)?,
+			None => self.folders.default_folder()?,
+		};
+		if !working_dir.is_dir() {
+			throw!("{} is not a folder", working_dir.display());
+		}
+
+		let mut command = sandboxed_command(command_line, &working_dir)?;
+		command
+			.current_dir(&working_dir)
+			.env_clear()
+			.envs(PASSED_ENV_VARS.iter().filter_map(|name| Some((*name, std::env::var_os(name)?))))
+			.stdin(Stdio::null())
+			.kill_on_drop(true);
+		let output = match tokio::time::timeout(COMMAND_TIMEOUT, command.output()).await {
+			Ok(Ok(output)) => output,
+			Ok(Err(e)) => throw!("Could not run {}: {}", command_line, e),
+			Err(_) => throw!("{} did not finish within {} seconds and was stopped", command_line, COMMAND_TIMEOUT.as_secs()),
+		};
+
+		let mut result = match output.status.code() {
+			Some(code) => format!("Exit code: {}\n", code),
+			None => "Stopped by a signal\n".to_string(),
+		};
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		if !stdout.is_empty() {
+			result.push_str(&format!("Output:\n{}\n", stdout.trim_end()));
+		}
+		if !stderr.is_empty() {
+			result.push_str(&format!("Errors:\n{}\n", stderr.trim_end()));
+		}
+		if result.chars().count() > MAX_OUTPUT_CHARS {
+			result = result.chars().take(MAX_OUTPUT_CHARS).collect();
+			result.push_str(&format!("\n[Cut off after {} characters]", MAX_OUTPUT_CHARS));
+		}
+		Ok(result)
+	}
+}
+
+/// Whether shell commands can run sandboxed on this machine
+#[cfg(target_os = "macos")]
+pub fn sandbox_available() -> bool {
+	Path::new(SANDBOX_EXEC).exists()
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn sandbox_available() -> bool {
+	BWRAP.is_some()
+}
+
+#[cfg(target_os = "macos")]
+fn sandboxed_command(command_line: &str, working_dir: &Path) -> Result<Command, String> {
+	let quote = |path: &Path| path.to_string_lossy().replace('\\', "\\\\").replace('"', "\\\"");
+	let temp_dir = std::env::temp_dir().canonicalize().unwrap_or_else(|_| std::env::temp_dir());
+	let profile = format!(
+		"(version 1)(allow default)(deny network*)(deny file-write*)\
+		(allow file-write* (subpath \"{}\") (subpath \"{}\") (subpath \"/private/tmp\") (literal \"/dev/null\") (literal \"/dev/tty\"))",
+		quote(working_dir),
+		quote(&temp_dir)
+	);
+	let mut command = Command::new(SANDBOX_EXEC);
+	command.args(["-p", profile.as_str(), "/bin/sh", "-c", command_line]);
+	Ok(command)
+}
+
+/// Everything is read-only except the working directory and a private `/tmp`, and there is no network
+#[cfg(not(target_os = "macos"))]
+fn sandboxed_command(command_line: &str, working_dir: &Path) -> Result<Command, String> {
+	let bwrap = match BWRAP.as_ref() {
+		Some(bwrap) => bwrap,
+		None => throw!("{}", NO_SANDBOX),
+	};
+	let mut command = Command::new(bwrap);
+	command
+		.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+		.arg("--bind")
+		.arg(working_dir)
+		.arg(working_dir)
+		.args(["--unshare-all", "--die-with-parent", "--new-session", "--chdir"])
+		.arg(working_dir)
+		.args(["/bin/sh", "-c", command_line]);
+	Ok(command)
+}
+
+/// Looks for `bwrap` on the `PATH` and checks that it can set up a sandbox
+#[cfg(not(target_os = "macos"))]
+fn find_bwrap() -> Option<std::path::PathBuf> {
+	if !cfg!(target_os = "linux") {
+		return None;
+	}
+	let bwrap = std::env::split_paths(&std::env::var_os("PATH")?)
+		.map(|dir| dir.join("bwrap"))
+		.find(|path| path.is_file())?;
+	let works = std::process::Command::new(&bwrap)
+		.args(["--ro-bind", "/", "/", "--unshare-all", "--die-with-parent", "true"])
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.status()
+		.map(|status| status.success())
+		.unwrap_or(false);
+	works.then(|| bwrap)
+}
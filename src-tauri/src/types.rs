@@ -5,6 +5,8 @@ use serde::{Deserialize, Serialize};
 use specta::Type;
 use sqlx::FromRow;
 
+use crate::llm_providers::{KeyStatus, ToolCall};
+
 #[derive(Serialize, Deserialize, Debug, Type, Clone)]
 pub struct Message {
 	pub id: String,
@@ -12,6 +14,27 @@ pub struct Message {
 	pub content: String,
 	pub model_name: String,
 	pub blocks: Option<MessageBlocks>,
+	/// The tools an assistant message calls, answered by the following `tool` messages
+	pub tool_calls: Option<Vec<ToolCall>>,
+	/// The call a `tool` message is the result of
+	pub tool_call_id: Option<String>,
+	/// Files sent along with a user message, loaded for the models only. The app shows them as `attachment` blocks.
+	#[serde(skip)]
+	pub attachments: Vec<Attachment>,
+}
+
+/// A file attached to a user message, see `attachments::attach_files`
+#[derive(Debug, FromRow, Clone)]
+pub struct Attachment {
+	pub id: String,
+	pub file_name: String,
+	pub mime_type: String,
+	/// The bytes of images
+	pub data: Option<Vec<u8>>,
+	/// Where the file was attached from
+	pub path: Option<String>,
+	/// The content of text files, which is inlined into the message
+	pub extracted_text: Option<String>,
 }
 
 // impl fmt::Display for Message {
@@ -77,6 +100,7 @@ pub struct Model {
 	pub show: bool,
 	pub max_tokens: u32,
 	pub context_window: u32,
+	pub deprecated: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Type, FromRow, Clone, Deref)]
@@ -92,3 +116,19 @@ pub struct Chat {
 
 #[derive(Serialize, Deserialize, Type, Debug, Deref)]
 pub struct Chats(pub Vec<Chat>);
+
+/// A named API key of a provider. The key itself never leaves the backend, only its masked suffix.
+#[derive(Serialize, Deserialize, Type, Debug, FromRow, Clone)]
+pub struct ApiKey {
+	pub id: String,
+	pub provider_name: String,
+	pub label: String,
+	pub key_suffix: String,
+	pub api_key_valid: bool,
+	pub validation_status: KeyStatus,
+	pub last_validated_at: Option<String>,
+	pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Deref)]
+pub struct ApiKeys(pub Vec<ApiKey>);
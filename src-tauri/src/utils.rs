@@ -1,187 +1,318 @@
-use regex::Regex;
+use std::sync::Arc;
+
+use comrak::{markdown_to_html, Options as ComrakOptions};
+use lazy_static::lazy_static;
 use syntect::easy::HighlightLines;
-use syntect::highlighting::{Style, ThemeSet};
+use syntect::highlighting::Style;
 use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
-use syntect::parsing::SyntaxSet;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
 use syntect::util::LinesWithEndings;
 
+use crate::diagrams::{is_diagram_language, render_diagram};
+use crate::math::{normalize_inline_delimiters, render_math_spans, render_tex_or_source, split_display_math, MathSegment};
+use crate::syntaxes::syntax_set;
+use crate::themes::with_theme;
 use crate::types::{MessageBlock, MessageBlocks};
 
 pub async fn render_message(message: &str, code_theme: &str) -> MessageBlocks {
 	let mut message_blocks: MessageBlocks = MessageBlocks(vec![]);
-	let regex = Regex::new(r"```([a-zA-Z]*\n[\s\S]*?)```").unwrap();
-	let mut last_index = 0;
-
-	for cap in regex.captures_iter(message) {
-		let match_str = cap.get(0).unwrap().as_str();
-		let code_with_lang = cap.get(1).unwrap().as_str();
-		let offset = cap.get(0).unwrap().start();
-
-		// Add the text before the code block, if any
-		if offset > last_index {
-			let raw_content = message[last_index..offset].trim().to_string();
-			let rendered_content = process_text(raw_content.clone());
-			message_blocks.0.push(MessageBlock {
-				id: None,
-				type_: "text".to_string(),
-				language: None,
-				raw_content: raw_content,
-				rendered_content: rendered_content,
-				copied: Some(false),
-			});
-		}
-
-		// Extract the language from the code block, if present
-		let mut lines = code_with_lang.lines();
-		let first_line = lines.next().unwrap_or("");
-		let (language, code) = if !first_line.is_empty() && first_line.chars().all(|c| c.is_alphabetic()) {
-			(first_line.to_lowercase(), lines.collect::<Vec<_>>().join("\n"))
-		} else {
-			("plain".to_string(), code_with_lang.trim_start_matches("\n").to_string())
-		};
-
-		let highlighted_code: String = match highlight_code(&code, &language, code_theme) {
-			Ok(highlighted_code) => highlighted_code.to_string(),
-			Err(_) => code.to_string(),
-		};
-
-		// Add the code block
-		message_blocks.push(MessageBlock {
-			id: None,
-			type_: "code".to_string(),
-			language: Some(language),
-			raw_content: code,
-			rendered_content: highlighted_code,
-			copied: Some(false),
-		});
-
-		// Update last_index to the end of the current match
-		last_index = offset + match_str.len();
-	}
 
-	// Add any remaining text after the last code block
-	if last_index < message.len() {
-		let raw_content = message[last_index..].trim().to_string();
-		let rendered_content = process_text(raw_content.clone());
-		message_blocks.push(MessageBlock {
-			id: None,
-			type_: "text".to_string(),
-			language: None,
-			raw_content: raw_content,
-			rendered_content: rendered_content,
-			copied: Some(false),
-		});
+	for segment in split_code_fences(message) {
+		match segment {
+			MessageSegment::Text(text) => {
+				for math_segment in split_display_math(&text) {
+					match math_segment {
+						MathSegment::Text(text) => {
+							let raw_content = text.trim().to_string();
+							if raw_content.is_empty() {
+								continue;
+							}
+							let rendered_content = render_markdown(&raw_content);
+							message_blocks.push(MessageBlock {
+								id: None,
+								type_: "text".to_string(),
+								language: None,
+								raw_content: raw_content,
+								rendered_content: rendered_content,
+								copied: Some(false),
+							});
+						}
+						// Display math gets its own block, the TeX source stays in raw_content for copying
+						MathSegment::Display(tex) => {
+							let rendered_content = render_tex_or_source(&tex, true);
+							message_blocks.push(MessageBlock {
+								id: None,
+								type_: "math".to_string(),
+								language: Some("display".to_string()),
+								raw_content: tex,
+								rendered_content: rendered_content,
+								copied: Some(false),
+							});
+						}
+					}
+				}
+			}
+			MessageSegment::Code { language, code } => {
+				let language = language.unwrap_or_else(|| "plain".to_string());
+				// Diagrams whose rendering fails are shown as highlighted source instead
+				if is_diagram_language(&language) {
+					match render_diagram(&language, &code) {
+						Ok(svg) => {
+							message_blocks.push(MessageBlock {
+								id: None,
+								type_: "diagram".to_string(),
+								language: Some(language),
+								raw_content: code,
+								rendered_content: svg,
+								copied: Some(false),
+							});
+							continue;
+						}
+						Err(e) => log::debug!("Could not render {} diagram: {}", language, e),
+					}
+				}
+				let highlighted_code: String = match highlight_code(&code, &language, code_theme) {
+					Ok(highlighted_code) => highlighted_code.to_string(),
+					Err(_) => code.to_string(),
+				};
+				message_blocks.push(MessageBlock {
+					id: None,
+					type_: "code".to_string(),
+					language: Some(language),
+					raw_content: code,
+					rendered_content: highlighted_code,
+					copied: Some(false),
+				});
+			}
+		}
 	}
 	return message_blocks;
 }
 
-pub fn highlight_code(code: &str, language: &str, code_theme: &str) -> Result<String, String> {
-	let ps: SyntaxSet = SyntaxSet::load_defaults_newlines();
-	let ts: ThemeSet = ThemeSet::load_defaults();
-	let capitalized_language: String = capitalize_first_letter(&language);
-	let syntax: &syntect::parsing::SyntaxReference = ps
-		.find_syntax_by_token(&capitalized_language)
-		.unwrap_or_else(|| ps.find_syntax_by_extension("js").unwrap());
-	let theme = &ts.themes[code_theme];
-	let mut h: HighlightLines<'_> = HighlightLines::new(syntax, theme);
-	let bgc = theme.settings.background.unwrap_or_else(|| syntect::highlighting::Color::BLACK);
-	let mut result: String = String::new();
-	result.push_str(&format!(
-		"<div style=\"background-color:#{:02x}{:02x}{:02x};padding:0.75rem;border-bottom-right-radius:0.375rem;border-bottom-left-radius:0.375rem;overflow-x:scroll\">",
-		bgc.r, bgc.g, bgc.b
-	));
-	result.push_str("<pre><code>");
-	for line in LinesWithEndings::from(code) {
-		let ranges: Vec<(Style, &str)> = h.highlight_line(line, &ps).unwrap();
-		let html = styled_line_to_highlighted_html(&ranges[..], IncludeBackground::Yes).unwrap();
-		result.push_str(&html);
+/// A `tool_call` block with the arguments of a call or a `tool_result` block with its result.
+/// `language` holds the name of the tool, JSON is pretty-printed and highlighted.
+pub fn render_tool_block(type_: &str, tool_name: &str, content: &str, code_theme: &str) -> MessageBlock {
+	let (raw_content, language) = match serde_json::from_str::<serde_json::Value>(content) {
+		Ok(json) if json.is_object() || json.is_array() => (serde_json::to_string_pretty(&json).unwrap_or_else(|_| content.to_string()), "json"),
+		_ => (content.to_string(), "plain"),
+	};
+	let rendered_content = match highlight_code(&raw_content, language, code_theme) {
+		Ok(highlighted_code) => highlighted_code,
+		Err(_) => raw_content.clone(),
+	};
+	MessageBlock {
+		id: None,
+		type_: type_.to_string(),
+		language: Some(tool_name.to_string()),
+		raw_content,
+		rendered_content,
+		copied: Some(false),
 	}
-	result.push_str("</code></pre>");
-	result.push_str("</div>");
-	Ok(result)
 }
 
-fn capitalize_first_letter(word: &str) -> String {
-	let mut chars = word.chars();
-	match chars.next() {
-		None => word.to_string(),
-		Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
-	}
+#[derive(Debug, PartialEq)]
+pub enum MessageSegment {
+	Text(String),
+	Code { language: Option<String>, code: String },
 }
 
-fn process_text(mut text: String) -> String {
-	text = escape_html_tags(text);
-	text = replace_backticks_with_span(text);
-	text = replace_markdown_headers_with_html(&text);
-	text = replace_linebreaks_with_br(text);
-	text = replace_bold_with_html(text);
-	text.to_string()
+struct Fence {
+	indent: usize,
+	marker: char,
+	len: usize,
 }
 
-fn replace_backticks_with_span(str: String) -> String {
-	let regex = Regex::new(r"`([^`]+)`").unwrap();
-	regex
-		.replace_all(&str, |caps: &regex::Captures| {
-			format!(
-				"<span class=\"bg-gray2 text-codefontcolor text-sm rounded-xs font-mono px-1\">{}</span>",
-				&caps[1]
-			)
-		})
-		.to_string()
+fn leading_whitespace(line: &str) -> usize {
+	line.len() - line.trim_start_matches(|c| c == ' ' || c == '\t').len()
 }
 
-fn replace_bold_with_html(input: String) -> String {
-	// Regular expression to find text enclosed in '**'
-	let bold_re = Regex::new(r"\*\*(.*?)\*\*").unwrap();
-
-	// Replace '**text**' with '<span class="font-bold">text</span>'
-	let result = bold_re.replace_all(&input, r#"<span class="font-bold">$1</span>"#);
+/// An opening fence is a run of at least three backticks or tildes, followed by an optional info string.
+/// Backtick fences can't have backticks in their info string, otherwise it's inline code.
+fn parse_opening_fence(line: &str) -> Option<(Fence, &str)> {
+	let indent = leading_whitespace(line);
+	let rest = &line[indent..];
+	let marker = rest.chars().next()?;
+	if marker != '`' && marker != '~' {
+		return None;
+	}
+	let len = rest.len() - rest.trim_start_matches(marker).len();
+	if len < 3 {
+		return None;
+	}
+	let info = rest[len..].trim();
+	if marker == '`' && info.contains('`') {
+		return None;
+	}
+	Some((Fence { indent, marker, len }, info))
+}
 
-	result.into_owned()
+/// A closing fence uses the same character and is at least as long as the opening fence
+fn is_closing_fence(line: &str, fence: &Fence) -> bool {
+	let rest = line.trim_start_matches(|c| c == ' ' || c == '\t');
+	let len = rest.len() - rest.trim_start_matches(fence.marker).len();
+	len >= fence.len && rest[len..].trim().is_empty()
 }
 
-fn replace_linebreaks_with_br(input: String) -> String {
-	input.replace("\n", "<br>")
+/// The language is the first word of the info string, e.g. `rust` for ```` ```rust title="main.rs" ````
+fn language_from_info(info: &str) -> Option<String> {
+	info.split_whitespace()
+		.next()
+		.map(|word| word.trim_matches(|c| c == '{' || c == '}' || c == '.').to_lowercase())
+		.filter(|language| !language.is_empty())
 }
 
-fn replace_markdown_headers_with_html(text: &str) -> String {
-	let mut result = String::new();
+/// Splits a message into prose and fenced code blocks. Handles backtick and tilde fences, longer fences
+/// around nested fences, info strings and indented fences (e.g. inside list items). A fence that is never
+/// closed, e.g. in a truncated answer, runs until the end of the message.
+pub fn split_code_fences(message: &str) -> Vec<MessageSegment> {
+	let mut segments: Vec<MessageSegment> = vec![];
+	let mut text = String::new();
+	let mut lines = message.split_inclusive('\n');
 
-	for line in text.lines() {
-		let trimmed_line = line.trim_start();
-		let mut header_level = 0;
+	while let Some(line) = lines.next() {
+		let (fence, info) = match parse_opening_fence(line.trim_end()) {
+			Some(opening_fence) => opening_fence,
+			None => {
+				text.push_str(line);
+				continue;
+			}
+		};
+		if !text.is_empty() {
+			segments.push(MessageSegment::Text(std::mem::take(&mut text)));
+		}
 
-		// Count the number of consecutive '#' characters at the beginning of the line
-		for c in trimmed_line.chars() {
-			if c == '#' {
-				header_level += 1;
-			} else {
+		let mut code = String::new();
+		for line in lines.by_ref() {
+			if is_closing_fence(line.trim_end(), &fence) {
 				break;
 			}
+			// Remove the fence's indentation from the content, but not more than the line has
+			let strip = leading_whitespace(line).min(fence.indent);
+			code.push_str(&line[strip..]);
 		}
-
-		// Replace Markdown headers with HTML headers if '#' characters are found
-		let header_content: &str = &trimmed_line[header_level..].trim_start();
-		let class: &str = match header_level {
-			1 => "text-4xl font-bold",
-			2 => "text-3xl font-bold",
-			3 => "text-2xl font-bold",
-			4 => "text-xl font-bold",
-			5 => "text-lg font-bold",
-			6 => "text-base font-bold",
-			_ => "",
-		};
-		let new_line = format!("<span class=\"{}\">{}</span>\n", class, header_content);
-		result.push_str(&new_line);
+		let code = code.strip_suffix('\n').map(|code| code.strip_suffix('\r').unwrap_or(code)).unwrap_or(&code).to_string();
+		segments.push(MessageSegment::Code {
+			language: language_from_info(info),
+			code,
+		});
+	}
+	if !text.is_empty() {
+		segments.push(MessageSegment::Text(text));
 	}
+	segments
+}
+
+/// Maps the language of a code fence to a syntax, including common aliases. Unknown languages are shown as plain text.
+fn find_syntax<'a>(ps: &'a SyntaxSet, language: &str) -> &'a SyntaxReference {
+	let language = language.to_lowercase();
+	let token = match language.as_str() {
+		"py" | "python3" | "py3" => "py",
+		"ts" | "typescript" | "mts" | "cts" => "ts",
+		"jsx" | "mjs" | "cjs" | "node" => "js",
+		"sh" | "shell" | "bash" | "zsh" | "console" | "shell-session" | "shellsession" | "terminal" => "sh",
+		"yml" => "yaml",
+		"c++" | "cxx" => "cpp",
+		"objc" | "objective-c" | "obj-c" => "m",
+		"objc++" | "objective-c++" => "mm",
+		"c#" | "csharp" | "cs" => "cs",
+		"rs" => "rs",
+		"golang" => "go",
+		"rb" => "rb",
+		"md" => "md",
+		"jsonc" | "json5" => "json",
+		"patch" => "diff",
+		"latex" => "tex",
+		"kt" | "kts" => "kotlin",
+		"terraform" | "hcl" => "tf",
+		"containerfile" => "dockerfile",
+		other => other,
+	};
+	ps.find_syntax_by_token(token).unwrap_or_else(|| ps.find_syntax_plain_text())
+}
+
+/// The file extension for code in the given language, e.g. `py` for `python`. Unknown languages get `txt`.
+pub fn file_extension_for_language(language: &str) -> String {
+	let ps = syntax_set();
+	let syntax = find_syntax(&ps, language);
+	syntax.file_extensions.first().cloned().unwrap_or_else(|| "txt".to_string())
+}
+
+pub fn highlight_code(code: &str, language: &str, code_theme: &str) -> Result<String, String> {
+	let ps: Arc<SyntaxSet> = syntax_set();
+	let syntax: &SyntaxReference = find_syntax(&ps, language);
+	with_theme(code_theme, |theme| {
+		let mut h: HighlightLines<'_> = HighlightLines::new(syntax, theme);
+		let bgc = theme.settings.background.unwrap_or_else(|| syntect::highlighting::Color::BLACK);
+		let mut result: String = String::new();
+		result.push_str(&format!(
+			"<div style=\"background-color:#{:02x}{:02x}{:02x};padding:0.75rem;border-bottom-right-radius:0.375rem;border-bottom-left-radius:0.375rem;overflow-x:scroll\">",
+			bgc.r, bgc.g, bgc.b
+		));
+		result.push_str("<pre><code>");
+		for line in LinesWithEndings::from(code) {
+			let ranges: Vec<(Style, &str)> = h.highlight_line(line, &ps).map_err(|e| e.to_string())?;
+			let html = styled_line_to_highlighted_html(&ranges[..], IncludeBackground::Yes).map_err(|e| e.to_string())?;
+			result.push_str(&html);
+		}
+		result.push_str("</code></pre>");
+		result.push_str("</div>");
+		Ok(result)
+	})
+}
 
-	result
+lazy_static! {
+	/// Sanitizes the rendered Markdown and adds the Tailwind classes for each element, since
+	/// Tailwind's preflight strips the browser's default styles.
+	static ref HTML_SANITIZER: ammonia::Builder<'static> = {
+		let mut builder = ammonia::Builder::default();
+		builder
+			.add_tags(&["input"])
+			.add_tag_attributes("input", &["checked"])
+			.add_tag_attributes("td", &["align"])
+			.add_tag_attributes("th", &["align"])
+			.add_tag_attributes("span", &["data-math-style"])
+			.set_tag_attribute_value("input", "type", "checkbox")
+			.set_tag_attribute_value("input", "disabled", "")
+			.set_tag_attribute_value("input", "class", "mr-1 align-middle")
+			.set_tag_attribute_value("h1", "class", "text-4xl font-bold")
+			.set_tag_attribute_value("h2", "class", "text-3xl font-bold")
+			.set_tag_attribute_value("h3", "class", "text-2xl font-bold")
+			.set_tag_attribute_value("h4", "class", "text-xl font-bold")
+			.set_tag_attribute_value("h5", "class", "text-lg font-bold")
+			.set_tag_attribute_value("h6", "class", "text-base font-bold")
+			.set_tag_attribute_value("p", "class", "my-2")
+			.set_tag_attribute_value("strong", "class", "font-bold")
+			.set_tag_attribute_value("em", "class", "italic")
+			.set_tag_attribute_value("del", "class", "line-through")
+			.set_tag_attribute_value("code", "class", "bg-gray2 text-codefontcolor text-sm rounded-xs font-mono px-1")
+			.set_tag_attribute_value("a", "class", "underline text-blue-400")
+			.set_tag_attribute_value("ul", "class", "list-disc pl-6 my-2")
+			.set_tag_attribute_value("ol", "class", "list-decimal pl-6 my-2")
+			.set_tag_attribute_value("blockquote", "class", "border-l-4 border-gray2 pl-3 my-2 italic")
+			.set_tag_attribute_value("table", "class", "table-auto border-collapse my-2")
+			.set_tag_attribute_value("th", "class", "border border-gray2 px-2 py-1 font-bold")
+			.set_tag_attribute_value("td", "class", "border border-gray2 px-2 py-1")
+			.set_tag_attribute_value("hr", "class", "my-2 border-gray2");
+		builder
+	};
 }
 
-pub fn escape_html_tags(input: String) -> String {
-	let re = Regex::new(r"<([^<>]+)>").unwrap();
-	let escaped = re.replace_all(&input, "&lt;$1&gt;");
-	escaped.to_string()
+/// Renders CommonMark with the GFM extensions (tables, task lists, strikethrough, autolinks) to sanitized HTML.
+/// Raw HTML in the message is escaped so it shows up as text. Inline math (`$...$`, `\(...\)`) is rendered to MathML;
+/// a `$` needs a non-space character right after it and the closing `$` must not be followed by a digit,
+/// so dollar amounts like "$5 and $10" stay prose.
+pub fn render_markdown(text: &str) -> String {
+	let mut options = ComrakOptions::default();
+	options.extension.table = true;
+	options.extension.tasklist = true;
+	options.extension.strikethrough = true;
+	options.extension.autolink = true;
+	options.render.hardbreaks = true;
+	options.render.escape = true;
+	options.extension.math_dollars = true;
+	let html = markdown_to_html(&normalize_inline_delimiters(text), &options);
+	// MathML is added after sanitizing, it is generated by KaTeX from the escaped TeX source
+	render_math_spans(&HTML_SANITIZER.clean(&html).to_string())
 }
 
 #[cfg(debug_assertions)]
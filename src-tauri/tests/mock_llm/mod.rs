@@ -0,0 +1,419 @@
+//! A local stand-in for the provider APIs, so provider and chat tests run offline and deterministically.
+//! Every wire format gets its own server, and the model name of a request picks the scenario.
+
+use byok::llm_providers::LLMProvider;
+use serde_json::{json, Value};
+use wiremock::matchers::{body_partial_json, header, method, path};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+/// Answers with `ANSWER`, or with `TITLE` when asked to name a chat
+pub const MODEL_OK: &str = "mock-ok";
+pub const MODEL_UNAUTHORIZED: &str = "mock-401";
+/// Always rate limited, with `Retry-After: RETRY_AFTER_SECS`
+pub const MODEL_RATE_LIMITED: &str = "mock-429";
+/// Rate limited on the first request only, then answers like `MODEL_OK`
+pub const MODEL_RATE_LIMITED_ONCE: &str = "mock-429-once";
+pub const MODEL_SERVER_ERROR: &str = "mock-500";
+/// Answers 200 with a truncated JSON body
+pub const MODEL_MALFORMED: &str = "mock-malformed";
+/// Calls `TOOL_NAME` with `TOOL_ARGUMENTS` until the request carries a tool result, then answers with `TOOL_ANSWER`
+pub const MODEL_TOOLS: &str = "mock-tools";
+/// Calls `TOOL_NAME` on every request
+pub const MODEL_TOOLS_LOOP: &str = "mock-tools-loop";
+
+/// The key the servers reject on every endpoint
+pub const INVALID_KEY: &str = "invalid";
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+/// Streamed answers arrive in these pieces
+pub const ANSWER_DELTAS: [&str; 4] = ["Here you go:\n\n", "```rust\n", "fn main() {}\n", "```\n"];
+pub const ANSWER: &str = "Here you go:\n\n```rust\nfn main() {}\n```\n";
+pub const TITLE: &str = "Mock title";
+pub const TOOL_NAME: &str = "add";
+pub const TOOL_ARGUMENTS: &str = r#"{"a":2,"b":3}"#;
+pub const TOOL_ANSWER: &str = "2 + 3 = 5";
+
+/// `ChatService::name_chat` asks for titles with this token limit, which tells them apart from other requests
+const TITLE_MAX_TOKENS: u32 = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub enum WireFormat {
+	OpenAI,
+	Anthropic,
+	Mistral,
+	Groq,
+	Ollama,
+}
+
+impl WireFormat {
+	pub const ALL: [WireFormat; 5] = [
+		WireFormat::OpenAI,
+		WireFormat::Anthropic,
+		WireFormat::Mistral,
+		WireFormat::Groq,
+		WireFormat::Ollama,
+	];
+
+	/// The name of the provider in the `providers` table
+	pub fn provider_name(self) -> &'static str {
+		match self {
+			WireFormat::OpenAI => "openai",
+			WireFormat::Anthropic => "anthropic",
+			WireFormat::Mistral => "mistralai",
+			WireFormat::Groq => "groqcloud",
+			WireFormat::Ollama => "local",
+		}
+	}
+
+	/// The path of the API root, like on the real servers
+	fn api_root(self) -> &'static str {
+		match self {
+			WireFormat::Groq => "/openai/v1",
+			_ => "/v1",
+		}
+	}
+
+	fn chat_path(self) -> String {
+		match self {
+			WireFormat::Anthropic => format!("{}/messages", self.api_root()),
+			_ => format!("{}/chat/completions", self.api_root()),
+		}
+	}
+
+	fn completion(self, model: &str, content: &str) -> Value {
+		match self {
+			WireFormat::Anthropic => json!({
+				"id": "msg_mock",
+				"type": "message",
+				"role": "assistant",
+				"model": model,
+				"content": [{ "type": "text", "text": content }],
+				"stop_reason": "end_turn",
+				"stop_sequence": null,
+				"usage": { "input_tokens": 12, "output_tokens": 8 }
+			}),
+			_ => {
+				let mut completion = json!({
+					"id": "chatcmpl-mock",
+					"object": "chat.completion",
+					"created": 1700000000,
+					"model": model,
+					"choices": [{
+						"index": 0,
+						"message": { "role": "assistant", "content": content },
+						"finish_reason": "stop"
+					}],
+					"usage": { "prompt_tokens": 12, "completion_tokens": 8, "total_tokens": 20 }
+				});
+				match self {
+					WireFormat::OpenAI => {
+						completion["choices"][0]["logprobs"] = Value::Null;
+						completion["system_fingerprint"] = json!("fp_mock");
+					}
+					WireFormat::Mistral => completion["choices"][0]["message"]["tool_calls"] = Value::Null,
+					WireFormat::Groq => completion["x_groq"] = json!({ "id": "req_mock" }),
+					WireFormat::Ollama => completion["system_fingerprint"] = json!("fp_ollama"),
+					WireFormat::Anthropic => unreachable!(),
+				}
+				completion
+			}
+		}
+	}
+
+	/// An answer that calls `TOOL_NAME`
+	fn tool_call(self, model: &str) -> Value {
+		let mut completion = self.completion(model, "");
+		match self {
+			WireFormat::Anthropic => {
+				completion["content"] = json!([
+					{ "type": "text", "text": "Let me add these." },
+					{ "type": "tool_use", "id": "toolu_mock", "name": TOOL_NAME, "input": serde_json::from_str::<Value>(TOOL_ARGUMENTS).unwrap() }
+				]);
+				completion["stop_reason"] = json!("tool_use");
+			}
+			_ => {
+				completion["choices"][0]["message"] = json!({
+					"role": "assistant",
+					"content": null,
+					"tool_calls": [{
+						"id": "call_mock",
+						"type": "function",
+						"function": { "name": TOOL_NAME, "arguments": TOOL_ARGUMENTS }
+					}]
+				});
+				completion["choices"][0]["finish_reason"] = json!("tool_calls");
+			}
+		}
+		completion
+	}
+
+	/// Whether the request sends a tool result back, as a `tool` message or a `tool_result` block
+	fn has_tool_result(self, request: &Request) -> bool {
+		let body: Value = match serde_json::from_slice(&request.body) {
+			Ok(body) => body,
+			Err(_) => return false,
+		};
+		let messages = body["messages"].as_array().cloned().unwrap_or_default();
+		messages.iter().any(|message| match self {
+			WireFormat::Anthropic => message["content"]
+				.as_array()
+				.map_or(false, |blocks| blocks.iter().any(|block| block["type"] == "tool_result")),
+			_ => message["role"] == "tool",
+		})
+	}
+
+	/// Whether the real API would refuse the request's tool history: Anthropic needs the tool definitions
+	/// to accept `tool_use` and `tool_result` blocks, OpenAI a `tool` message after every call
+	fn has_invalid_tool_history(self, request: &Request) -> bool {
+		let body: Value = match serde_json::from_slice(&request.body) {
+			Ok(body) => body,
+			Err(_) => return false,
+		};
+		let messages = body["messages"].as_array().cloned().unwrap_or_default();
+		match self {
+			WireFormat::Anthropic => {
+				body["tools"].is_null()
+					&& messages.iter().any(|message| {
+						message["content"].as_array().map_or(false, |blocks| {
+							blocks.iter().any(|block| block["type"] == "tool_use" || block["type"] == "tool_result")
+						})
+					})
+			}
+			_ => messages.iter().enumerate().any(|(index, message)| {
+				let results: Vec<&Value> = messages[index + 1..].iter().take_while(|message| message["role"] == "tool").collect();
+				message["tool_calls"].as_array().map_or(false, |calls| {
+					calls.iter().any(|call| !results.iter().any(|result| result["tool_call_id"] == call["id"]))
+				})
+			}),
+		}
+	}
+
+	/// The answer as server-sent events
+	fn stream(self, model: &str) -> String {
+		let event = |name: Option<&str>, data: Value| match name {
+			Some(name) => format!("event: {}\ndata: {}\n\n", name, data),
+			None => format!("data: {}\n\n", data),
+		};
+		let mut events = String::new();
+		match self {
+			WireFormat::Anthropic => {
+				let message =
+					json!({ "type": "message_start", "message": { "id": "msg_mock", "type": "message", "role": "assistant", "model": model, "content": [] } });
+				events += &event(Some("message_start"), message);
+				events += &event(
+					Some("content_block_start"),
+					json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+				);
+				events += &event(Some("ping"), json!({ "type": "ping" }));
+				for delta in ANSWER_DELTAS {
+					events += &event(
+						Some("content_block_delta"),
+						json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": delta } }),
+					);
+				}
+				events += &event(Some("content_block_stop"), json!({ "type": "content_block_stop", "index": 0 }));
+				events += &event(
+					Some("message_delta"),
+					json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn" } }),
+				);
+				events += &event(Some("message_stop"), json!({ "type": "message_stop" }));
+			}
+			_ => {
+				let chunk = |delta: Value, finish_reason: Value| {
+					json!({
+						"id": "chatcmpl-mock",
+						"object": "chat.completion.chunk",
+						"created": 1700000000,
+						"model": model,
+						"choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+					})
+				};
+				// The first chunk only carries the role, the last one only the finish reason
+				events += &event(None, chunk(json!({ "role": "assistant", "content": "" }), Value::Null));
+				for delta in ANSWER_DELTAS {
+					events += &event(None, chunk(json!({ "content": delta }), Value::Null));
+				}
+				events += &event(None, chunk(json!({}), json!("stop")));
+				events += "data: [DONE]\n\n";
+			}
+		}
+		events
+	}
+
+	fn error(self, status: u16, kind: &str, message: &str) -> ResponseTemplate {
+		let body = match self {
+			WireFormat::Anthropic => json!({ "type": "error", "error": { "type": kind, "message": message } }),
+			WireFormat::Mistral => json!({ "object": "error", "message": message, "type": kind, "code": status.to_string() }),
+			WireFormat::Ollama => json!({ "error": message }),
+			_ => json!({ "error": { "message": message, "type": kind, "code": kind } }),
+		};
+		ResponseTemplate::new(status).set_body_json(body)
+	}
+
+	fn models(self) -> Value {
+		match self {
+			WireFormat::Anthropic => json!({
+				"data": [{ "type": "model", "id": MODEL_OK, "display_name": "Mock", "created_at": "2024-01-01T00:00:00Z" }],
+				"has_more": false
+			}),
+			WireFormat::Groq => {
+				json!({ "object": "list", "data": [{ "id": MODEL_OK, "object": "model", "owned_by": "mock", "context_window": 8192, "max_completion_tokens": 1024 }] })
+			}
+			WireFormat::Mistral => {
+				json!({ "object": "list", "data": [{ "id": MODEL_OK, "object": "model", "owned_by": "mock", "max_context_length": 32768 }] })
+			}
+			_ => json!({ "object": "list", "data": [{ "id": MODEL_OK, "object": "model", "created": 1700000000, "owned_by": "mock" }] }),
+		}
+	}
+
+	/// Matches requests made with `INVALID_KEY`
+	fn invalid_key(self) -> wiremock::matchers::HeaderExactMatcher {
+		match self {
+			WireFormat::Anthropic => header("x-api-key", INVALID_KEY),
+			_ => header("Authorization", format!("Bearer {}", INVALID_KEY).as_str()),
+		}
+	}
+}
+
+pub struct MockLlm {
+	pub server: MockServer,
+	pub format: WireFormat,
+}
+
+impl MockLlm {
+	pub async fn start(format: WireFormat) -> Self {
+		let server = MockServer::start().await;
+		let chat_path = format.chat_path();
+		let models_path = format!("{}/models", format.api_root());
+		let chat_for = |model: &str| {
+			Mock::given(method("POST"))
+				.and(path(chat_path.as_str()))
+				.and(body_partial_json(json!({ "model": model })))
+		};
+
+		// Lower numbers win, wiremock's default priority is 5
+		Mock::given(method("POST"))
+			.and(path(chat_path.as_str()))
+			.and(format.invalid_key())
+			.respond_with(format.error(401, "authentication_error", "Invalid API key"))
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		Mock::given(method("POST"))
+			.and(path(chat_path.as_str()))
+			.and(move |request: &Request| format.has_invalid_tool_history(request))
+			.respond_with(format.error(400, "invalid_request_error", "Invalid tool history"))
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_UNAUTHORIZED)
+			.respond_with(format.error(401, "authentication_error", "Invalid API key"))
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_RATE_LIMITED)
+			.respond_with(
+				format
+					.error(429, "rate_limit_error", "Rate limit reached")
+					.insert_header("Retry-After", RETRY_AFTER_SECS.to_string().as_str()),
+			)
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_RATE_LIMITED_ONCE)
+			.respond_with(
+				format
+					.error(429, "rate_limit_error", "Rate limit reached")
+					.insert_header("Retry-After", RETRY_AFTER_SECS.to_string().as_str()),
+			)
+			.up_to_n_times(1)
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_RATE_LIMITED_ONCE)
+			.respond_with(ResponseTemplate::new(200).set_body_json(format.completion(MODEL_RATE_LIMITED_ONCE, ANSWER)))
+			.mount(&server)
+			.await;
+		chat_for(MODEL_SERVER_ERROR)
+			.respond_with(format.error(500, "server_error", "The server had an error while processing your request"))
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_MALFORMED)
+			.respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"id": "chatcmpl-mock", "choices": [{"#, "application/json"))
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		for model in [MODEL_OK, MODEL_TOOLS] {
+			chat_for(model)
+				.and(body_partial_json(json!({ "max_tokens": TITLE_MAX_TOKENS })))
+				.respond_with(ResponseTemplate::new(200).set_body_json(format.completion(model, TITLE)))
+				.with_priority(2)
+				.mount(&server)
+				.await;
+		}
+		chat_for(MODEL_TOOLS)
+			.and(move |request: &Request| format.has_tool_result(request))
+			.respond_with(ResponseTemplate::new(200).set_body_json(format.completion(MODEL_TOOLS, TOOL_ANSWER)))
+			.with_priority(3)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_TOOLS)
+			.respond_with(ResponseTemplate::new(200).set_body_json(format.tool_call(MODEL_TOOLS)))
+			.mount(&server)
+			.await;
+		chat_for(MODEL_TOOLS_LOOP)
+			.respond_with(ResponseTemplate::new(200).set_body_json(format.tool_call(MODEL_TOOLS_LOOP)))
+			.mount(&server)
+			.await;
+		chat_for(MODEL_OK)
+			.and(body_partial_json(json!({ "stream": true })))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(format.stream(MODEL_OK), "text/event-stream"))
+			.with_priority(3)
+			.mount(&server)
+			.await;
+		chat_for(MODEL_OK)
+			.respond_with(ResponseTemplate::new(200).set_body_json(format.completion(MODEL_OK, ANSWER)))
+			.mount(&server)
+			.await;
+
+		Mock::given(method("GET"))
+			.and(path(models_path.as_str()))
+			.and(format.invalid_key())
+			.respond_with(format.error(401, "authentication_error", "Invalid API key"))
+			.with_priority(1)
+			.mount(&server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path(models_path.as_str()))
+			.respond_with(ResponseTemplate::new(200).set_body_json(format.models()))
+			.mount(&server)
+			.await;
+
+		MockLlm { server, format }
+	}
+
+	/// The API root to inject, e.g. with `LLMProvider::with_base_url` or `ChatService::with_base_url`
+	pub fn base_url(&self) -> String {
+		format!("{}{}", self.server.uri(), self.format.api_root())
+	}
+
+	pub fn provider(&self, api_key: &str) -> LLMProvider {
+		LLMProvider::with_base_url(self.format.provider_name(), api_key.to_string(), &self.base_url()).unwrap()
+	}
+
+	/// The chat requests the server received, as JSON
+	pub async fn chat_requests(&self) -> Vec<Value> {
+		let chat_path = self.format.chat_path();
+		self.requests()
+			.await
+			.iter()
+			.filter(|request| request.url.path() == chat_path)
+			.map(|request| serde_json::from_slice(&request.body).unwrap())
+			.collect()
+	}
+
+	pub async fn requests(&self) -> Vec<Request> {
+		self.server.received_requests().await.unwrap_or_default()
+	}
+}
@@ -0,0 +1,122 @@
+//! A small MCP server for the client tests. It is reached over an in-process pipe like a stdio server,
+//! or over HTTP like a streamable HTTP server.
+
+use byok::mcp::transport::{StreamTransport, Transport};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+pub const SERVER_NAME: &str = "stub";
+/// Returns its `text` argument, marked as read-only
+pub const ECHO_TOOL: &str = "echo";
+/// Answers with an error result, marked as read-only
+pub const FAILING_TOOL: &str = "fail";
+/// Listed on the second page of `tools/list`, not read-only
+pub const NOTE_TOOL: &str = "write_note";
+pub const RESOURCE_URI: &str = "note://welcome";
+pub const RESOURCE_TEXT: &str = "Welcome to the stub server";
+/// Has a required `name` argument
+pub const PROMPT_NAME: &str = "greet";
+pub const SESSION_ID: &str = "stub-session";
+
+/// The answer to a JSON-RPC message, `None` for notifications
+pub fn answer(message: &Value) -> Option<Value> {
+	let id = message.get("id")?.clone();
+	let params = &message["params"];
+	let result = match message["method"].as_str().unwrap_or_default() {
+		"initialize" => json!({
+			"protocolVersion": params["protocolVersion"],
+			"capabilities": { "tools": {}, "resources": {}, "prompts": {} },
+			"serverInfo": { "name": "stub-server", "version": "0.1.0" }
+		}),
+		"tools/list" => match params["cursor"].as_str() {
+			None => json!({ "tools": [tool(ECHO_TOOL, true), tool(FAILING_TOOL, true)], "nextCursor": "2" }),
+			Some(_) => json!({ "tools": [tool(NOTE_TOOL, false)] }),
+		},
+		"tools/call" => match params["name"].as_str().unwrap_or_default() {
+			ECHO_TOOL => json!({ "content": [{ "type": "text", "text": params["arguments"]["text"] }] }),
+			NOTE_TOOL => json!({ "content": [{ "type": "text", "text": "Saved" }, { "type": "image", "data": "", "mimeType": "image/png" }] }),
+			name => json!({ "content": [{ "type": "text", "text": format!("{} failed", name) }], "isError": true }),
+		},
+		"resources/list" => json!({ "resources": [{ "uri": RESOURCE_URI, "name": "Welcome", "mimeType": "text/plain" }] }),
+		"resources/read" => json!({ "contents": [{ "uri": params["uri"], "mimeType": "text/plain", "text": RESOURCE_TEXT }] }),
+		"prompts/list" => json!({
+			"prompts": [{ "name": PROMPT_NAME, "description": "Greets someone", "arguments": [{ "name": "name", "required": true }] }]
+		}),
+		"prompts/get" => json!({
+			"messages": [{
+				"role": "user",
+				"content": { "type": "text", "text": format!("Please greet {}", params["arguments"]["name"].as_str().unwrap_or_default()) }
+			}]
+		}),
+		method => return Some(json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32601, "message": format!("Method not found: {}", method) } })),
+	};
+	Some(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
+
+fn tool(name: &str, read_only: bool) -> Value {
+	json!({
+		"name": name,
+		"description": format!("The {} tool", name),
+		"inputSchema": { "type": "object", "properties": { "text": { "type": "string" } } },
+		"annotations": { "readOnlyHint": read_only }
+	})
+}
+
+/// Runs the server on one end of a pipe and returns a transport for the other end.
+/// Like real servers, it also sends log notifications and blank lines, which the client skips.
+pub fn stdio_transport() -> Transport {
+	let (client, server) = tokio::io::duplex(64 * 1024);
+	tokio::spawn(async move {
+		let (reader, mut writer) = tokio::io::split(server);
+		let mut lines = BufReader::new(reader).lines();
+		while let Ok(Some(line)) = lines.next_line().await {
+			let message: Value = serde_json::from_str(&line).unwrap();
+			if let Some(answer) = answer(&message) {
+				let log = json!({ "jsonrpc": "2.0", "method": "notifications/message", "params": { "level": "info", "data": "Answering" } });
+				let output = format!("{}\n\n{}\n", log, answer);
+				if writer.write_all(output.as_bytes()).await.is_err() {
+					return;
+				}
+			}
+		}
+	});
+	let (reader, writer) = tokio::io::split(client);
+	Transport::Stream(StreamTransport::new(SERVER_NAME, reader, writer))
+}
+
+/// Serves the stub at `/mcp`. `initialize` starts a session that later requests need,
+/// and tool calls are answered with an event stream.
+pub async fn start_http() -> MockServer {
+	let server = MockServer::start().await;
+	Mock::given(method("POST")).and(path("/mcp")).respond_with(HttpStub).mount(&server).await;
+	server
+}
+
+struct HttpStub;
+
+impl Respond for HttpStub {
+	fn respond(&self, request: &Request) -> ResponseTemplate {
+		let message: Value = match serde_json::from_slice(&request.body) {
+			Ok(message) => message,
+			Err(_) => return ResponseTemplate::new(400),
+		};
+		let has_session = request
+			.headers
+			.get("Mcp-Session-Id")
+			.map_or(false, |session_id| session_id.as_bytes() == SESSION_ID.as_bytes());
+		if message["method"] != "initialize" && !has_session {
+			return ResponseTemplate::new(400).set_body_string("Missing session");
+		}
+		match answer(&message) {
+			None => ResponseTemplate::new(202),
+			Some(answer) if message["method"] == "tools/call" => {
+				let progress = json!({ "jsonrpc": "2.0", "method": "notifications/progress", "params": { "progress": 1 } });
+				let stream = format!("event: message\ndata: {}\n\nevent: message\ndata: {}\n\n", progress, answer);
+				ResponseTemplate::new(200).set_body_raw(stream, "text/event-stream")
+			}
+			Some(answer) => ResponseTemplate::new(200).insert_header("Mcp-Session-Id", SESSION_ID).set_body_json(answer),
+		}
+	}
+}
@@ -1,17 +1,1149 @@
-use byok::llm_providers::openai::OpenAIProvider;
+mod mock_llm;
+mod mock_mcp;
 
 #[cfg(test)]
 mod tests {
-	use byok::{llm_providers::LLMConfig, types::MessageHistory};
+	use std::collections::BTreeMap;
+	use std::sync::{Arc, Mutex};
+	use std::time::{Duration, Instant};
 
-	use super::*;
+	use async_trait::async_trait;
+	use serde_json::{json, Value};
+
+	use byok::attachments::attach_files;
+	use byok::db::{fetch_all_models, init_in_memory, insert_api_key, insert_custom_model, remove_api_key, set_default_api_key_secret, sync_models};
+	use byok::diagrams::render_diagram;
+	use byok::events::EventSink;
+	use byok::jobs::resume_jobs;
+	use byok::llm_providers::cassette::Cassette;
+	use byok::llm_providers::{http, KeyStatus, LLMProvider};
+	use byok::math::{normalize_inline_delimiters, split_display_math, MathSegment};
+	use byok::mcp::client::{McpClient, McpToolInfo};
+	use byok::mcp::tools::{client_tools, tool_name, tool_name_collisions};
+	use byok::mcp::{
+		connect, remove_unused_env_secrets, resolve_env_secrets, store_env_secrets, validate_servers, McpManager, McpServerConfig, McpServerState,
+	};
+	use byok::secrets::{SecretStore, SECRET_REF_PREFIX};
+	use byok::service::ChatService;
+	use byok::settings::{Settings, SETTINGS_VERSION};
+	use byok::themes::DEFAULT_CODE_THEME;
+	use byok::tools::approval::EventApprover;
+	use byok::tools::calculator::{evaluate, CalculatorTool};
+	use byok::tools::clock::CurrentTimeTool;
+	use byok::tools::files::{ApprovedFolders, ListDirectoryTool, ReadFileTool};
+	use byok::tools::shell::{sandbox_available, ShellTool};
+	use byok::tools::{builtin_tools, Tool, ToolRegistry, MAX_TOOL_ITERATIONS};
+	use byok::utils::{highlight_code, render_markdown, split_code_fences, MessageSegment};
+	use byok::{
+		llm_providers::LLMConfig,
+		types::{Attachment, Message, MessageHistory, Model},
+	};
+
+	use super::mock_llm::*;
+	use super::mock_mcp::{self, *};
+
+	/// Keeps all events in order
+	#[derive(Default)]
+	struct EventLog {
+		events: Mutex<Vec<(String, Value)>>,
+	}
+
+	impl EventLog {
+		fn names(&self) -> Vec<String> {
+			self.events.lock().unwrap().iter().map(|(event, _)| event.clone()).collect()
+		}
+	}
+
+	impl EventSink for EventLog {
+		fn emit_value(&self, event: &str, payload: Value) {
+			self.events.lock().unwrap().push((event.to_string(), payload));
+		}
+	}
+
+	fn user_message(content: &str) -> MessageHistory {
+		MessageHistory(vec![Message {
+			id: "".to_string(),
+			role: "user".to_string(),
+			content: content.to_string(),
+			model_name: MODEL_OK.to_string(),
+			blocks: None,
+			tool_calls: None,
+			tool_call_id: None,
+			attachments: vec![],
+		}])
+	}
+
+	/// A service on an empty database that sends the requests of the mock's provider to it, with a key for that provider
+	async fn service_for(mock: &MockLlm) -> ChatService {
+		let provider_name = mock.format.provider_name();
+		let secrets = SecretStore::without_keyring("test passphrase");
+		let pool = init_in_memory(&secrets).await.unwrap();
+		set_default_api_key_secret(provider_name, "test-key", &secrets, &pool).await.unwrap();
+		ChatService::new(pool, secrets, Arc::new(EventLog::default())).with_base_url(provider_name, &mock.base_url())
+	}
+
+	struct AddTool;
+
+	#[async_trait]
+	impl Tool for AddTool {
+		fn name(&self) -> &str {
+			TOOL_NAME
+		}
+
+		fn description(&self) -> &str {
+			"Adds two numbers"
+		}
+
+		fn parameters(&self) -> Value {
+			json!({
+				"type": "object",
+				"properties": { "a": { "type": "number" }, "b": { "type": "number" } },
+				"required": ["a", "b"]
+			})
+		}
+
+		async fn call(&self, arguments: Value) -> Result<String, String> {
+			match (arguments["a"].as_f64(), arguments["b"].as_f64()) {
+				(Some(a), Some(b)) => Ok((a + b).to_string()),
+				_ => Err("a and b must be numbers".to_string()),
+			}
+		}
+	}
 
 	#[tokio::test]
-	async fn test_openai_provider() {
-		let provider = OpenAIProvider::new("test", "https://api.openai.com/v1/chat/completions");
-		let messages = MessageHistory(vec![]);
+	async fn test_providers_against_mock_servers() {
+		let messages = user_message("Hi");
 		let config = LLMConfig::default();
-		let response = provider.send_message(&messages, "gpt-3.5-turbo", &config).await;
-		assert!(response.is_err()); // Will error due to invalid API key, which is expected
+		for format in WireFormat::ALL {
+			let mock = MockLlm::start(format).await;
+			let provider = mock.provider("test-key");
+
+			assert_eq!(provider.send_message(&messages, MODEL_OK, &config).await.unwrap(), ANSWER, "{:?}", format);
+			let mut deltas: Vec<String> = vec![];
+			let answer = provider
+				.stream_message(&messages, MODEL_OK, &config, &mut |delta| deltas.push(delta.to_string()))
+				.await
+				.unwrap();
+			assert_eq!(answer, ANSWER, "{:?}", format);
+			assert_eq!(deltas, ANSWER_DELTAS, "{:?}", format);
+			assert_eq!(mock.chat_requests().await[0]["messages"][0]["content"], "Hi", "{:?}", format);
+
+			for model in [MODEL_UNAUTHORIZED, MODEL_RATE_LIMITED, MODEL_SERVER_ERROR, MODEL_MALFORMED] {
+				assert!(provider.send_message(&messages, model, &config).await.is_err(), "{:?} {}", format, model);
+				assert!(
+					provider.stream_message(&messages, model, &config, &mut |_| {}).await.is_err(),
+					"{:?} {}",
+					format,
+					model
+				);
+			}
+			let error = mock.provider(INVALID_KEY).send_message(&messages, MODEL_OK, &config).await.unwrap_err();
+			assert!(error.to_string().contains("Invalid API key"), "{:?}: {}", format, error);
+
+			let listed_model = provider.list_models().await.unwrap().remove(0);
+			assert_eq!(listed_model.id, MODEL_OK, "{:?}", format);
+			// Only Groq reports the output limit
+			assert_eq!(
+				listed_model.max_output_tokens,
+				Some(1024).filter(|_| matches!(format, WireFormat::Groq)),
+				"{:?}",
+				format
+			);
+			assert!(provider.validate_key().await.is_valid(), "{:?}", format);
+			assert!(
+				matches!(mock.provider(INVALID_KEY).validate_key().await.status, KeyStatus::Invalid),
+				"{:?}",
+				format
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_retry_after() {
+		let mock = MockLlm::start(WireFormat::OpenAI).await;
+		// The retry count of the settings is shared by all tests running in parallel, so this one only sets it for its requests
+		let config = LLMConfig {
+			max_retries: Some(1),
+			..Default::default()
+		};
+		let started = Instant::now();
+		let answer = mock
+			.provider("test-key")
+			.send_message(&user_message("Hi"), MODEL_RATE_LIMITED_ONCE, &config)
+			.await;
+		let elapsed = started.elapsed();
+		// The server may have processed the request before it failed, so only 429 and 503 are retried
+		let server_error = mock.provider("test-key").send_message(&user_message("Hi"), MODEL_SERVER_ERROR, &config).await;
+
+		assert_eq!(answer.unwrap(), ANSWER);
+		assert!(elapsed >= Duration::from_secs(RETRY_AFTER_SECS));
+		assert!(server_error.is_err());
+		assert_eq!(mock.chat_requests().await.len(), 3);
+		assert_eq!(http::backoff(0), Duration::from_millis(500));
+		assert_eq!(http::backoff(2), Duration::from_secs(2));
+		assert_eq!(http::backoff(u32::MAX), http::backoff(http::MAX_RETRIES));
+	}
+
+	#[test]
+	fn test_split_code_fences() {
+		let message = "Intro\n```c++ title=\"main.cpp\"\nint main() {}\n```\n````md\n```py\nprint(1)\n```\n````\n~~~sh\nls\n~~~\n```rust\nfn truncated() {";
+		let segments = split_code_fences(message);
+		assert_eq!(
+			segments,
+			vec![
+				MessageSegment::Text("Intro\n".to_string()),
+				MessageSegment::Code {
+					language: Some("c++".to_string()),
+					code: "int main() {}".to_string()
+				},
+				MessageSegment::Code {
+					language: Some("md".to_string()),
+					code: "```py\nprint(1)\n```".to_string()
+				},
+				MessageSegment::Code {
+					language: Some("sh".to_string()),
+					code: "ls".to_string()
+				},
+				MessageSegment::Code {
+					language: Some("rust".to_string()),
+					code: "fn truncated() {".to_string()
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn test_math_detection() {
+		let prose = render_markdown("It costs $5 and $10, or $5-$10.");
+		assert!(!prose.contains("<math"));
+		assert!(render_markdown("The loss is $L = x^2$.").contains("<math"));
+		assert_eq!(normalize_inline_delimiters("so \\(x\\) is `\\(x\\)`"), "so $x$ is `\\(x\\)`");
+		assert!(render_markdown("Write `\\(x\\)` for \\(x\\)").contains(">\\(x\\)</code>"));
+
+		assert_eq!(
+			split_display_math("Solve\n\\[ x^2 = 1 \\]\nfor x"),
+			vec![
+				MathSegment::Text("Solve\n".to_string()),
+				MathSegment::Display("x^2 = 1".to_string()),
+				MathSegment::Text("\nfor x".to_string()),
+			]
+		);
+		// Dollars in code spans and escaped brackets within a line aren't math
+		for text in ["Run `echo $$` to get the PID, then `kill $$`", "Set arr\\[0\\] and arr\\[1\\], then print \\]"] {
+			assert_eq!(split_display_math(text), vec![MathSegment::Text(text.to_string())]);
+		}
+		assert_eq!(
+			split_display_math("``a ` $$`` is\n$$ x^2 $$"),
+			vec![MathSegment::Text("``a ` $$`` is\n".to_string()), MathSegment::Display("x^2".to_string())]
+		);
+	}
+
+	#[test]
+	fn test_render_diagram() {
+		let svg = render_diagram("dot", "digraph { a -> b }").unwrap();
+		assert!(svg.starts_with("<svg"));
+		assert!(!svg.contains("<style"));
+		assert!(render_diagram("dot", "digraph {}").is_err());
+		assert!(render_diagram("mermaid", "graph TD; A-->B").is_err());
+
+		// Graphs the models commonly write, none of them may panic
+		let graphs = [
+			"digraph { a -> a; a -> b; b -> b }",
+			"digraph { subgraph s { a -> b } c -> a }",
+			"digraph { subgraph cluster_x { label=\"X\"; a -> b } subgraph cluster_y { c -> c } b -> c }",
+			"digraph { \"ä\" -> \"日本\"; \"日本\" [label=\"漢字 ✓\"] }",
+			"digraph { a -> b; a -> b; a -> b [label=\"again\"] }",
+			"graph { a -- b; b -- a; a -- a }",
+		];
+		for graph in graphs {
+			let svg = render_diagram("dot", graph).unwrap_or_else(|e| panic!("{}: {}", graph, e));
+			assert!(svg.contains("<text"), "{}", graph);
+		}
+		assert!(render_diagram("dot", "digraph { \"ä\" -> \"日本\"; \"日本\" [label=\"漢字 ✓\"] }")
+			.unwrap()
+			.contains("漢字 ✓"));
+		// Features layout-rs panics on are left as code
+		for graph in [
+			"digraph { a [shape=record, label=\"\"] }",
+			"digraph { a [shape=Mrecord, label=\"{\"]; a -> b }",
+			"digraph { subgraph cluster_x { a [shape=record] } }",
+			"digraph { a [fontsize=18446744073709551615]; a -> b }",
+		] {
+			assert!(render_diagram("dot", graph).is_err(), "{}", graph);
+		}
+	}
+
+	#[test]
+	fn test_unknown_code_theme_falls_back() {
+		let highlighted = highlight_code("fn main() {}", "rust", "no-such-theme").unwrap();
+		assert_eq!(highlighted, highlight_code("fn main() {}", "rust", byok::themes::DEFAULT_CODE_THEME).unwrap());
+	}
+
+	#[test]
+	fn test_settings_migration_and_reset() {
+		let dir = std::env::temp_dir().join(format!("byok-settings-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let settings_file = dir.join("settings.json");
+
+		std::fs::write(
+			&settings_file,
+			r#"{"default_model":"claude-3-opus-20240229","default_provider":"anthropic","code_theme":"no-such-theme"}"#,
+		)
+		.unwrap();
+		let (settings, warning) = Settings::load(&settings_file);
+		assert!(warning.is_none());
+		assert_eq!(settings.version, SETTINGS_VERSION);
+		assert_eq!(settings.default_model, Settings::default().default_model);
+		assert_eq!(settings.code_theme, byok::themes::DEFAULT_CODE_THEME);
+		assert!(std::fs::read_to_string(&settings_file).unwrap().contains("\"version\""));
+
+		std::fs::write(&settings_file, "{not json").unwrap();
+		let (settings, warning) = Settings::load(&settings_file);
+		assert!(warning.is_some());
+		assert_eq!(settings.default_model, Settings::default().default_model);
+		let backups = std::fs::read_dir(&dir).unwrap().filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "bak").count();
+		assert_eq!(backups, 1);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_chat_service() {
+		let secrets = SecretStore::default();
+		let pool = init_in_memory(&secrets).await.unwrap();
+		let events = Arc::new(EventLog::default());
+		let service = ChatService::new(pool, secrets, events.clone());
+
+		let message = "Try this:\n```rust\nfn main() {}\n```\nand\n```sh\nls\n```";
+		let message_id = service.add_message("chat", "user", message, "model", DEFAULT_CODE_THEME).await.unwrap();
+		service.add_message("chat", "assistant", "Done", "model", DEFAULT_CODE_THEME).await.unwrap();
+		assert_eq!(events.names(), vec!["newChat", "newMessage", "newMessage"]);
+		assert_eq!(service.chats().await.unwrap().len(), 1);
+		assert!(service.is_unnamed("chat").await.unwrap());
+		assert!(service.llm("openai", Some("chat")).await.is_err());
+
+		let messages = service.messages("chat").await.unwrap();
+		assert_eq!(
+			messages.iter().map(|message| message.role.as_str()).collect::<Vec<_>>(),
+			vec!["user", "assistant"]
+		);
+		assert_eq!(service.copy_all_code(&message_id).await.unwrap(), "fn main() {}\n\nls");
+		let blocks = service.messages("chat").await.unwrap()[0].blocks.clone().unwrap();
+		assert!(blocks.iter().filter(|block| block.type_ == "code").all(|block| block.copied == Some(true)));
+
+		// Files are only replaced when the path comes from a save dialog, which asked the user
+		let dir = std::env::temp_dir().join(format!("byok-save-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let block_id = blocks.iter().find(|block| block.type_ == "code").unwrap().id.unwrap();
+		let path = service.save_block_to_file(block_id, &dir.join("main"), false).await.unwrap();
+		assert_eq!(path, dir.join("main.rs"));
+		assert_eq!(std::fs::read_to_string(&path).unwrap().trim_end(), "fn main() {}");
+		for (path, overwrite) in [(dir.join("main"), true), (dir.join("main.rs"), false)] {
+			let error = service.save_block_to_file(block_id, &path, overwrite).await.unwrap_err();
+			assert!(error.contains("already exists"), "{}", error);
+		}
+		assert!(service.save_block_to_file(block_id, &dir.join("main.rs"), true).await.is_ok());
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		service.rename_chat("chat", "Rust").await.unwrap();
+		assert!(!service.is_unnamed("chat").await.unwrap());
+		service.archive_chat("chat").await.unwrap();
+		assert!(service.chats().await.unwrap().is_empty());
+
+		// Deleting a chat leaves nothing of it behind
+		let notes = Attachment {
+			id: "notes".to_string(),
+			file_name: "notes.txt".to_string(),
+			mime_type: "text/plain".to_string(),
+			data: None,
+			path: None,
+			extracted_text: Some("Remember the milk".to_string()),
+		};
+		service
+			.add_message_with_attachments("chat", "Summarize this", vec![notes], "model", DEFAULT_CODE_THEME)
+			.await
+			.unwrap();
+		let other_chat_message = service.add_message("other", "user", "Hi", "model", DEFAULT_CODE_THEME).await.unwrap();
+		for query in [
+			"INSERT INTO chat_tools (chat_id, tool_name) VALUES ('chat', 'calculator')",
+			"INSERT INTO jobs (id, kind, chat_id) VALUES ('job', 'generate_answer', 'chat')",
+		] {
+			sqlx::query(query).execute(&service.pool).await.unwrap();
+		}
+		service.delete_chat("chat").await.unwrap();
+		for table in ["chats", "messages", "message_blocks", "attachments", "chat_tools", "jobs"] {
+			let query = format!("SELECT COUNT(*) FROM {}", table);
+			let rows = sqlx::query_scalar::<_, i64>(&query).fetch_one(&service.pool).await.unwrap();
+			let expected = match table {
+				"chats" | "messages" | "message_blocks" => 1,
+				_ => 0,
+			};
+			assert_eq!(rows, expected, "{}", table);
+		}
+		assert_eq!(service.messages("other").await.unwrap()[0].id, other_chat_message);
+	}
+
+	#[tokio::test]
+	async fn test_sync_models() {
+		let secrets = SecretStore::default();
+		let pool = init_in_memory(&secrets).await.unwrap();
+		let model = |model_name: &str| Model {
+			provider_name: "openai".to_string(),
+			model_name: model_name.to_string(),
+			model_display_name: model_name.to_string(),
+			show: false,
+			max_tokens: 4096,
+			context_window: 4096,
+			deprecated: false,
+		};
+		let pool = &pool;
+		let deprecated_models = move || async move {
+			let models = fetch_all_models(pool).await.unwrap().0;
+			models
+				.into_iter()
+				.filter(|model| model.provider_name == "openai" && model.deprecated)
+				.map(|model| model.model_name)
+				.collect::<Vec<_>>()
+		};
+		insert_custom_model(&model("gpt-4o-2024-08-06"), pool).await.unwrap();
+
+		// Models the user added aren't deprecated because the listing doesn't know them
+		sync_models("openai", &[model("gpt-4o")], pool).await.unwrap();
+		assert_eq!(deprecated_models().await, vec!["chatgpt-4o-latest"]);
+		// An empty listing changes nothing
+		sync_models("openai", &[], pool).await.unwrap();
+		assert_eq!(deprecated_models().await, vec!["chatgpt-4o-latest"]);
+		sync_models("openai", &[model("chatgpt-4o-latest")], pool).await.unwrap();
+		assert_eq!(deprecated_models().await, vec!["gpt-4o"]);
+	}
+
+	#[tokio::test]
+	async fn test_remove_api_key() {
+		let secrets = SecretStore::without_keyring("test passphrase");
+		let pool = init_in_memory(&secrets).await.unwrap();
+		let pool = &pool;
+		let default_api_key = move || async move {
+			let query = "SELECT default_api_key_id FROM providers WHERE provider_name = 'openai'";
+			sqlx::query_scalar::<_, Option<String>>(query).fetch_one(pool).await.unwrap()
+		};
+		let first = set_default_api_key_secret("openai", "first-key", &secrets, pool).await.unwrap();
+		let second = insert_api_key("openai", "Second", "second-key", &secrets, pool).await.unwrap();
+		assert_eq!(default_api_key().await, Some(first.clone()));
+
+		// The remaining key takes over as the default
+		remove_api_key(&first, &secrets, pool).await.unwrap();
+		assert_eq!(default_api_key().await, Some(second.clone()));
+		remove_api_key(&second, &secrets, pool).await.unwrap();
+		assert_eq!(default_api_key().await, None);
+		assert!(remove_api_key(&second, &secrets, pool).await.unwrap_err().contains("does not exist"));
+	}
+
+	#[tokio::test]
+	async fn test_chat_pipeline_against_mock_servers() {
+		let openai = MockLlm::start(WireFormat::OpenAI).await;
+		let ollama = MockLlm::start(WireFormat::Ollama).await;
+		let events = Arc::new(EventLog::default());
+		let service = ChatService {
+			events: events.clone(),
+			..service_for(&openai).await
+		}
+		.with_base_url("local", &ollama.base_url());
+
+		// The first turn streams the answer, stores and renders it, then names the chat
+		let mut streamed = String::new();
+		let answer = service
+			.ask("chat", "openai", MODEL_OK, "Say hi in Rust", vec![], DEFAULT_CODE_THEME, &mut |delta| {
+				streamed.push_str(delta)
+			})
+			.await
+			.unwrap();
+		assert_eq!(answer, ANSWER);
+		assert_eq!(streamed, ANSWER);
+		assert_eq!(events.names(), vec!["newChat", "newMessage", "newMessage", "newChat"]);
+
+		let messages = service.messages("chat").await.unwrap();
+		assert_eq!(
+			messages.iter().map(|message| message.content.as_str()).collect::<Vec<_>>(),
+			vec!["Say hi in Rust", ANSWER]
+		);
+		let blocks = messages[1].blocks.clone().unwrap();
+		let code = blocks.iter().find(|block| block.type_ == "code").unwrap();
+		assert_eq!(code.language.as_deref(), Some("rust"));
+		assert_eq!(code.raw_content.trim_end(), "fn main() {}");
+		assert!(code.rendered_content.contains("<span"));
+		assert_eq!(service.chats().await.unwrap()[0].display_name, TITLE);
+
+		let requests = openai.chat_requests().await;
+		assert_eq!(requests.len(), 2);
+		assert_eq!(requests[0]["stream"], true);
+		assert!(requests[1]["messages"][0]["content"].as_str().unwrap().contains("Say hi in Rust"));
+		assert!(openai
+			.requests()
+			.await
+			.iter()
+			.all(|request| request.headers.get("Authorization").unwrap() == "Bearer test-key"));
+
+		// Later turns keep the name, and local models need no key
+		service
+			.ask("chat", "local", MODEL_OK, "Again", vec![], DEFAULT_CODE_THEME, &mut |_| {})
+			.await
+			.unwrap();
+		assert_eq!(ollama.chat_requests().await[0]["messages"].as_array().unwrap().len(), 3);
+		assert_eq!(service.messages("chat").await.unwrap().len(), 4);
+		assert_eq!(service.chats().await.unwrap()[0].display_name, TITLE);
+
+		// Failed requests store no answer
+		assert!(service
+			.ask("chat", "openai", MODEL_SERVER_ERROR, "Once more", vec![], DEFAULT_CODE_THEME, &mut |_| {})
+			.await
+			.is_err());
+		let messages = service.messages("chat").await.unwrap();
+		assert_eq!(messages.len(), 5);
+		assert_eq!(messages[4].role, "user");
+	}
+	#[tokio::test]
+	async fn test_resume_answer_job() {
+		let mock = MockLlm::start(WireFormat::OpenAI).await;
+		let service = service_for(&mock).await;
+		service.add_message("chat", "user", "Hello", MODEL_OK, DEFAULT_CODE_THEME).await.unwrap();
+		let job_query = "INSERT INTO jobs (id, kind, chat_id, payload, status) VALUES ('job', 'generate_answer', 'chat', '{}', 'running')";
+		sqlx::query(job_query).execute(&service.pool).await.unwrap();
+
+		// The app quit after the answer was stored, before the job was done
+		let llm = service.llm("openai", Some("chat")).await.unwrap();
+		assert_eq!(service.answer(&llm, "chat", "job", MODEL_OK, DEFAULT_CODE_THEME).await.unwrap(), "job");
+		assert_eq!(resume_jobs(&service.pool).await.unwrap(), vec!["job"]);
+		assert_eq!(service.answer(&llm, "chat", "job", MODEL_OK, DEFAULT_CODE_THEME).await.unwrap(), "job");
+		let messages = service.messages("chat").await.unwrap();
+		assert_eq!(
+			messages
+				.iter()
+				.map(|message| (message.role.as_str(), message.content.as_str()))
+				.collect::<Vec<_>>(),
+			vec![("user", "Hello"), ("assistant", ANSWER)]
+		);
+		assert_eq!(mock.chat_requests().await.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_attachments_against_mock_servers() {
+		let dir = std::env::temp_dir().join(format!("byok-attachments-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let image = dir.join("pixel.PNG");
+		let notes = dir.join("notes.md");
+		let binary = dir.join("data.bin");
+		std::fs::write(&image, [0x89, b'P', b'N', b'G']).unwrap();
+		std::fs::write(&notes, "# Notes\nBuy milk\n").unwrap();
+		std::fs::write(&binary, [0, 1, 2, 3]).unwrap();
+
+		let error = attach_files(&[&notes, &binary]).unwrap_err();
+		assert!(error.contains("data.bin can't be attached"), "{}", error);
+		assert!(attach_files(&[dir.join("missing.txt")]).is_err());
+		let attachments = attach_files(&[&image, &notes]).unwrap();
+		assert_eq!(attachments[0].mime_type, "image/png");
+		assert_eq!(attachments[1].mime_type, "text/markdown");
+		assert_eq!(attachments[1].extracted_text.as_deref(), Some("# Notes\nBuy milk\n"));
+
+		for format in [WireFormat::OpenAI, WireFormat::Anthropic] {
+			let mock = MockLlm::start(format).await;
+			let service = service_for(&mock).await;
+			service
+				.ask(
+					"chat",
+					format.provider_name(),
+					MODEL_OK,
+					"What's this?",
+					attachments.clone(),
+					DEFAULT_CODE_THEME,
+					&mut |_| {},
+				)
+				.await
+				.unwrap();
+
+			// The image becomes a content part, the text file is inlined after the prompt
+			let requests = mock.chat_requests().await;
+			let parts = requests[0]["messages"][0]["content"].as_array().unwrap();
+			let (image_part, text_part) = match format {
+				WireFormat::Anthropic => (&parts[0], &parts[1]),
+				_ => (&parts[1], &parts[0]),
+			};
+			let text = text_part["text"].as_str().unwrap();
+			assert!(text.starts_with("What's this?"), "{:?}: {}", format, text);
+			assert!(text.contains("<file name=\"notes.md\">\n# Notes\nBuy milk\n</file>"), "{:?}: {}", format, text);
+			match format {
+				WireFormat::Anthropic => assert_eq!(image_part["source"], json!({ "type": "base64", "media_type": "image/png", "data": "iVBORw==" })),
+				_ => assert_eq!(image_part["image_url"]["url"], "data:image/png;base64,iVBORw=="),
+			}
+
+			// The app shows the files as blocks after the prompt
+			let messages = service.messages("chat").await.unwrap();
+			let attachment_blocks: Vec<(&str, Option<&str>)> = messages[0]
+				.blocks
+				.as_ref()
+				.unwrap()
+				.iter()
+				.filter(|block| block.type_ == "attachment")
+				.map(|block| (block.raw_content.as_str(), block.language.as_deref()))
+				.collect();
+			assert_eq!(attachment_blocks, vec![("pixel.PNG", Some("image/png")), ("notes.md", Some("text/markdown"))]);
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_tool_calling_against_mock_servers() {
+		for format in [WireFormat::OpenAI, WireFormat::Anthropic, WireFormat::Mistral] {
+			let mock = MockLlm::start(format).await;
+			let service = service_for(&mock).await.with_tools(ToolRegistry::default().with(AddTool));
+			service.set_chat_tool("chat", TOOL_NAME, true).await.unwrap();
+			service.set_chat_tool("loop", TOOL_NAME, true).await.unwrap();
+
+			// Each call and result is its own message, the final answer comes after them
+			let answer = service
+				.ask(
+					"chat",
+					format.provider_name(),
+					MODEL_TOOLS,
+					"What is 2 + 3?",
+					vec![],
+					DEFAULT_CODE_THEME,
+					&mut |_| {},
+				)
+				.await
+				.unwrap();
+			assert_eq!(answer, TOOL_ANSWER, "{:?}", format);
+			let messages = service.messages("chat").await.unwrap();
+			assert_eq!(
+				messages.iter().map(|message| message.role.as_str()).collect::<Vec<_>>(),
+				vec!["user", "assistant", "tool", "assistant"],
+				"{:?}",
+				format
+			);
+			let call = &messages[1].tool_calls.as_ref().unwrap()[0];
+			assert_eq!(call.name, TOOL_NAME);
+			assert_eq!(serde_json::from_str::<Value>(&call.arguments).unwrap(), json!({ "a": 2, "b": 3 }));
+			assert_eq!(messages[2].content, "5");
+			assert_eq!(messages[2].tool_call_id.as_ref(), Some(&call.id));
+			assert!(messages[1].blocks.as_ref().unwrap().iter().any(|block| block.type_ == "tool_call"));
+			assert_eq!(messages[2].blocks.as_ref().unwrap()[0].type_, "tool_result");
+			assert_eq!(messages[3].content, TOOL_ANSWER);
+			assert_eq!(service.chats().await.unwrap()[0].display_name, TITLE);
+
+			let requests = mock.chat_requests().await;
+			assert_eq!(requests[0]["tools"].as_array().unwrap().len(), 1, "{:?}", format);
+			assert_eq!(requests[1]["messages"].as_array().unwrap().len(), 3, "{:?}", format);
+
+			// Without tools, the earlier calls and results are sent as text, Anthropic refuses tool blocks without tool definitions
+			service.set_chat_tool("chat", TOOL_NAME, false).await.unwrap();
+			let answer = service
+				.ask("chat", format.provider_name(), MODEL_OK, "Thanks", vec![], DEFAULT_CODE_THEME, &mut |_| {})
+				.await
+				.unwrap();
+			assert_eq!(answer, ANSWER, "{:?}", format);
+			let request = mock.chat_requests().await.pop().unwrap();
+			assert!(request["tools"].is_null());
+			let history = request["messages"].to_string();
+			assert!(history.contains("[add returned: 5]") && !history.contains("tool_use"), "{}", history);
+
+			// A call that never got its result, e.g. because the app quit while the tool ran, gets one saying so
+			let lost_call = byok::llm_providers::ToolCall {
+				id: "call_lost".to_string(),
+				name: TOOL_NAME.to_string(),
+				arguments: TOOL_ARGUMENTS.to_string(),
+			};
+			service
+				.add_message("interrupted", "user", "What is 2 + 3?", MODEL_TOOLS, DEFAULT_CODE_THEME)
+				.await
+				.unwrap();
+			service
+				.add_tool_calls("interrupted", "", &[lost_call], MODEL_TOOLS, DEFAULT_CODE_THEME)
+				.await
+				.unwrap();
+			service.set_chat_tool("interrupted", TOOL_NAME, true).await.unwrap();
+			let answer = service
+				.ask(
+					"interrupted",
+					format.provider_name(),
+					MODEL_TOOLS,
+					"Are you still there?",
+					vec![],
+					DEFAULT_CODE_THEME,
+					&mut |_| {},
+				)
+				.await
+				.unwrap();
+			assert_eq!(answer, TOOL_ANSWER, "{:?}", format);
+			let history = mock.chat_requests().await.iter().rev().find(|request| !request["tools"].is_null()).unwrap()["messages"].to_string();
+			assert!(
+				history.contains("call_lost") && history.contains("interrupted before the tool returned"),
+				"{}",
+				history
+			);
+
+			// A model that keeps calling tools is stopped
+			let error = service
+				.ask(
+					"loop",
+					format.provider_name(),
+					MODEL_TOOLS_LOOP,
+					"Add forever",
+					vec![],
+					DEFAULT_CODE_THEME,
+					&mut |_| {},
+				)
+				.await
+				.unwrap_err();
+			assert!(error.contains("still calling tools"), "{}", error);
+			assert_eq!(service.messages("loop").await.unwrap().len(), 1 + 2 * MAX_TOOL_ITERATIONS);
+		}
+	}
+
+	/// `AddTool`, but as if it had side effects
+	struct GuardedAddTool;
+
+	#[async_trait]
+	impl Tool for GuardedAddTool {
+		fn name(&self) -> &str {
+			AddTool.name()
+		}
+
+		fn description(&self) -> &str {
+			AddTool.description()
+		}
+
+		fn parameters(&self) -> Value {
+			AddTool.parameters()
+		}
+
+		fn needs_approval(&self) -> bool {
+			true
+		}
+
+		async fn call(&self, arguments: Value) -> Result<String, String> {
+			AddTool.call(arguments).await
+		}
+	}
+
+	#[tokio::test]
+	async fn test_chat_tools_and_approvals() {
+		let mock = MockLlm::start(WireFormat::OpenAI).await;
+		let events = Arc::new(EventLog::default());
+		let service = ChatService {
+			events: events.clone(),
+			..service_for(&mock).await
+		}
+		.with_tools(ToolRegistry::default().with(GuardedAddTool).with(CalculatorTool));
+
+		// Chats only get the tools turned on for them
+		assert!(service.chat_tools("chat").await.unwrap().is_empty());
+		assert!(service.set_chat_tool("chat", "subtract", true).await.is_err());
+		service.set_chat_tool("chat", TOOL_NAME, true).await.unwrap();
+		service.set_chat_tool("chat", "calculator", true).await.unwrap();
+		service.set_chat_tool("chat", "calculator", false).await.unwrap();
+		let settings = service.chat_tool_settings("chat").await.unwrap();
+		assert_eq!(
+			settings
+				.iter()
+				.map(|tool| (tool.name.as_str(), tool.enabled, tool.needs_approval))
+				.collect::<Vec<_>>(),
+			vec![(TOOL_NAME, true, true), ("calculator", false, false)]
+		);
+		assert_eq!(service.chat_tools("chat").await.unwrap().names(), vec![TOOL_NAME]);
+		assert!(service.chat_tools("other").await.unwrap().is_empty());
+
+		// Without an approver the call is declined, and the model learns about it
+		let answer = service
+			.ask("chat", "openai", MODEL_TOOLS, "What is 2 + 3?", vec![], DEFAULT_CODE_THEME, &mut |_| {})
+			.await
+			.unwrap();
+		assert_eq!(answer, TOOL_ANSWER);
+		let messages = service.messages("chat").await.unwrap();
+		assert!(messages[2].content.contains("did not allow"), "{}", messages[2].content);
+
+		// The UI approves through `answer_tool_approval`
+		let approvals = Arc::new(EventApprover::new(events.clone()));
+		let service = service.with_approver(approvals.clone());
+		service.set_chat_tool("approved", TOOL_NAME, true).await.unwrap();
+		let answering = {
+			let approvals = approvals.clone();
+			tokio::spawn(async move {
+				loop {
+					if let Some(request) = approvals.pending().pop() {
+						approvals.answer(&request.id, true).unwrap();
+						return request;
+					}
+					tokio::time::sleep(Duration::from_millis(10)).await;
+				}
+			})
+		};
+		service
+			.ask("approved", "openai", MODEL_TOOLS, "What is 2 + 3?", vec![], DEFAULT_CODE_THEME, &mut |_| {})
+			.await
+			.unwrap();
+		let request = answering.await.unwrap();
+		assert_eq!((request.chat_id.as_str(), request.tool_name.as_str()), ("approved", TOOL_NAME));
+		assert!(events.names().contains(&"toolApprovalRequested".to_string()));
+		assert_eq!(service.messages("approved").await.unwrap()[2].content, "5");
+		assert!(approvals.pending().is_empty());
+		assert!(approvals.answer(&request.id, true).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_builtin_tools() {
+		assert_eq!(evaluate("2 + 3 * 4").unwrap(), 14.0);
+		assert_eq!(evaluate("(2 + 3) * 4").unwrap(), 20.0);
+		assert_eq!(evaluate("-2^2 + 2^3^2").unwrap(), 508.0);
+		assert_eq!(evaluate("sqrt(16) + abs(-1.5) + 10 % 4").unwrap(), 7.5);
+		assert_eq!(evaluate("1.5e3 / 3").unwrap(), 500.0);
+		assert!((evaluate("cos(pi)").unwrap() + 1.0).abs() < 1e-12);
+		for invalid in ["1 / 0", "2 +", "(1", "foo(2)", "2 $ 3", ""] {
+			assert!(evaluate(invalid).is_err(), "{}", invalid);
+		}
+		// Deep nesting is refused instead of overflowing the stack
+		assert_eq!(evaluate(&format!("{}1{}", "(".repeat(200), ")".repeat(200))).unwrap(), 1.0);
+		let deep = [
+			"(".repeat(300) + "1" + &")".repeat(300),
+			"-".repeat(500) + "1",
+			"2^".repeat(300) + "1",
+			"(-".repeat(200) + "1" + &")".repeat(200),
+		];
+		for expression in deep {
+			assert!(evaluate(&expression).unwrap_err().contains("nested too deeply"));
+		}
+		assert!(evaluate(&"(".repeat(100_000)).unwrap_err().contains("too long"));
+		assert_eq!(CalculatorTool.call(json!({ "expression": "7 / 2" })).await.unwrap(), "3.5");
+		assert_eq!(CalculatorTool.call(json!({ "expression": "6 * 7" })).await.unwrap(), "42");
+		assert!(CurrentTimeTool.call(json!({})).await.unwrap().starts_with("Local: "));
+
+		let root = std::env::temp_dir().join(format!("byok-tools-test-{}", std::process::id()));
+		let approved = root.join("approved");
+		std::fs::create_dir_all(approved.join("src")).unwrap();
+		std::fs::write(approved.join("notes.txt"), "Remember the milk").unwrap();
+		std::fs::write(approved.join("image.bin"), [0xff, 0xfe, 0x00]).unwrap();
+		std::fs::write(root.join("secret.txt"), "Top secret").unwrap();
+
+		let no_folders = ApprovedFolders::default();
+		assert!(ReadFileTool { folders: no_folders.clone() }
+			.call(json!({ "path": "notes.txt" }))
+			.await
+			.unwrap_err()
+			.contains("No folders"));
+		let folders = ApprovedFolders::new(&[approved.to_string_lossy().to_string(), root.join("missing").to_string_lossy().to_string()]);
+		assert_eq!(folders.list(), vec![approved.canonicalize().unwrap()]);
+		let read_file = ReadFileTool { folders: folders.clone() };
+		let list_directory = ListDirectoryTool { folders: folders.clone() };
+
+		assert_eq!(read_file.call(json!({ "path": "notes.txt" })).await.unwrap(), "Remember the milk");
+		let absolute_path = approved.join("notes.txt").to_string_lossy().to_string();
+		assert_eq!(read_file.call(json!({ "path": absolute_path })).await.unwrap(), "Remember the milk");
+		assert!(read_file.call(json!({ "path": "image.bin" })).await.unwrap_err().contains("not a text file"));
+		assert!(read_file.call(json!({ "path": "../secret.txt" })).await.unwrap_err().contains("outside"));
+		let outside_path = root.join("secret.txt").to_string_lossy().to_string();
+		assert!(read_file.call(json!({ "path": outside_path })).await.unwrap_err().contains("outside"));
+		assert!(read_file.call(json!({ "path": "missing.txt" })).await.unwrap_err().contains("does not exist"));
+
+		assert!(list_directory.call(json!({})).await.unwrap().contains("approved/"));
+		assert_eq!(
+			list_directory.call(json!({ "path": "." })).await.unwrap(),
+			"image.bin (3 bytes)\nnotes.txt (17 bytes)\nsrc/"
+		);
+		assert!(list_directory.call(json!({ "path": ".." })).await.is_err());
+		// Big files are cut off, the last character may not be split
+		std::fs::write(approved.join("big.txt"), format!("a{}", "é".repeat(150_000))).unwrap();
+		let big = read_file.call(json!({ "path": "big.txt" })).await.unwrap();
+		assert!(big.ends_with("é\n[Cut off after 200000 bytes]"));
+		assert_eq!(big.len(), 199_999 + "\n[Cut off after 200000 bytes]".len());
+
+		let tools = builtin_tools(&folders);
+		assert_eq!(tools.names()[..4], ["calculator", "current_time", "list_directory", "read_file"]);
+		// The shell is left out where its commands can't be sandboxed
+		assert_eq!(tools.get("run_shell_command").is_some(), sandbox_available());
+		assert!(tools
+			.iter()
+			.filter(|tool| tool.name() != "run_shell_command")
+			.all(|tool| !tool.needs_approval()));
+		if let Some(shell) = tools.get("run_shell_command") {
+			assert!(shell.needs_approval());
+			let output = shell.call(json!({ "command": "pwd && cat notes.txt && echo oops >&2" })).await.unwrap();
+			assert!(output.starts_with("Exit code: 0\n"), "{}", output);
+			assert!(output.contains(&approved.canonicalize().unwrap().to_string_lossy().to_string()), "{}", output);
+			assert!(output.contains("Remember the milk") && output.contains("Errors:\noops"), "{}", output);
+			assert!(shell.call(json!({ "command": "exit 3" })).await.unwrap().starts_with("Exit code: 3"));
+			assert!(shell.call(json!({ "command": "ls", "working_dir": ".." })).await.is_err());
+			// Bubblewrap gives the commands a /tmp of their own, only the working directory is shared
+			#[cfg(target_os = "linux")]
+			{
+				shell
+					.call(json!({ "command": "echo hi > written.txt; echo hi > ../written.txt" }))
+					.await
+					.unwrap();
+				assert!(approved.join("written.txt").exists() && !root.join("written.txt").exists());
+			}
+		} else {
+			assert!(ShellTool { folders: folders.clone() }
+				.call(json!({ "command": "echo unsandboxed" }))
+				.await
+				.unwrap_err()
+				.contains("sandboxed"));
+		}
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_tool_registry() {
+		let tools = ToolRegistry::default().with(AddTool);
+		assert_eq!(tools.names(), vec![TOOL_NAME]);
+		assert_eq!(tools.specs()[0].parameters["required"], json!(["a", "b"]));
+		let call = |name: &str, arguments: &str| byok::llm_providers::ToolCall {
+			id: "call".to_string(),
+			name: name.to_string(),
+			arguments: arguments.to_string(),
+		};
+		assert_eq!(tools.call(&call(TOOL_NAME, TOOL_ARGUMENTS)).await.unwrap(), "5");
+		assert!(tools.call(&call(TOOL_NAME, "")).await.unwrap_err().contains("numbers"));
+		assert!(tools.call(&call(TOOL_NAME, "{\"a\":")).await.unwrap_err().contains("not valid JSON"));
+		assert!(tools.call(&call("subtract", "{}")).await.unwrap_err().contains("Unknown tool"));
+	}
+
+	#[tokio::test]
+	async fn test_mcp_client() {
+		let client = Arc::new(McpClient::connect(SERVER_NAME, mock_mcp::stdio_transport()).await.unwrap());
+		assert_eq!(client.server_info, "stub-server 0.1.0");
+		// The tools come in two pages
+		assert_eq!(
+			client.tools.iter().map(|tool| tool.name.as_str()).collect::<Vec<_>>(),
+			vec![ECHO_TOOL, FAILING_TOOL, NOTE_TOOL]
+		);
+		assert_eq!(client.resources[0].uri, RESOURCE_URI);
+		assert_eq!((client.prompts[0].name.as_str(), client.prompts[0].arguments[0].required), (PROMPT_NAME, true));
+
+		assert_eq!(client.call_tool(ECHO_TOOL, json!({ "text": "hello" })).await.unwrap(), "hello");
+		assert_eq!(client.call_tool(FAILING_TOOL, json!({})).await.unwrap_err(), "fail failed");
+		assert_eq!(
+			client.call_tool(NOTE_TOOL, json!({})).await.unwrap(),
+			"Saved\n[image content (image/png) left out]"
+		);
+		assert_eq!(client.read_resource(RESOURCE_URI).await.unwrap(), RESOURCE_TEXT);
+		let arguments = BTreeMap::from([("name".to_string(), "Ada".to_string())]);
+		assert_eq!(client.get_prompt(PROMPT_NAME, &arguments).await.unwrap(), "Please greet Ada");
+
+		// The models see the tools under the server's name, only read-only ones run without approval
+		let tools = client_tools(&client);
+		assert_eq!(tools.names(), vec!["stub__echo", "stub__fail", "stub__read_resource", "stub__write_note"]);
+		assert!(!tools.get("stub__echo").unwrap().needs_approval());
+		assert!(tools.get("stub__write_note").unwrap().needs_approval());
+		assert!(tools.get("stub__read_resource").unwrap().description().contains(RESOURCE_URI));
+		let call = |name: &str, arguments: &str| byok::llm_providers::ToolCall {
+			id: "call".to_string(),
+			name: name.to_string(),
+			arguments: arguments.to_string(),
+		};
+		assert_eq!(tools.call(&call("stub__echo", r#"{"text":"hi"}"#)).await.unwrap(), "hi");
+		let uri = format!(r#"{{"uri":"{}"}}"#, RESOURCE_URI);
+		assert_eq!(tools.call(&call("stub__read_resource", &uri)).await.unwrap(), RESOURCE_TEXT);
+		assert_eq!(tool_name("my server", "notes.search"), "my_server__notes_search");
+		assert_eq!(tool_name(&"x".repeat(100), "y").len(), 64);
+		assert_ne!(tool_name(&"x".repeat(100), "y"), tool_name(&"x".repeat(100), "z"));
+		// Tools whose names clash after replacing characters get a hash suffix and the clash is reported
+		let mut clashing = McpClient::connect(SERVER_NAME, mock_mcp::stdio_transport()).await.unwrap();
+		for name in ["write.note", "read_resource"] {
+			let tool = McpToolInfo {
+				name: name.to_string(),
+				..clashing.tools[0].clone()
+			};
+			clashing.tools.push(tool);
+		}
+		let clashing = Arc::new(clashing);
+		assert_eq!(
+			client_tools(&clashing).names(),
+			vec![
+				"stub__echo",
+				"stub__fail",
+				"stub__read_resource",
+				"stub__read_resource_e0701ecc",
+				"stub__write_note_770bdf54",
+				"stub__write_note_a67d0e7b"
+			]
+		);
+		assert_eq!(tool_name_collisions(&clashing).len(), 2);
+
+		// HTTP servers keep a session and may answer with an event stream
+		let server = mock_mcp::start_http().await;
+		let config = McpServerConfig {
+			name: "http".to_string(),
+			url: Some(format!("{}/mcp", server.uri())),
+			..Default::default()
+		};
+		let client = connect(&config).await.unwrap();
+		assert_eq!(client.tools.len(), 3);
+		assert_eq!(client.call_tool(ECHO_TOOL, json!({ "text": "over http" })).await.unwrap(), "over http");
+
+		let missing = McpServerConfig {
+			name: "missing".to_string(),
+			command: Some("byok-missing-mcp-server".to_string()),
+			..Default::default()
+		};
+		assert!(connect(&missing).await.err().unwrap().contains("Could not start"));
+		// A server that exits fails the connection instead of hanging
+		#[cfg(unix)]
+		{
+			let exits = McpServerConfig {
+				name: "exits".to_string(),
+				command: Some("sh".to_string()),
+				args: vec!["-c".to_string(), "exit 0".to_string()],
+				..Default::default()
+			};
+			assert!(connect(&exits).await.err().unwrap().contains("MCP server"));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_mcp_servers() {
+		let server = mock_mcp::start_http().await;
+		let url = format!("{}/mcp", server.uri());
+		let config = |name: &str, enabled: bool| McpServerConfig {
+			name: name.to_string(),
+			url: Some(url.clone()),
+			enabled,
+			..Default::default()
+		};
+
+		let valid = validate_servers(vec![McpServerConfig {
+			name: " notes ".to_string(),
+			command: Some(" ".to_string()),
+			url: Some(format!(" {} ", url)),
+			..Default::default()
+		}])
+		.unwrap();
+		assert_eq!(valid, vec![config("notes", true)]);
+		assert!(validate_servers(vec![config("notes", true), config("notes", false)])
+			.unwrap_err()
+			.contains("two MCP servers"));
+		assert!(validate_servers(vec![config("my notes", true)]).is_err());
+		assert!(validate_servers(vec![McpServerConfig {
+			name: "notes".to_string(),
+			..Default::default()
+		}])
+		.unwrap_err()
+		.contains("needs a command or a URL"));
+		assert!(validate_servers(vec![McpServerConfig {
+			name: "notes".to_string(),
+			url: Some("ftp://localhost/mcp".to_string()),
+			..Default::default()
+		}])
+		.is_err());
+
+		let events = Arc::new(EventLog::default());
+		let manager = McpManager::new(events.clone());
+		manager.apply(&[config("notes", true), config("off", false)]).await;
+		assert_eq!(
+			manager.statuses().iter().map(|status| (status.name.as_str(), status.state)).collect::<Vec<_>>(),
+			vec![("notes", McpServerState::Connected), ("off", McpServerState::Disabled)]
+		);
+		assert_eq!(manager.statuses()[0].prompts[0].name, PROMPT_NAME);
+		assert!(manager.tools().names().contains(&"notes__echo".to_string()));
+		assert!(events.names().contains(&"mcpServersChanged".to_string()));
+		assert!(manager.client("off").is_err());
+		assert!(manager.reconnect("off").await.is_err());
+		manager.reconnect("notes").await.unwrap();
+		assert_eq!(manager.client("notes").unwrap().tools.len(), 3);
+
+		// Chats can turn MCP tools on like built-in ones
+		let secrets = SecretStore::without_keyring("test passphrase");
+		let pool = init_in_memory(&secrets).await.unwrap();
+		let service = ChatService::new(pool.clone(), secrets.clone(), events.clone()).with_tools(manager.tools());
+		service.set_chat_tool("chat", "notes__echo", true).await.unwrap();
+		assert_eq!(service.chat_tools("chat").await.unwrap().names(), vec!["notes__echo"]);
+
+		// Environment variables are stored as secrets, the settings only hold references to them
+		let mut servers = vec![McpServerConfig {
+			env: BTreeMap::from([("TOKEN".to_string(), "s3cret".to_string())]),
+			..config("secret", true)
+		}];
+		store_env_secrets(&mut servers, &[], &secrets, &pool).await.unwrap();
+		assert!(servers[0].env["TOKEN"].starts_with(SECRET_REF_PREFIX));
+		assert_eq!(resolve_env_secrets(&servers[0], &secrets, &pool).await.unwrap().env["TOKEN"], "s3cret");
+		let previous = servers.clone();
+		store_env_secrets(&mut servers, &previous, &secrets, &pool).await.unwrap();
+		assert_eq!(servers, previous);
+		let secret_manager = McpManager::new(events.clone()).with_secrets(secrets.clone(), pool.clone());
+		secret_manager.apply(&servers).await;
+		assert_eq!(secret_manager.statuses()[0].state, McpServerState::Connected);
+		remove_unused_env_secrets(&previous, &[], &secrets, &pool).await;
+		assert!(resolve_env_secrets(&previous[0], &secrets, &pool).await.unwrap_err().contains("doesn't exist"));
+		secret_manager.reconnect("secret").await.unwrap();
+		assert_eq!(secret_manager.statuses()[0].state, McpServerState::Failed);
+
+		manager.apply(&[]).await;
+		assert!(manager.statuses().is_empty() && manager.tools().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_cassette_record_and_replay() {
+		let path = std::env::temp_dir().join(format!("byok-cassette-test-{}.json", std::process::id()));
+		let mock = MockLlm::start(WireFormat::Anthropic).await;
+		let base_url = mock.base_url();
+		let exchange = |provider: LLMProvider| async move {
+			let messages = user_message("Hi");
+			let config = LLMConfig::default();
+			let mut deltas: Vec<String> = vec![];
+			let answer = provider
+				.stream_message(&messages, MODEL_OK, &config, &mut |delta| deltas.push(delta.to_string()))
+				.await
+				.unwrap();
+			let error = provider.send_message(&messages, MODEL_UNAUTHORIZED, &config).await.unwrap_err().to_string();
+			(answer, deltas, error)
+		};
+
+		let recording = Arc::new(Cassette::record(&path));
+		let recorded = recording.clone().run(exchange(mock.provider("secret-key"))).await;
+		assert_eq!(recorded.0, ANSWER);
+		assert_eq!(recorded.1, ANSWER_DELTAS);
+		assert_eq!(recording.interactions().len(), 2);
+		let cassette = std::fs::read_to_string(&path).unwrap();
+		assert!(!cassette.contains("secret-key"));
+		assert!(cassette.contains("REDACTED"));
+
+		// Replaying needs neither the server nor the recorded key
+		drop(mock);
+		let provider = LLMProvider::with_base_url("anthropic", "other-key".to_string(), &base_url).unwrap();
+		let replaying = Arc::new(Cassette::replay(&path).unwrap());
+		assert_eq!(replaying.clone().run(exchange(provider.clone())).await, recorded);
+		let unrecorded = replaying
+			.run(provider.send_message(&user_message("Hi"), MODEL_SERVER_ERROR, &LLMConfig::default()))
+			.await;
+		assert!(unrecorded.unwrap_err().to_string().contains("No recorded response"));
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_render_recorded_answer() {
+		let cassette = Arc::new(Cassette::replay(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/cassettes/openai_quicksort.json")).unwrap());
+		let secrets = SecretStore::default();
+		let pool = init_in_memory(&secrets).await.unwrap();
+		let service = ChatService::new(pool, secrets, Arc::new(EventLog::default()));
+
+		let blocks = cassette
+			.run(async {
+				let prompt = "How does quicksort work? Keep it short.";
+				service.add_message("chat", "user", prompt, "gpt-4o", DEFAULT_CODE_THEME).await.unwrap();
+				// No key is stored, replaying works without one
+				let llm = service.llm("openai", Some("chat")).await.unwrap();
+				let answer = service.complete_streaming(&llm, "chat", "gpt-4o", &mut |_| {}).await.unwrap();
+				service.add_message("chat", "assistant", &answer, "gpt-4o", DEFAULT_CODE_THEME).await.unwrap();
+				service.messages("chat").await.unwrap()[1].blocks.clone().unwrap()
+			})
+			.await;
+
+		assert_eq!(
+			blocks.iter().map(|block| block.type_.as_str()).collect::<Vec<_>>(),
+			vec!["text", "code", "text", "math", "text"]
+		);
+		assert_eq!(blocks[1].language.as_deref(), Some("python"));
+		assert!(blocks[1].raw_content.starts_with("def quicksort(items):"));
+		assert_eq!(blocks[3].raw_content, "O(n \\log n)");
+		assert!(blocks[3].rendered_content.contains("<math"));
+		assert!(blocks[4].rendered_content.contains("<table"));
+		assert!(blocks[4].rendered_content.contains("<math"));
 	}
 }
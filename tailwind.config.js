@@ -1,7 +1,7 @@
 import { addDynamicIconSelectors } from '@iconify/tailwind'
 
 export default {
-	content: ['./src/**/*.{html,js,svelte,ts}'],
+	content: ['./src/**/*.{html,js,svelte,ts}', './src-tauri/src/**/*.rs'],
 	theme: {
 		extend: {
 			colors: {